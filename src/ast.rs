@@ -4,6 +4,7 @@ use crate::typechecker::TypeError;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::rc::Rc;
 
 pub type Name = usize;
 pub type TypeId = usize;
@@ -26,6 +27,7 @@ pub struct Program {
 pub struct ProgramT {
     pub stmts: Vec<Loc<StmtT>>,
     pub named_types: Vec<(Name, TypeId)>,
+    pub exports: Vec<Name>,
     pub errors: Vec<TypeError>,
 }
 
@@ -33,23 +35,45 @@ pub struct ProgramT {
 pub enum Stmt {
     Def(Name, Loc<TypeSig>, Loc<Expr>),
     Asgn(Name, Loc<Expr>),
+    // a = b = x. Targets are in source order
+    ChainedAsgn(Vec<Name>, Loc<Expr>),
+    // x += 1. Desugared into x = x + 1 before typechecking
+    OpAsgn(Name, Op, Loc<Expr>),
+    // a.b = x, where a can be any expression that evaluates to a record
+    FieldAsgn(Loc<Expr>, Name, Loc<Expr>),
     Expr(Loc<Expr>),
     Return(Loc<Expr>),
     Function {
         name: Name,
         params: Vec<Loc<(Name, Loc<TypeSig>)>>,
-        return_type: Loc<TypeSig>,
+        // None if it's left for the typechecker to infer
+        return_type: Option<Loc<TypeSig>>,
         body: Box<Loc<Expr>>,
     },
+    // export fn or export let. Only wraps Function or Def
+    Export(Box<Loc<Stmt>>),
+    // { ... } on its own, with a scope for the statements inside
+    Block(Vec<Loc<Stmt>>),
+    // while cond { ... }. The body is always a block
+    While(Loc<Expr>, Box<Loc<Expr>>),
+    Break,
+    Continue,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum StmtT {
     Def(Name, Loc<ExprT>),
     Asgn(Name, Loc<ExprT>),
+    ChainedAsgn(Vec<Name>, Loc<ExprT>),
+    // Records are tuples by this point so fields are assigned by position
+    TupleFieldAsgn(Loc<ExprT>, usize, Loc<ExprT>),
     Expr(Loc<ExprT>),
     Return(Loc<ExprT>),
     Function(Name),
+    Block(Vec<Loc<StmtT>>),
+    While(Loc<ExprT>, Box<Loc<ExprT>>),
+    Break,
+    Continue,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -77,11 +101,13 @@ pub enum Expr {
     },
     Field(Box<Loc<Expr>>, Name),
     TupleField(Box<Loc<Expr>>, usize),
+    Cast(Box<Loc<Expr>>, Loc<TypeSig>),
     Record {
         name: Name,
         fields: Vec<(Name, Loc<Expr>)>,
     },
     Tuple(Vec<Loc<Expr>>),
+    Array(Vec<Loc<Expr>>),
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -118,12 +144,23 @@ pub enum ExprT {
         type_: TypeId,
     },
     TupleField(Box<Loc<ExprT>>, usize, TypeId),
+    Cast(Box<Loc<ExprT>>, TypeId),
     Call {
         callee: Name,
         args: Vec<Loc<ExprT>>,
         type_: TypeId,
     },
     Tuple(Vec<Loc<ExprT>>, TypeId),
+    // Laid out like a tuple, with fields in declaration order
+    Record {
+        name: Name,
+        fields: Vec<Loc<ExprT>>,
+        type_: TypeId,
+    },
+    Array(Vec<Loc<ExprT>>, TypeId),
+    // Stands in for a sub-expression that failed to check, so we
+    // can keep checking around it. Never run
+    Error(TypeId),
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -132,14 +169,18 @@ pub enum Value {
     Integer(i64),
     Bool(bool),
     String(String),
+    Char(char),
     Tuple(Vec<Value>),
     Empty,
+    // The none literal. Its type is an optional of whatever it's used as
+    None,
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Function {
     pub params: Vec<Loc<(Name, TypeId)>>,
-    pub body: Box<Loc<ExprT>>,
+    // Shared so cloning a function never copies its body
+    pub body: Rc<Loc<ExprT>>,
     pub local_variables: Vec<TypeId>,
     pub scope_index: usize,
 }
@@ -155,11 +196,13 @@ impl fmt::Display for Value {
                 Value::Bool(b) => format!("bool: {}", b),
                 // TODO: Have this truncate the string
                 Value::String(s) => format!("string: {}", s),
+                Value::Char(c) => format!("char: {}", c),
                 Value::Tuple(ts) => format!(
                     "tuple: ({})",
                     ts.iter().map(|t| format!("{}", t)).join(", ")
                 ),
                 Value::Empty => format!("empty: ()"),
+                Value::None => "none".to_string(),
             }
         )
     }
@@ -183,6 +226,13 @@ pub enum Op {
     GreaterEqual,
     Less,
     LessEqual,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
 }
 
 impl fmt::Display for Op {
@@ -201,6 +251,13 @@ impl fmt::Display for Op {
                 Op::GreaterEqual => ">=",
                 Op::Less => "<",
                 Op::LessEqual => "<=",
+                Op::And => "&&",
+                Op::Or => "||",
+                Op::BitAnd => "&",
+                Op::BitOr => "|",
+                Op::BitXor => "^",
+                Op::Shl => "<<",
+                Op::Shr => ">>",
             }
         )
     }
@@ -218,6 +275,8 @@ pub enum Type {
     Record(Vec<(Name, TypeId)>),
     Tuple(Vec<TypeId>),
     Arrow(Vec<TypeId>, TypeId),
+    // T?, either none or some(T)
+    Optional(TypeId),
     // This is a hack to get print to work with any value. DO NOT USE
     Any,
     // Points to a type that is solved further
@@ -238,6 +297,7 @@ impl fmt::Display for Type {
                 Type::Char => "char".into(),
                 Type::String => "string".into(),
                 Type::Array(t) => format!("[{}]", t),
+                Type::Optional(t) => format!("{}?", t),
                 Type::Record(fields) => {
                     let elems = fields
                         .iter()
@@ -275,6 +335,10 @@ pub enum TypeSig {
     Tuple(Vec<Loc<TypeSig>>),
     Name(Name),
     Empty,
+    // (int, int) -> int
+    Arrow(Vec<Loc<TypeSig>>, Box<Loc<TypeSig>>),
+    // int?
+    Optional(Box<Loc<TypeSig>>),
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -282,6 +346,142 @@ pub enum TypeDef {
     Struct(Name, Vec<(Name, Loc<TypeSig>)>),
 }
 
+// Debug builds check that every range in a parsed program runs forwards,
+// since building one as (right, left) is an easy mistake to make and
+// only shows up later as a garbled diagnostic. No-op in release builds
+pub fn check_ranges(program: &Program) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    for type_def in &program.type_defs {
+        check_range(type_def);
+        let TypeDef::Struct(_, fields) = &type_def.inner;
+        for (_, type_sig) in fields {
+            check_type_sig_ranges(type_sig);
+        }
+    }
+    for stmt in &program.stmts {
+        check_stmt_ranges(stmt);
+    }
+}
+
+fn check_range<T: fmt::Debug>(loc: &Loc<T>) {
+    let LocationRange(start, end) = loc.location;
+    if start.0 > end.0 {
+        panic!("Inverted range {:?} on {:?}", loc.location, loc.inner);
+    }
+}
+
+fn check_stmt_ranges(stmt: &Loc<Stmt>) {
+    check_range(stmt);
+    match &stmt.inner {
+        Stmt::Def(_, type_sig, rhs) => {
+            check_type_sig_ranges(type_sig);
+            check_expr_ranges(rhs);
+        }
+        Stmt::Asgn(_, rhs) | Stmt::ChainedAsgn(_, rhs) | Stmt::OpAsgn(_, _, rhs) => {
+            check_expr_ranges(rhs)
+        }
+        Stmt::FieldAsgn(lhs, _, rhs) => {
+            check_expr_ranges(lhs);
+            check_expr_ranges(rhs);
+        }
+        Stmt::Expr(expr) | Stmt::Return(expr) => check_expr_ranges(expr),
+        Stmt::Function {
+            name: _,
+            params,
+            return_type,
+            body,
+        } => {
+            for param in params {
+                check_range(param);
+                check_type_sig_ranges(&param.inner.1);
+            }
+            if let Some(return_type) = return_type {
+                check_type_sig_ranges(return_type);
+            }
+            check_expr_ranges(body);
+        }
+        Stmt::Export(stmt) => check_stmt_ranges(stmt),
+        Stmt::Block(stmts) => {
+            for stmt in stmts {
+                check_stmt_ranges(stmt);
+            }
+        }
+        Stmt::While(cond, body) => {
+            check_expr_ranges(cond);
+            check_expr_ranges(body);
+        }
+        Stmt::Break | Stmt::Continue => {}
+    }
+}
+
+fn check_expr_ranges(expr: &Loc<Expr>) {
+    check_range(expr);
+    match &expr.inner {
+        Expr::Block(stmts, end_expr) => {
+            for stmt in stmts {
+                check_stmt_ranges(stmt);
+            }
+            if let Some(end_expr) = end_expr {
+                check_expr_ranges(end_expr);
+            }
+        }
+        Expr::If(cond, then_block, else_block) => {
+            check_expr_ranges(cond);
+            check_expr_ranges(then_block);
+            if let Some(else_block) = else_block {
+                check_expr_ranges(else_block);
+            }
+        }
+        Expr::BinOp { op: _, lhs, rhs } => {
+            check_expr_ranges(lhs);
+            check_expr_ranges(rhs);
+        }
+        Expr::UnaryOp { op: _, rhs } => check_expr_ranges(rhs),
+        Expr::Field(lhs, _) | Expr::TupleField(lhs, _) => check_expr_ranges(lhs),
+        Expr::Cast(lhs, type_sig) => {
+            check_expr_ranges(lhs);
+            check_type_sig_ranges(type_sig);
+        }
+        Expr::Record { name: _, fields } => {
+            for (_, expr) in fields {
+                check_expr_ranges(expr);
+            }
+        }
+        Expr::Call {
+            callee: _,
+            args: entries,
+        }
+        | Expr::Tuple(entries)
+        | Expr::Array(entries) => {
+            for entry in entries {
+                check_expr_ranges(entry);
+            }
+        }
+        Expr::Primary { value: _ } | Expr::Var { name: _ } => {}
+    }
+}
+
+fn check_type_sig_ranges(type_sig: &Loc<TypeSig>) {
+    check_range(type_sig);
+    match &type_sig.inner {
+        TypeSig::Array(entry) | TypeSig::Optional(entry) => check_type_sig_ranges(entry),
+        TypeSig::Tuple(entries) => {
+            for entry in entries {
+                check_type_sig_ranges(entry);
+            }
+        }
+        TypeSig::Arrow(params, return_type) => {
+            for param in params {
+                check_type_sig_ranges(param);
+            }
+            check_type_sig_ranges(return_type);
+        }
+        TypeSig::Name(_) | TypeSig::Empty => {}
+    }
+}
+
 // Oy vey, cause Rust doesn't allow enum field access
 impl ExprT {
     pub fn get_type(&self) -> TypeId {
@@ -289,6 +489,13 @@ impl ExprT {
             ExprT::Primary { value: _, type_ } => *type_,
             ExprT::Var { name: _, type_ } => *type_,
             ExprT::Tuple(_elems, type_) => *type_,
+            ExprT::Array(_elems, type_) => *type_,
+            ExprT::Error(type_) => *type_,
+            ExprT::Record {
+                name: _,
+                fields: _,
+                type_,
+            } => *type_,
             ExprT::BinOp {
                 op: _,
                 lhs: _,
@@ -301,6 +508,7 @@ impl ExprT {
                 type_,
             } => *type_,
             ExprT::TupleField(_, _, type_) => *type_,
+            ExprT::Cast(_, type_) => *type_,
             ExprT::Call {
                 callee: _,
                 args: _,
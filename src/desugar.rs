@@ -0,0 +1,130 @@
+use crate::ast::{Expr, Loc, Name, Op, Program, Stmt};
+use crate::lexer::LocationRange;
+
+// Rewrites syntactic sugar into the core subset of Stmt/Expr
+// that the typechecker works with. Right now that's just
+// compound assignment
+pub fn desugar_program(program: Program) -> Program {
+    Program {
+        stmts: program.stmts.into_iter().map(desugar_stmt).collect(),
+        type_defs: program.type_defs,
+        errors: program.errors,
+    }
+}
+
+pub fn desugar_stmt(stmt: Loc<Stmt>) -> Loc<Stmt> {
+    let location = stmt.location;
+    let inner = match stmt.inner {
+        Stmt::OpAsgn(name, op, rhs) => return op_asgn(name, op, desugar_expr(rhs), location),
+        Stmt::Def(name, type_sig, rhs) => Stmt::Def(name, type_sig, desugar_expr(rhs)),
+        Stmt::Asgn(name, rhs) => Stmt::Asgn(name, desugar_expr(rhs)),
+        Stmt::ChainedAsgn(names, rhs) => Stmt::ChainedAsgn(names, desugar_expr(rhs)),
+        Stmt::FieldAsgn(lhs, name, rhs) => {
+            Stmt::FieldAsgn(desugar_expr(lhs), name, desugar_expr(rhs))
+        }
+        Stmt::Expr(expr) => Stmt::Expr(desugar_expr(expr)),
+        Stmt::Return(expr) => Stmt::Return(desugar_expr(expr)),
+        Stmt::Function {
+            name,
+            params,
+            return_type,
+            body,
+        } => Stmt::Function {
+            name,
+            params,
+            return_type,
+            body: Box::new(desugar_expr(*body)),
+        },
+        Stmt::Export(stmt) => Stmt::Export(Box::new(desugar_stmt(*stmt))),
+        Stmt::Block(stmts) => Stmt::Block(stmts.into_iter().map(desugar_stmt).collect()),
+        Stmt::While(cond, body) => Stmt::While(desugar_expr(cond), Box::new(desugar_expr(*body))),
+        stmt @ Stmt::Break | stmt @ Stmt::Continue => stmt,
+    };
+    Loc { location, inner }
+}
+
+// x op= rhs becomes x = x op rhs
+pub fn op_asgn(name: Name, op: Op, rhs: Loc<Expr>, location: LocationRange) -> Loc<Stmt> {
+    let lhs = Loc {
+        location,
+        inner: Expr::Var { name },
+    };
+    let rhs = Loc {
+        location,
+        inner: Expr::BinOp {
+            op,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        },
+    };
+    Loc {
+        location,
+        inner: Stmt::Asgn(name, rhs),
+    }
+}
+
+fn desugar_expr(expr: Loc<Expr>) -> Loc<Expr> {
+    let location = expr.location;
+    let desugar_box = |expr: Box<Loc<Expr>>| Box::new(desugar_expr(*expr));
+    let desugar_vec =
+        |exprs: Vec<Loc<Expr>>| -> Vec<Loc<Expr>> { exprs.into_iter().map(desugar_expr).collect() };
+    let inner = match expr.inner {
+        Expr::Block(stmts, end_expr) => Expr::Block(
+            stmts.into_iter().map(desugar_stmt).collect(),
+            end_expr.map(desugar_box),
+        ),
+        Expr::If(cond, then_block, else_block) => Expr::If(
+            desugar_box(cond),
+            desugar_box(then_block),
+            else_block.map(desugar_box),
+        ),
+        Expr::BinOp { op, lhs, rhs } => Expr::BinOp {
+            op,
+            lhs: desugar_box(lhs),
+            rhs: desugar_box(rhs),
+        },
+        Expr::UnaryOp { op, rhs } => Expr::UnaryOp {
+            op,
+            rhs: desugar_box(rhs),
+        },
+        Expr::Call { callee, args } => Expr::Call {
+            callee,
+            args: desugar_vec(args),
+        },
+        Expr::Field(lhs, name) => Expr::Field(desugar_box(lhs), name),
+        Expr::TupleField(lhs, index) => Expr::TupleField(desugar_box(lhs), index),
+        Expr::Cast(lhs, type_sig) => Expr::Cast(desugar_box(lhs), type_sig),
+        Expr::Record { name, fields } => Expr::Record {
+            name,
+            fields: fields
+                .into_iter()
+                .map(|(name, expr)| (name, desugar_expr(expr)))
+                .collect(),
+        },
+        Expr::Tuple(entries) => Expr::Tuple(desugar_vec(entries)),
+        Expr::Array(entries) => Expr::Array(desugar_vec(entries)),
+        expr @ Expr::Primary { .. } | expr @ Expr::Var { .. } => expr,
+    };
+    Loc { location, inner }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::desugar::desugar_program;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::unparser::Unparser;
+
+    #[test]
+    fn compound_assignment() {
+        let source = "let x: int = 1;\nx += 1;\nfn f(y: int) -> int { y * 2 }\nx *= f(x) - 1;";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = desugar_program(parser.program().unwrap());
+        let hir = Unparser::new(parser.get_name_table())
+            .unparse_source(&program)
+            .unwrap();
+        let lines: Vec<_> = hir.lines().collect();
+        assert_eq!(lines[1], "x = x + 1;");
+        assert_eq!(lines.last(), Some(&"x = x * (f(x) - 1);"));
+    }
+}
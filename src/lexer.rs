@@ -9,6 +9,7 @@ use std::str::CharIndices;
 pub enum Token {
     False,
     True,
+    None,
     Else,
     Export,
     For,
@@ -17,7 +18,10 @@ pub enum Token {
     Struct,
     Let,
     While,
+    Break,
+    Continue,
     Fn,
+    As,
     Ident(usize),
     Float(f64),
     Integer(i64),
@@ -29,16 +33,20 @@ pub enum Token {
     RParen,
     Semicolon,
     Colon,
+    Question,
     Comma,
     Dot,
     Amp,
     AmpAmp,
     Pipe,
     PipePipe,
+    Caret,
     Greater,
     GreaterEqual,
+    GreaterGreater,
     Less,
     LessEqual,
+    LessLess,
     Bang,
     BangEqual,
     Equal,
@@ -55,6 +63,7 @@ pub enum Token {
     FatArrow,
     Slash,
     String(String),
+    Char(char),
 }
 
 impl Display for TokenD {
@@ -65,6 +74,7 @@ impl Display for TokenD {
             match self {
                 TokenD::False => "false",
                 TokenD::True => "true",
+                TokenD::None => "none",
                 TokenD::Else => "else",
                 TokenD::Export => "export",
                 TokenD::For => "for",
@@ -73,7 +83,10 @@ impl Display for TokenD {
                 TokenD::Struct => "struct",
                 TokenD::Let => "let",
                 TokenD::While => "while",
+                TokenD::Break => "break",
+                TokenD::Continue => "continue",
                 TokenD::Fn => "fn",
+                TokenD::As => "as",
                 TokenD::Ident => "identifier",
                 TokenD::Float => "float",
                 TokenD::Integer => "int",
@@ -85,16 +98,20 @@ impl Display for TokenD {
                 TokenD::RParen => ")",
                 TokenD::Semicolon => ";",
                 TokenD::Colon => ":",
+                TokenD::Question => "?",
                 TokenD::Comma => ",",
                 TokenD::Dot => ".",
                 TokenD::Amp => "&",
                 TokenD::AmpAmp => "&&",
                 TokenD::Pipe => "|",
                 TokenD::PipePipe => "||",
+                TokenD::Caret => "^",
                 TokenD::Greater => ">",
                 TokenD::GreaterEqual => ">=",
+                TokenD::GreaterGreater => ">>",
                 TokenD::Less => "<",
                 TokenD::LessEqual => "<=",
+                TokenD::LessLess => "<<",
                 TokenD::Bang => "!",
                 TokenD::BangEqual => "!=",
                 TokenD::Equal => "=",
@@ -111,6 +128,7 @@ impl Display for TokenD {
                 TokenD::Arrow => "->",
                 TokenD::Slash => "\\",
                 TokenD::String => "string",
+                TokenD::Char => "char",
             }
         )
     }
@@ -168,8 +186,33 @@ pub enum LexicalError {
     #[fail(display = "{}: String was not terminated", location)]
     UnterminatedString { location: LocationRange },
 
+    #[fail(display = "{}: Invalid escape sequence '\\{}'", location, ch)]
+    InvalidEscape { ch: char, location: LocationRange },
+
+    #[fail(display = "{}: Char literal must hold exactly one character", location)]
+    InvalidCharLiteral { location: LocationRange },
+
+    #[fail(
+        display = "{}: \\u{{...}} must hold 1 to 6 hex digits of a valid char",
+        location
+    )]
+    InvalidUnicodeEscape { location: LocationRange },
+
+    #[fail(display = "{}: Integer literal is too large", location)]
+    IntegerOutOfRange { location: LocationRange },
+
     #[fail(display = "This word is reserved for implementation reasons")]
-    ReservedWord {location: LocationRange }
+    ReservedWord { location: LocationRange },
+
+    #[fail(
+        display = "{}: {} is longer than the maximum of {} bytes",
+        location, kind, max_len
+    )]
+    TooLong {
+        kind: String,
+        max_len: usize,
+        location: LocationRange,
+    },
 }
 
 impl LexicalError {
@@ -177,15 +220,41 @@ impl LexicalError {
         match self {
             LexicalError::InvalidCharacter { ch: _, location } => *location,
             LexicalError::UnterminatedString { location } => *location,
+            LexicalError::InvalidEscape { ch: _, location } => *location,
+            LexicalError::InvalidCharLiteral { location } => *location,
+            LexicalError::InvalidUnicodeEscape { location } => *location,
+            LexicalError::IntegerOutOfRange { location } => *location,
             LexicalError::ReservedWord { location} => *location,
+            LexicalError::TooLong {
+                kind: _,
+                max_len: _,
+                location,
+            } => *location,
         }
     }
 }
 
+// Caps on token lengths so pathological inputs (i.e. from
+// fuzzing) error out instead of allocating huge strings
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LexerLimits {
+    pub max_identifier_len: Option<usize>,
+    pub max_string_len: Option<usize>,
+}
+
+// A `//` comment, kept so the formatter can put it back
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    pub text: String,
+    pub location: LocationRange,
+}
+
 pub struct Lexer<'input> {
     source: &'input str,
     chars: CharIndices<'input>,
     pub name_table: NameTable,
+    pub comments: Vec<Comment>,
+    limits: LexerLimits,
     row: usize,
     column: usize,
     index: usize,
@@ -195,6 +264,12 @@ pub struct Lexer<'input> {
 
 impl<'input> Lexer<'input> {
     pub fn new(source: &'input str) -> Lexer<'input> {
+        Self::with_name_table(source, NameTable::new())
+    }
+
+    // Lexes with an existing name table so ids stay consistent across
+    // multiple sources, i.e. between lines in the REPL
+    pub fn with_name_table(source: &'input str, name_table: NameTable) -> Lexer<'input> {
         let mut chars = source.char_indices();
         let lookahead = chars.next();
         let lookahead2 = chars.next();
@@ -205,12 +280,35 @@ impl<'input> Lexer<'input> {
             row: 1,
             column: 1,
             index: 0,
-            name_table: NameTable::new(),
+            name_table,
+            comments: Vec::new(),
+            limits: LexerLimits::default(),
             lookahead,
             lookahead2,
         }
     }
 
+    pub fn with_limits(mut self, limits: LexerLimits) -> Lexer<'input> {
+        self.limits = limits;
+        self
+    }
+
+    fn check_len(
+        kind: &str,
+        len: usize,
+        max_len: Option<usize>,
+        location: LocationRange,
+    ) -> Result<(), LexicalError> {
+        match max_len {
+            Some(max_len) if len > max_len => Err(LexicalError::TooLong {
+                kind: kind.to_string(),
+                max_len,
+                location,
+            }),
+            _ => Ok(()),
+        }
+    }
+
     pub fn get_location(&self) -> Location {
         Location(self.index)
     }
@@ -219,7 +317,12 @@ impl<'input> Lexer<'input> {
         let next = self.lookahead;
         self.lookahead = self.lookahead2;
         self.lookahead2 = self.chars.next();
-        self.index += 1;
+        // Locations are byte offsets, so skip over the whole
+        // character rather than counting characters
+        self.index = self
+            .lookahead
+            .map(|(i, _)| i)
+            .unwrap_or_else(|| self.source.len());
         if let Some((_, '\n')) = next {
             self.row += 1;
             self.column = 0;
@@ -250,7 +353,7 @@ impl<'input> Lexer<'input> {
                     Ok((alt_token, LocationRange(start_loc, self.get_location())))
                 }
             }
-            None => Ok((alt_token, LocationRange(start_loc, start_loc))),
+            None => Ok((alt_token, LocationRange(start_loc, self.get_location()))),
         }
     }
 
@@ -283,17 +386,164 @@ impl<'input> Lexer<'input> {
         self.take_while(|ch| ch.is_whitespace());
     }
 
-    fn read_string(
+    fn read_string(&mut self, start_loc: Location) -> <Lexer<'input> as Iterator>::Item {
+        let mut value = String::new();
+        let mut too_long = false;
+        loop {
+            let ch = match self.bump() {
+                Some((_, '"')) => break,
+                Some((i, '\\')) => match self.read_escape(i, start_loc) {
+                    Ok(ch) => ch,
+                    Err(err) => {
+                        self.skip_literal('"');
+                        return Err(err);
+                    }
+                },
+                Some((_, ch)) => ch,
+                None => {
+                    return Err(LexicalError::UnterminatedString {
+                        location: LocationRange(start_loc, Location(self.index)),
+                    })
+                }
+            };
+            // Keep going to the closing quote so we don't
+            // pick up lexing in the middle of the string
+            if !too_long {
+                value.push(ch);
+                too_long = self
+                    .limits
+                    .max_string_len
+                    .is_some_and(|max_len| value.len() > max_len);
+            }
+        }
+        let location = LocationRange(start_loc, self.get_location());
+        match self.limits.max_string_len {
+            Some(max_len) if too_long => Err(LexicalError::TooLong {
+                kind: "String".to_string(),
+                max_len,
+                location,
+            }),
+            _ => Ok((Token::String(value), location)),
+        }
+    }
+
+    // The character after a backslash in a string or char literal.
+    // `i` is where the backslash is
+    fn read_escape(&mut self, i: usize, start_loc: Location) -> Result<char, LexicalError> {
+        match self.bump() {
+            Some((_, 'n')) => Ok('\n'),
+            Some((_, 't')) => Ok('\t'),
+            Some((_, 'r')) => Ok('\r'),
+            Some((_, '0')) => Ok('\0'),
+            Some((_, '\\')) => Ok('\\'),
+            Some((_, '"')) => Ok('"'),
+            Some((_, '\'')) => Ok('\''),
+            Some((_, 'u')) => self.read_unicode_escape(i),
+            Some((_, ch)) => Err(LexicalError::InvalidEscape {
+                ch,
+                location: LocationRange(Location(i), self.get_location()),
+            }),
+            None => Err(LexicalError::UnterminatedString {
+                location: LocationRange(start_loc, Location(self.index)),
+            }),
+        }
+    }
+
+    // After a bad escape, skips past the closing quote so the rest
+    // of the literal isn't lexed as code. Stops at the end of the
+    // line if the literal is never closed
+    fn skip_literal(&mut self, quote: char) {
+        while let Some((_, ch)) = self.lookahead {
+            if ch == '\n' {
+                break;
+            }
+            self.bump();
+            if ch == quote {
+                break;
+            }
+            if ch == '\\' && self.lookahead.map(|(_, ch)| ch) != Some('\n') {
+                self.bump();
+            }
+        }
+    }
+
+    // \u{...} with the hex digits of a code point, after the u
+    fn read_unicode_escape(&mut self, i: usize) -> Result<char, LexicalError> {
+        let mut digits = String::new();
+        let mut closed = false;
+        if let Some((_, '{')) = self.lookahead {
+            self.bump();
+            // Take anything that looks like part of the escape
+            // so a typo doesn't spill into the rest of the string
+            while let Some((_, ch)) = self.lookahead {
+                if ch == '}' {
+                    self.bump();
+                    closed = true;
+                    break;
+                } else if !ch.is_ascii_alphanumeric() {
+                    break;
+                }
+                digits.push(ch);
+                self.bump();
+            }
+        }
+        let code_point = if closed && digits.len() <= 6 {
+            u32::from_str_radix(&digits, 16).ok()
+        } else {
+            None
+        };
+        code_point
+            .and_then(char::from_u32)
+            .ok_or(LexicalError::InvalidUnicodeEscape {
+                location: LocationRange(Location(i), self.get_location()),
+            })
+    }
+
+    // 'c', where c is a single character or an escape
+    fn read_char(&mut self, start_loc: Location) -> <Lexer<'input> as Iterator>::Item {
+        let ch = match self.bump() {
+            Some((i, '\\')) => match self.read_escape(i, start_loc) {
+                Ok(ch) => Some(ch),
+                Err(err) => {
+                    self.skip_literal('\'');
+                    return Err(err);
+                }
+            },
+            Some((_, '\'')) | Some((_, '\n')) | None => None,
+            Some((_, ch)) => Some(ch),
+        };
+        match (ch, self.lookahead) {
+            (Some(ch), Some((_, '\''))) => {
+                self.bump();
+                Ok((
+                    Token::Char(ch),
+                    LocationRange(start_loc, self.get_location()),
+                ))
+            }
+            _ => Err(LexicalError::InvalidCharLiteral {
+                location: LocationRange(start_loc, self.get_location()),
+            }),
+        }
+    }
+
+    // r"..." strings have no escapes, so they end at the first quote
+    fn read_raw_string(
         &mut self,
         start_index: usize,
         start_loc: Location,
     ) -> <Lexer<'input> as Iterator>::Item {
+        // Skip the quote after the r
+        self.bump();
+        let contents_start = start_index + 2;
         match self.take_until(|ch| ch == '"') {
             Some(i) => {
                 self.bump();
                 let end_loc = self.get_location();
+                let location = LocationRange(start_loc, end_loc);
+                let len = i - contents_start;
+                Self::check_len("String", len, self.limits.max_string_len, location)?;
                 Ok((
-                    Token::String(self.source[start_index + 1..i].to_string()),
+                    Token::String(self.source[contents_start..i].to_string()),
                     LocationRange(start_loc, end_loc),
                 ))
             }
@@ -310,8 +560,10 @@ impl<'input> Lexer<'input> {
     ) -> <Lexer<'input> as Iterator>::Item {
         let mut end_index = self.take_while(|ch| ch.is_ascii_digit());
         let mut is_decimal = false;
+        // In t.0.1 the 0 is a tuple index, not the start of 0.1
+        let is_tuple_index = self.source[..start_index].ends_with('.');
 
-        if let Some((_, '.')) = self.lookahead {
+        if let (Some((_, '.')), false) = (self.lookahead, is_tuple_index) {
             // Check if it's a decimal or a field access
             if let Some((_, next_ch)) = self.lookahead2 {
                 if next_ch.is_ascii_digit() {
@@ -333,14 +585,13 @@ impl<'input> Lexer<'input> {
                 LocationRange(start_loc, end_loc),
             ))
         } else {
-            Ok((
-                Token::Integer(
-                    self.source[start_index..end_index]
-                        .parse()
-                        .expect("unparseable number"),
-                ),
-                LocationRange(start_loc, end_loc),
-            ))
+            let location = LocationRange(start_loc, end_loc);
+            // Only digits get here, so the parse only fails if
+            // the number doesn't fit
+            match self.source[start_index..end_index].parse() {
+                Ok(int) => Ok((Token::Integer(int), location)),
+                Err(_) => Err(LexicalError::IntegerOutOfRange { location }),
+            }
         }
     }
 
@@ -362,14 +613,15 @@ impl<'input> Lexer<'input> {
             "struct" => Token::Struct,
             "return" => Token::Return,
             "true" => Token::True,
+            "none" => Token::None,
             "let" => Token::Let,
             "while" => Token::While,
+            "break" => Token::Break,
+            "continue" => Token::Continue,
             "fn" => Token::Fn,
             "export" => Token::Export,
-            "as" => return Err(LexicalError::ReservedWord { location }),
-            "break" => return Err(LexicalError::ReservedWord { location }),
+            "as" => Token::As,
             "const" => return Err(LexicalError::ReservedWord { location }),
-            "continue" => return Err(LexicalError::ReservedWord { location }),
             "crate" => return Err(LexicalError::ReservedWord { location }),
             "enum" => return Err(LexicalError::ReservedWord { location }),
             "extern" => return Err(LexicalError::ReservedWord { location }),
@@ -408,11 +660,12 @@ impl<'input> Lexer<'input> {
             "yield" => return Err(LexicalError::ReservedWord { location }),
             "try" => return Err(LexicalError::ReservedWord { location }),
             ident => {
-                let ident = ident.to_string();
-                if let Some(id) = self.name_table.get_id(&ident) {
+                let max_len = self.limits.max_identifier_len;
+                Self::check_len("Identifier", ident.len(), max_len, location)?;
+                if let Some(id) = self.name_table.get_id(ident) {
                     Token::Ident(*id)
                 } else {
-                    let id = self.name_table.insert(ident);
+                    let id = self.name_table.insert(ident.to_string());
                     Token::Ident(id)
                 }
             }
@@ -441,6 +694,7 @@ impl<'input> Iterator for Lexer<'input> {
                 '.' => Some(Ok((Token::Dot, LocationRange(start_loc, end_loc)))),
                 '\\' => Some(Ok((Token::Slash, LocationRange(start_loc, end_loc)))),
                 ':' => Some(Ok((Token::Colon, LocationRange(start_loc, end_loc)))),
+                '?' => Some(Ok((Token::Question, LocationRange(start_loc, end_loc)))),
                 '+' => Some(self.lookahead_match(start_loc, Token::PlusEqual, Token::Plus, '=')),
                 '-' => match self.lookahead {
                     Some((_, '>')) => {
@@ -463,6 +717,12 @@ impl<'input> Iterator for Lexer<'input> {
                 '/' => match self.lookahead {
                     Some((_, '/')) => {
                         self.skip_to_line_end();
+                        let location = LocationRange(start_loc, self.get_location());
+                        let text = self.source[(location.0).0..(location.1).0].trim_end();
+                        self.comments.push(Comment {
+                            text: text.to_string(),
+                            location,
+                        });
                         self.next()
                     }
                     Some((_, '=')) => {
@@ -492,13 +752,34 @@ impl<'input> Iterator for Lexer<'input> {
                     }
                     _ => Some(Ok((Token::Equal, LocationRange(start_loc, end_loc)))),
                 },
-                '>' => {
-                    Some(self.lookahead_match(start_loc, Token::GreaterEqual, Token::Greater, '='))
-                }
-                '<' => Some(self.lookahead_match(start_loc, Token::LessEqual, Token::Less, '=')),
+                '>' => match self.lookahead {
+                    Some((_, '>')) => Some(self.lookahead_match(
+                        start_loc,
+                        Token::GreaterGreater,
+                        Token::Greater,
+                        '>',
+                    )),
+                    _ => Some(self.lookahead_match(
+                        start_loc,
+                        Token::GreaterEqual,
+                        Token::Greater,
+                        '=',
+                    )),
+                },
+                '<' => match self.lookahead {
+                    Some((_, '<')) => {
+                        Some(self.lookahead_match(start_loc, Token::LessLess, Token::Less, '<'))
+                    }
+                    _ => Some(self.lookahead_match(start_loc, Token::LessEqual, Token::Less, '=')),
+                },
+                '^' => Some(Ok((Token::Caret, LocationRange(start_loc, end_loc)))),
                 '&' => Some(self.lookahead_match(start_loc, Token::AmpAmp, Token::Amp, '&')),
                 '|' => Some(self.lookahead_match(start_loc, Token::PipePipe, Token::Pipe, '|')),
-                '"' => Some(self.read_string(i, start_loc)),
+                '"' => Some(self.read_string(start_loc)),
+                '\'' => Some(self.read_char(start_loc)),
+                'r' if self.lookahead.map(|(_, ch)| ch) == Some('"') => {
+                    Some(self.read_raw_string(i, start_loc))
+                }
                 ch if is_id_start(ch) => Some(self.read_identifier(i, start_loc)),
                 ch if ch.is_ascii_digit() => Some(self.read_number(i, start_loc)),
                 ch => {
@@ -514,3 +795,224 @@ impl<'input> Iterator for Lexer<'input> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::{Lexer, LexerLimits, LexicalError, Location, LocationRange, Token};
+    use crate::utils::NameTable;
+
+    #[test]
+    fn integer_out_of_range() {
+        let mut lexer = Lexer::new("9223372036854775807 9223372036854775808");
+        assert!(lexer.next().unwrap().is_ok());
+        assert_eq!(
+            lexer.next().unwrap(),
+            Err(LexicalError::IntegerOutOfRange {
+                location: LocationRange(Location(20), Location(39)),
+            })
+        );
+    }
+
+    #[test]
+    fn max_lengths() {
+        let limits = LexerLimits {
+            max_identifier_len: Some(3),
+            max_string_len: Some(5),
+        };
+        let mut lexer = Lexer::new("foo food \"hello\" \"hello!\"").with_limits(limits);
+        assert!(lexer.next().unwrap().is_ok());
+        assert_eq!(
+            lexer.next().unwrap(),
+            Err(LexicalError::TooLong {
+                kind: "Identifier".to_string(),
+                max_len: 3,
+                location: LocationRange(Location(4), Location(8)),
+            })
+        );
+        assert!(lexer.next().unwrap().is_ok());
+        match lexer.next().unwrap() {
+            Err(LexicalError::TooLong { kind, .. }) => assert_eq!(kind, "String"),
+            res => panic!("expected string to be too long, got {:?}", res),
+        }
+
+        // No limits by default
+        let long_name = "a".repeat(10_000);
+        assert!(Lexer::new(&long_name).next().unwrap().is_ok());
+    }
+
+    #[test]
+    fn raw_strings() {
+        let string = |source: &str| match Lexer::new(source).next() {
+            Some(Ok((Token::String(s), _))) => s,
+            res => panic!("expected string, got {:?}", res),
+        };
+        assert_eq!(string(r#"r"\n""#), "\\n");
+        assert_eq!(string(r#""\n""#), "\n");
+        assert_eq!(string(r#"r"C:\dir\""#), "C:\\dir\\");
+        assert_eq!(string(r#""say \"hi\"""#), "say \"hi\"");
+
+        let mut lexer = Lexer::new("rx radius r");
+        for _ in 0..3 {
+            match lexer.next() {
+                Some(Ok((Token::Ident(_), _))) => {}
+                res => panic!("expected identifier, got {:?}", res),
+            }
+        }
+
+        match Lexer::new("r\"abc").next() {
+            Some(Err(LexicalError::UnterminatedString { .. })) => {}
+            res => panic!("expected unterminated string, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn char_literals() {
+        let tokens: Vec<_> = Lexer::new(r"'a' '\n' '\'' 'é'")
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::Char('a'),
+                Token::Char('\n'),
+                Token::Char('\''),
+                Token::Char('é')
+            ]
+        );
+        for source in &["''", "'ab'", "'a"] {
+            match Lexer::new(source).next() {
+                Some(Err(LexicalError::InvalidCharLiteral { .. })) => {}
+                res => panic!("expected invalid char in {}, got {:?}", source, res),
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_escape_recovers() {
+        for source in &[
+            r#""a\qb" + 1"#,
+            r#""a\qb\"c" + 1"#,
+            r"'\q' + 1",
+            r#""\u{zz}" + 1"#,
+        ] {
+            let tokens: Vec<_> = Lexer::new(source).collect();
+            assert_eq!(tokens.len(), 3, "{}: {:?}", source, tokens);
+            assert!(tokens[0].is_err(), "{}: {:?}", source, tokens);
+            assert_eq!(tokens[1].as_ref().unwrap().0, Token::Plus);
+            assert_eq!(tokens[2].as_ref().unwrap().0, Token::Integer(1));
+        }
+        // An unclosed literal stops at the end of the line
+        let tokens: Vec<_> = Lexer::new("\"a\\q\n1").collect();
+        assert_eq!(tokens.len(), 2, "{:?}", tokens);
+        assert_eq!(tokens[1].as_ref().unwrap().0, Token::Integer(1));
+    }
+
+    #[test]
+    fn unicode_escapes() {
+        let tokens: Vec<_> = Lexer::new(r#""\u{41}" "\u{1F600}!" '\u{e9}'"#)
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Token::String("A".to_string()),
+                Token::String("\u{1F600}!".to_string()),
+                Token::Char('é')
+            ]
+        );
+        for source in &[
+            r#""\u{110000}""#,
+            r#""\u{D800}""#,
+            r#""\u{4g}""#,
+            r#""\u{}""#,
+            r#""\u41""#,
+            r#""\u{1234567}""#,
+            r#""\u{41""#,
+        ] {
+            match Lexer::new(source).next() {
+                Some(Err(LexicalError::InvalidUnicodeEscape { .. })) => {}
+                res => panic!(
+                    "expected invalid unicode escape in {}, got {:?}",
+                    source, res
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn nested_tuple_index() {
+        let tokens: Vec<_> = Lexer::new("t.0.1 + 0.1")
+            .map(|res| res.unwrap().0)
+            .collect();
+        assert_eq!(
+            tokens[1..],
+            [
+                Token::Dot,
+                Token::Integer(0),
+                Token::Dot,
+                Token::Integer(1),
+                Token::Plus,
+                Token::Float(0.1)
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_identifiers_reuse_names() {
+        let mut lexer = Lexer::new("foo bar foo bar");
+        let foo = lexer.next().unwrap().unwrap().0;
+        let bar = lexer.next().unwrap().unwrap().0;
+        let names = lexer.name_table.names().count();
+        assert_eq!(lexer.next().unwrap().unwrap().0, foo);
+        assert_eq!(lexer.next().unwrap().unwrap().0, bar);
+        assert_eq!(lexer.name_table.names().count(), names);
+    }
+
+    #[test]
+    fn comment_mid_expression() {
+        let source = "1 + // one\n2";
+        let mut lexer = Lexer::new(source);
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Integer(1), LocationRange(Location(0), Location(1)))
+        );
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Plus, LocationRange(Location(2), Location(3)))
+        );
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Integer(2), LocationRange(Location(11), Location(12)))
+        );
+        assert_eq!(lexer.row, 2);
+        assert!(lexer.next().is_none());
+        assert_eq!(lexer.comments.len(), 1);
+        assert_eq!(lexer.comments[0].text, "// one");
+        assert_eq!(
+            lexer.comments[0].location,
+            LocationRange(Location(4), Location(10))
+        );
+    }
+
+    #[test]
+    fn comment_with_multibyte_chars() {
+        let source = "// héllo ✓\nfoo";
+        let mut lexer = Lexer::new(source);
+        let (token, location) = lexer.next().unwrap().unwrap();
+        let foo = NameTable::new().insert("foo".to_string());
+        assert_eq!(token, Token::Ident(foo));
+        assert_eq!(location, LocationRange(Location(14), Location(17)));
+        assert_eq!(&source[(location.0).0..(location.1).0], "foo");
+        assert_eq!(lexer.row, 2);
+    }
+
+    #[test]
+    fn operator_at_end_of_file() {
+        let mut lexer = Lexer::new("a +");
+        lexer.next();
+        assert_eq!(
+            lexer.next().unwrap().unwrap(),
+            (Token::Plus, LocationRange(Location(2), Location(3)))
+        );
+    }
+}
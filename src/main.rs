@@ -2,7 +2,6 @@
 #![allow(unused_variables)]
 
 extern crate base64;
-extern crate bimap;
 extern crate byteorder;
 extern crate codespan_reporting;
 extern crate failure;
@@ -18,27 +17,29 @@ extern crate serde;
 extern crate serde_json;
 
 
-use crate::ast::{Function, Name, Program, ProgramT};
+use crate::ast::{Program, ProgramT};
 use crate::parser::{ParseError, Parser};
-use crate::treewalker::TreeWalker;
+use crate::repl::Repl;
+use crate::runtime::{DefaultIO, IError, RuntimeIO, TestIO};
+use crate::treewalker::{FlushPolicy, TreeWalker, DEFAULT_MAX_CALL_DEPTH};
 use crate::typechecker::{TypeChecker, TypeError};
 use crate::unparser::Unparser;
 use crate::utils::NameTable;
-use std::io::{stdout, stdin};
-use codespan_reporting::diagnostic::{Diagnostic, Label};
-use codespan_reporting::files::SimpleFile;
+use codespan_reporting::diagnostic::{Diagnostic, Label, Severity};
+use codespan_reporting::files::{Files, SimpleFile};
 use codespan_reporting::term;
 use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
 use failure::Error;
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::process::{Command, Stdio};
-use std::{env, fs, mem};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs, mem, thread};
 
 mod ast;
+mod desugar;
 mod lexer;
 mod parser;
 mod printer;
+mod repl;
 mod runtime;
 mod symbol_table;
 mod treewalker;
@@ -47,95 +48,227 @@ mod unparser;
 mod utils;
 mod watcher;
 
+// The treewalker recurses on the host stack, so give it enough room
+// for its own call depth limit to kick in before the host stack runs
+// out. A call whose body has an if, a let and a nested call takes
+// about 85KB of stack in a debug build (5KB in release), so 128KB a
+// call leaves room for more deeply nested bodies. The stack is only
+// reserved up front, pages get used as the recursion reaches them
+const CALL_STACK_SIZE: usize = 128 * 1024;
+const STACK_SIZE: usize = DEFAULT_MAX_CALL_DEPTH * CALL_STACK_SIZE;
+
 fn main() -> Result<(), Error> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        return run_repl();
-    } else {
-        let file_name = &args[1];
-        let contents = fs::read_to_string(file_name)?;
-        interpret_code(&contents, &file_name)?;
-    };
-    Ok(())
+    let child = thread::Builder::new().stack_size(STACK_SIZE).spawn(run)?;
+    match child.join() {
+        Ok(res) => res,
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
 }
 
-fn run_repl() -> Result<(), Error> {
-    loop {
-        let mut input = String::new();
-        print!("> ");
-        stdout().flush()?;
-        stdin().read_line(&mut input)?;
-        match input.trim().chars().last() {
-            Some(';') | Some('}') => {
-                interpret_code(&input, "<repl>")?;
-            }
-            c => {
-                println!("{:?}", c);
-                interpret_expr(&input, "<repl>")
+// What to do with the file
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    Run,
+    Check,
+    Test,
+    Format,
+    Watch,
+    EmitHir,
+    EmitTypedAstJson,
+    DumpSymbolTable,
+}
+
+// Flags can come in any order and combine, e.g. `--trace --seed 1 main.brg`
+#[derive(Debug, PartialEq)]
+struct Options {
+    mode: Mode,
+    file_name: String,
+    seed: Option<u64>,
+    trace: bool,
+    checked_arithmetic: bool,
+    // Prints how many steps the run took
+    steps: bool,
+    flush_policy: FlushPolicy,
+    output_limit: Option<usize>,
+    parse_cache: Option<String>,
+    // Prints the formatted file instead of writing it back
+    format_stdout: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, Error> {
+        let mut options = Options {
+            mode: Mode::Run,
+            file_name: String::new(),
+            seed: None,
+            trace: false,
+            checked_arithmetic: false,
+            steps: false,
+            flush_policy: FlushPolicy::EachPrint,
+            output_limit: None,
+            parse_cache: None,
+            format_stdout: false,
+        };
+        let mut file_name = None;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| failure::err_msg(format!("{} needs a value", arg)))
+            };
+            match arg.as_str() {
+                "--check" => options.mode = Mode::Check,
+                "--test" => options.mode = Mode::Test,
+                "--format" => options.mode = Mode::Format,
+                "--watch" => options.mode = Mode::Watch,
+                "--emit-hir" => options.mode = Mode::EmitHir,
+                "--emit-typed-ast-json" => options.mode = Mode::EmitTypedAstJson,
+                "--dump-symbol-table" => options.mode = Mode::DumpSymbolTable,
+                "--stdout" => options.format_stdout = true,
+                "--trace" => options.trace = true,
+                // Integer overflow is an error instead of wrapping around
+                "--checked-arithmetic" => options.checked_arithmetic = true,
+                "--steps" => options.steps = true,
+                // Only writes the output once the program is done, which is
+                // faster for programs that print a lot
+                "--batch-output" => options.flush_policy = FlushPolicy::Batch,
+                "--seed" => options.seed = Some(value()?.parse()?),
+                "--max-output-bytes" => options.output_limit = Some(value()?.parse()?),
+                "--parse-cache" => options.parse_cache = Some(value()?.clone()),
+                flag if flag.starts_with("--") => {
+                    return Err(failure::err_msg(format!("Unknown flag {}", flag)))
+                }
+                _ if file_name.is_some() => {
+                    return Err(failure::err_msg(format!("Unexpected argument {}", arg)))
+                }
+                _ => file_name = Some(arg.clone()),
             }
         }
+        options.file_name = file_name.ok_or_else(|| failure::err_msg("Missing a file name"))?;
+        Ok(options)
+    }
 
+    // Sets up a treewalker for the checked file with the runtime options
+    fn treewalker(&self, mut typechecker: TypeChecker, code: &str) -> TreeWalker {
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let mut treewalker = TreeWalker::new(functions, name_table, type_table)
+            .with_trace(self.trace)
+            .with_checked_arithmetic(self.checked_arithmetic)
+            .with_source_file(&self.file_name, code)
+            .with_flush_policy(self.flush_policy);
+        if let Some(output_limit) = self.output_limit {
+            treewalker = treewalker.with_output_limit(output_limit);
+        }
+        if let Some(seed) = self.seed {
+            treewalker.set_seed(seed);
+        }
+        if self.steps {
+            treewalker = treewalker.with_step_count();
+        }
+        treewalker
     }
 }
 
-fn format_code(code: &str) -> Result<String, Error> {
-    fs::write("out.brg", code)?;
-    let process = Command::new("rustfmt")
-        .arg("out.brg")
-        .output().expect("failed to run rustfmt");
-    Ok(fs::read_to_string("out.brg")?)
+fn run() -> Result<(), Error> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        return Repl::new().run();
+    }
+    let options = Options::parse(&args)?;
+    // Reads the file itself on every change
+    if options.mode == Mode::Watch {
+        return watch_code(&options);
+    }
+    let file_name = &options.file_name;
+    let contents = fs::read_to_string(file_name)?;
+    match options.mode {
+        Mode::Run => {
+            let parsed = match &options.parse_cache {
+                Some(cache_path) => parse_file_cached(&contents, file_name, cache_path)?,
+                None => parse_file(&contents),
+            };
+            interpret_parsed(parsed, &contents, &options)?;
+        }
+        Mode::Check => {
+            if !check_code(&contents, file_name)? {
+                std::process::exit(1);
+            }
+        }
+        Mode::Test => {
+            let (summary, passed) = test_code(&contents, file_name, DefaultIO::new())?;
+            print!("{}", summary);
+            if !passed {
+                std::process::exit(1);
+            }
+        }
+        Mode::Format => {
+            let formatted = format_code(&contents)?;
+            if options.format_stdout {
+                print!("{}", formatted);
+            } else {
+                fs::write(file_name, formatted)?;
+            }
+        }
+        Mode::Watch => unreachable!(),
+        Mode::EmitHir => println!("{}", emit_hir(&contents)?),
+        Mode::EmitTypedAstJson => {
+            if !emit_typed_ast_json(&contents, file_name)? {
+                std::process::exit(1);
+            }
+        }
+        Mode::DumpSymbolTable => eprint!("{}", dump_symbol_table(&contents)?),
+    }
+    Ok(())
 }
 
-fn interpret_expr(code: &str, file_name: &str) {
-    let writer = StandardStream::stderr(ColorChoice::Always);
-    let config = codespan_reporting::term::Config::default();
-    let file = SimpleFile::new(file_name, code);
-    let lexer = lexer::Lexer::new(code);
-    let mut parser = Parser::new(lexer);
-    let expr = match parser.expr() {
-        Ok(e) => e,
-        Err(err) => {
-            let diagnostic: Diagnostic<()> = (&err).into();
-            term::emit(&mut writer.lock(), &config, &file, &diagnostic).unwrap();
-            return;
-        }
-    };
-    let mut typechecker = TypeChecker::new(parser.get_name_table());
-    let expr_t = match typechecker.expr(expr) {
-        Ok(e) => e,
-        Err(err) => {
-            let diagnostic: Diagnostic<()> = (&err).into();
-            term::emit(&mut writer.lock(), &config, &file, &diagnostic).unwrap();
-            return;
+// Formats the source with the unparser, keeping comments. Bails
+// on any parse error since the unparser would drop the broken code
+fn format_code(code: &str) -> Result<String, Error> {
+    let mut parser = Parser::new(lexer::Lexer::new(code));
+    let program = match parser.program() {
+        Ok(program) if program.errors.is_empty() => program,
+        _ => {
+            return Err(failure::err_msg(
+                "Refusing to format a file with parse errors",
+            ))
         }
     };
-    let functions = typechecker.get_functions();
-    let mut treewalker = TreeWalker::new(functions);
-    treewalker.print_expr(&expr_t);
+    let comments = mem::take(&mut parser.lexer.comments);
+    let formatted =
+        Unparser::new(parser.get_name_table()).format_source(&program, code, &comments)?;
+    Ok(format!("{}\n", formatted))
 }
 
+fn interpret_code(code: &str, options: &Options) -> Result<(), Error> {
+    interpret_parsed(parse_file(code), code, options)
+}
 
-fn interpret_code(code: &str, file_name: &str) -> Result<(), Error> {
+fn interpret_parsed(
+    parsed: Option<(Program, NameTable)>,
+    code: &str,
+    options: &Options,
+) -> Result<(), Error> {
     let writer = StandardStream::stderr(ColorChoice::Always);
     let config = codespan_reporting::term::Config::default();
-    let file = SimpleFile::new(file_name, code);
+    let file = SimpleFile::new(&options.file_name, code);
     let mut diagnostics: Vec<Diagnostic<()>> = Vec::new();
-    if let Some((program, name_table)) = parse_file(code) {
+    if let Some((program, name_table)) = parsed {
         for error in &program.errors {
             diagnostics.push(error.into());
         }
-        let (program_t, functions) = typecheck_file(program, name_table);
+        let (program_t, typechecker) = typecheck_file(program, name_table);
         for error in &program_t.errors {
             diagnostics.push(error.into());
         }
-        let mut treewalker = TreeWalker::new(functions);
-
-        match treewalker.interpret_program(program_t) {
-            Err(e) => {
-                println!("{:?}", e);
-            }
-            _ => {}
-        };
+        let mut treewalker = options.treewalker(typechecker, code);
+        if let Err(err) = treewalker.interpret_program(program_t) {
+            diagnostics.push((&err).into());
+        }
+        // On stderr so it doesn't end up in the program's output
+        if let Some(steps) = treewalker.step_count() {
+            eprintln!("{} steps", steps);
+        }
     }
     for diagnostic in diagnostics {
         term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
@@ -143,48 +276,190 @@ fn interpret_code(code: &str, file_name: &str) -> Result<(), Error> {
     Ok(())
 }
 
-fn unparse_code(program: &Program, name_table: NameTable) -> Result<String, Error> {
-    let unparser = Unparser::new(name_table);
-    let unparsed_program = unparser.unparse_program(program)?;
+// Reruns the file every time it's saved, clearing the screen
+// first. Errors get printed without ending the watch
+fn watch_code(options: &Options) -> Result<(), Error> {
+    let file_name = &options.file_name;
+    let mut run = || {
+        print!("\x1B[2J\x1B[1;1H");
+        println!("[{}] {}", utc_time(SystemTime::now()), file_name);
+        let res = fs::read_to_string(file_name)
+            .map_err(Error::from)
+            .and_then(|contents| interpret_code(&contents, options));
+        if let Err(err) = res {
+            eprintln!("{}", err);
+        }
+    };
+    run();
+    watcher::watch_file(Path::new(file_name), &mut run)?;
+    Ok(())
+}
 
-    let format_code = |program: String| -> Result<String, Error> {
-        let formatter = Command::new("rustfmt")
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
+// HH:MM:SS in UTC, so we don't need a time zone database
+fn utc_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    format!(
+        "{:02}:{:02}:{:02}",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
 
-        let mut stdin = formatter.stdin.unwrap();
-        let mut stdout = formatter.stdout.unwrap();
+// Parses and typechecks without running anything, emitting all the
+// diagnostics. Returns false if there were any errors
+fn check_code(code: &str, file_name: &str) -> Result<bool, Error> {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+    let file = SimpleFile::new(file_name, code);
+    let (program, name_table) = match parse_file(code) {
+        Some(res) => res,
+        None => {
+            eprintln!("{}: Failed to parse file", file_name);
+            return Ok(false);
+        }
+    };
+    let mut diagnostics: Vec<Diagnostic<()>> = Vec::new();
+    for error in &program.errors {
+        diagnostics.push(error.into());
+    }
+    let (program_t, _) = typecheck_file(program, name_table);
+    for error in &program_t.errors {
+        diagnostics.push(error.into());
+    }
+    let has_errors = diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity >= Severity::Error);
+    for diagnostic in diagnostics {
+        term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+    }
+    Ok(!has_errors)
+}
 
-        stdin.write_all(program.as_bytes())?;
-        mem::drop(stdin);
-        let mut out = String::new();
-        stdout.read_to_string(&mut out)?;
+// Runs the file as a test suite, where every assert is a test case.
+// Failed asserts are collected instead of stopping the program. Returns
+// the summary and whether everything passed
+fn test_code<IO: RuntimeIO>(code: &str, file_name: &str, io: IO) -> Result<(String, bool), Error> {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+    let file = SimpleFile::new(file_name, code);
+    let (program, name_table) = match parse_file(code) {
+        Some(res) => res,
+        None => return Ok((format!("{}: Failed to parse file\n", file_name), false)),
+    };
+    let mut diagnostics: Vec<Diagnostic<()>> = Vec::new();
+    for error in &program.errors {
+        diagnostics.push(error.into());
+    }
+    let (program_t, mut typechecker) = typecheck_file(program, name_table);
+    for error in &program_t.errors {
+        diagnostics.push(error.into());
+    }
+    let compiled = !diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity >= Severity::Error);
 
-        if let Some(stderr) = formatter.stderr {
-            let mut stderr = stderr;
-            let mut errors = String::new();
-            stderr.read_to_string(&mut errors)?;
-            println!("{}", errors);
+    let mut summary = format!("{}: Failed to compile\n", file_name);
+    let mut passed = compiled;
+    if compiled {
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let mut treewalker = TreeWalker::with_io(
+            functions,
+            name_table,
+            type_table,
+            DEFAULT_MAX_CALL_DEPTH,
+            TestIO::new(io),
+        );
+        // Any other runtime error still stops the run
+        if let Err(err) = treewalker.interpret_program(program_t) {
+            diagnostics.push((&err).into());
+            passed = false;
+        }
+        let test_io = treewalker.io();
+        passed = passed && test_io.failures.is_empty();
+        summary.clear();
+        for (location, message) in &test_io.failures {
+            let start = file
+                .location((), (location.0).0)
+                .ok_or_else(|| failure::err_msg("Assertion is outside of the file"))?;
+            summary.push_str(&format!(
+                "FAILED {}:{}:{}: {}\n",
+                file_name, start.line_number, start.column_number, message
+            ));
         }
+        summary.push_str(&format!(
+            "{} passed, {} failed\n",
+            test_io.passed,
+            test_io.failures.len()
+        ));
+    }
+    for diagnostic in diagnostics {
+        term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+    }
+    Ok((summary, passed))
+}
 
-        return Ok(out);
+// Shows the program after desugaring but before typechecking
+fn emit_hir(code: &str) -> Result<String, Error> {
+    let (program, name_table) = match parse_file(code) {
+        Some(res) => res,
+        None => return Err(failure::err_msg("Failed to parse file")),
     };
+    let program = desugar::desugar_program(program);
+    Ok(Unparser::new(name_table).unparse_source(&program)?)
+}
 
-    let functions = format_code(unparsed_program.functions)?;
-    let globals_fmt = format_code(unparsed_program.global_stmts)?;
-    let functions = functions.replace("print!(", "print(");
-    let globals_fmt = globals_fmt.replace("print!(", "print(");
-
-    let start = globals_fmt.find('{').unwrap() + 1;
-    let end = globals_fmt.len() - 2;
+// Typechecks the program and shows every scope the typechecker made
+// along with the variables in it, to see why a name wasn't found
+fn dump_symbol_table(code: &str) -> Result<String, Error> {
+    let (program, name_table) = match parse_file(code) {
+        Some(res) => res,
+        None => return Err(failure::err_msg("Failed to parse file")),
+    };
+    let (_, typechecker) = typecheck_file(program, name_table);
+    let (symbol_table, name_table, type_table) = typechecker.get_tables();
+    Ok(symbol_table.dump_scopes(&name_table, &type_table))
+}
 
-    let mut globals = String::new();
-    for line in globals_fmt[start..end].trim().split('\n') {
-        globals += line.trim();
+// Prints the typed AST as JSON. If the file has errors we report
+// them instead, since the AST would be missing whatever didn't check.
+// Returns whether the JSON got printed
+fn emit_typed_ast_json(code: &str, file_name: &str) -> Result<bool, Error> {
+    let writer = StandardStream::stderr(ColorChoice::Always);
+    let config = codespan_reporting::term::Config::default();
+    let file = SimpleFile::new(file_name, code);
+    let (program, name_table) = match parse_file(code) {
+        Some(res) => res,
+        None => return Err(failure::err_msg("Failed to parse file")),
+    };
+    let mut diagnostics: Vec<Diagnostic<()>> = Vec::new();
+    for error in &program.errors {
+        diagnostics.push(error.into());
     }
-
-    Ok(format!("{}\n{}", functions, globals))
+    let (program_t, typechecker) = typecheck_file(program, name_table);
+    for error in &program_t.errors {
+        diagnostics.push(error.into());
+    }
+    let has_errors = diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity >= Severity::Error);
+    for diagnostic in diagnostics {
+        term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+    }
+    if has_errors {
+        return Ok(false);
+    }
+    let json = printer::typed_ast_json(
+        program_t,
+        typechecker.get_name_table(),
+        typechecker.get_type_table(),
+    )?;
+    println!("{}", json);
+    Ok(true)
 }
 
 impl Into<Diagnostic<()>> for &TypeError {
@@ -192,8 +467,12 @@ impl Into<Diagnostic<()>> for &TypeError {
         let loc = self.get_location();
         let start = (loc.0).0;
         let end = (loc.1).0;
-        Diagnostic::error()
-            .with_message("Type Error")
+        let message = match self.severity() {
+            Severity::Warning => "Type Warning",
+            _ => "Type Error",
+        };
+        Diagnostic::new(self.severity())
+            .with_message(message)
             .with_labels(vec![
                 Label::primary((), (start)..(end)).with_message(self.to_string())
             ])
@@ -213,12 +492,43 @@ impl Into<Diagnostic<()>> for &ParseError {
     }
 }
 
-fn typecheck_file(program: Program, name_table: NameTable) -> (ProgramT, HashMap<Name, Function>) {
+impl Into<Diagnostic<()>> for &IError {
+    fn into(self) -> Diagnostic<()> {
+        let diagnostic = Diagnostic::error().with_message("Runtime Error");
+        match self.location {
+            Some(loc) => diagnostic.with_labels(vec![
+                Label::primary((), ((loc.0).0)..((loc.1).0)).with_message(self.to_string())
+            ]),
+            None => diagnostic.with_notes(vec![self.to_string()]),
+        }
+    }
+}
+
+fn typecheck_file(program: Program, name_table: NameTable) -> (ProgramT, TypeChecker) {
     let mut typechecker = TypeChecker::new(name_table);
-    (
-        typechecker.check_program(program),
-        typechecker.get_functions(),
-    )
+    let program_t = typechecker.check_program(program);
+    (program_t, typechecker)
+}
+
+// Loads the parsed program and its names from the cache if the cache is
+// newer than the source. Otherwise parses the source and writes the cache
+fn parse_file_cached(
+    contents: &str,
+    file_name: &str,
+    cache_path: &str,
+) -> Result<Option<(Program, NameTable)>, Error> {
+    let modified = |path: &str| fs::metadata(path).and_then(|metadata| metadata.modified());
+    if let (Ok(source_time), Ok(cache_time)) = (modified(file_name), modified(cache_path)) {
+        if cache_time > source_time {
+            let cache = fs::read_to_string(cache_path)?;
+            return Ok(Some(serde_json::from_str(&cache)?));
+        }
+    }
+    let parsed = parse_file(contents);
+    if let Some((program, name_table)) = &parsed {
+        fs::write(cache_path, serde_json::to_string(&(program, name_table))?)?;
+    }
+    Ok(parsed)
 }
 
 fn parse_file(contents: &str) -> Option<(Program, NameTable)> {
@@ -230,3 +540,176 @@ fn parse_file(contents: &str) -> Option<(Program, NameTable)> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::runtime::InMemoryIO;
+    use crate::treewalker::FlushPolicy;
+    use crate::treewalker::TreeWalker;
+    use crate::utils::StringWriter;
+    use crate::{
+        check_code, dump_symbol_table, format_code, parse_file, parse_file_cached, test_code,
+        typecheck_file, Mode, Options,
+    };
+    use codespan_reporting::diagnostic::Diagnostic;
+    use codespan_reporting::files::SimpleFile;
+    use codespan_reporting::term;
+    use std::{env, fs, thread, time};
+
+    #[test]
+    fn check_reports_errors() {
+        assert!(check_code("let x: int = 1;\nprint(x + 2);", "ok.brg").unwrap());
+        assert!(!check_code("let x: int = \"a\";", "type.brg").unwrap());
+        assert!(!check_code("let x: int = ;", "parse.brg").unwrap());
+    }
+
+    #[test]
+    fn parses_options() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+            Options::parse(&args)
+        };
+        let options = parse(&["--trace", "--seed", "1", "f.brg"]).unwrap();
+        assert_eq!(options.mode, Mode::Run);
+        assert_eq!(options.file_name, "f.brg");
+        assert_eq!(options.seed, Some(1));
+        assert!(options.trace);
+
+        let options = parse(&["f.brg", "--batch-output", "--max-output-bytes", "10"]).unwrap();
+        assert_eq!(options.flush_policy, FlushPolicy::Batch);
+        assert_eq!(options.output_limit, Some(10));
+        assert!(!options.trace);
+        assert!(!options.checked_arithmetic);
+
+        let options = parse(&["--checked-arithmetic", "f.brg", "--steps"]).unwrap();
+        assert!(options.checked_arithmetic);
+        assert!(options.steps);
+
+        let options = parse(&["--format", "f.brg", "--stdout"]).unwrap();
+        assert_eq!(options.mode, Mode::Format);
+        assert!(options.format_stdout);
+
+        assert!(parse(&["--seed", "f.brg"]).is_err());
+        assert!(parse(&["--seed"]).is_err());
+        assert!(parse(&["--trace"]).is_err());
+        assert!(parse(&["--bogus", "f.brg"]).is_err());
+        assert!(parse(&["a.brg", "b.brg"]).is_err());
+    }
+
+    #[test]
+    fn format_is_idempotent() {
+        let code = "// points
+struct Point { x: int, y: float }
+fn norm(p: Point) -> float {
+let s: float = p.y * p.y; // squared
+if p.x > 0 { print(\"positive\\n\"); } else { return 0.0; };
+s
+}
+
+
+fn twice(x: int) -> int x * 2;
+let p: Point = Point { x: 1, y: 2.0 };
+let n: int = 1;
+n += twice(3);
+p.x = n;
+if p.x == 7 { print(norm(p)) }
+";
+        let formatted = format_code(code).unwrap();
+        assert_ne!(formatted, code);
+        assert_eq!(format_code(&formatted).unwrap(), formatted);
+        assert!(check_code(&formatted, "formatted.brg").unwrap());
+        assert!(format_code("let x: int = ;").is_err());
+    }
+
+    #[test]
+    fn test_mode_summary() {
+        let code =
+            "assert(1 + 1 == 2);\nassert(2 < 1, \"two is less than one\");\nprint(\"done\");";
+        let (summary, passed) = test_code(code, "suite.brg", InMemoryIO::new()).unwrap();
+        assert!(!passed);
+        assert_eq!(
+            summary,
+            "FAILED suite.brg:2:8: two is less than one\n1 passed, 1 failed\n"
+        );
+
+        let (summary, passed) = test_code("assert(true);", "ok.brg", InMemoryIO::new()).unwrap();
+        assert!(passed);
+        assert_eq!(summary, "1 passed, 0 failed\n");
+    }
+
+    #[test]
+    fn parse_cache_round_trip() {
+        let dir = env::temp_dir().join(format!("bridge-parse-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let source_path = dir.join("main.brg");
+        let cache_path = dir.join("main.json");
+        let code = "struct Point { x: int, y: int }
+fn norm(p: Point) -> int { p.x * p.x + p.y * p.y }
+let origin: Point = Point { x: 0, y: 1 };
+print(norm(origin));";
+        fs::write(&source_path, code).unwrap();
+        let _ = fs::remove_file(&cache_path);
+        // So the cache comes out strictly newer than the source
+        thread::sleep(time::Duration::from_millis(10));
+        let source_path = source_path.to_str().unwrap();
+        let cache_path = cache_path.to_str().unwrap();
+
+        let (program, name_table) = parse_file(code).unwrap();
+        let (written, _) = parse_file_cached(code, source_path, cache_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(written, program);
+        assert!(fs::metadata(cache_path).is_ok());
+
+        // Empty source, so this only passes if the cache is read back
+        let (cached, cached_names) = parse_file_cached("", source_path, cache_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(cached, program);
+        assert!(cached_names.names().eq(name_table.names()));
+        assert_eq!(cached_names.get_id("norm"), name_table.get_id("norm"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn symbol_table_dump() {
+        let code = "let x: int = 1;
+fn outer(a: int) -> int {
+  let y: int = { let z: int = a; z + x };
+  y
+}";
+        let expected = "scope 0 (global)
+  x: int
+  scope 1 (function)
+    a: int
+    scope 2 (block)
+      y: int
+      scope 3 (block)
+        z: int
+";
+        assert_eq!(dump_symbol_table(code).unwrap(), expected);
+    }
+
+    #[test]
+    fn runtime_error_diagnostic() {
+        let code = "let x: int = 0;\nlet y: int = 10 / x;";
+        let (program, name_table) = parse_file(code).unwrap();
+        let (program_t, mut typechecker) = typecheck_file(program, name_table);
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let err = TreeWalker::new(functions, name_table, type_table)
+            .interpret_program(program_t)
+            .unwrap_err();
+        assert_eq!(err.short_name, "DivisionByZero");
+
+        let diagnostic: Diagnostic<()> = (&err).into();
+        let mut out = StringWriter::new();
+        let config = term::Config::default();
+        let file = SimpleFile::new("div.brg", code);
+        term::emit(&mut out, &config, &file, &diagnostic).unwrap();
+        let out = out.to_string();
+        assert!(out.contains("div.brg:2:14"), "{}", out);
+        assert!(out.contains("^^^^^^ DivisionByZero: Cannot divide 10 by zero"));
+        assert!(!out.contains("IError"));
+    }
+}
@@ -1,8 +1,11 @@
-use crate::ast::{Expr, Loc, Name, Op, Program, Stmt, TypeDef, TypeSig, UnaryOp, Value};
+use crate::ast::{
+    check_ranges, Expr, Loc, Name, Op, Program, Stmt, TypeDef, TypeSig, UnaryOp, Value,
+};
 use crate::lexer::{Lexer, LexicalError, LocationRange, Token, TokenD};
 use crate::printer::{expected_tokens_to_string, token_to_string};
 use crate::utils::NameTable;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::fmt::Debug;
 
@@ -10,6 +13,9 @@ pub struct Parser<'input> {
     pub lexer: Lexer<'input>,
     errors: Vec<ParseError>,
     pushedback_tokens: Vec<(Token, LocationRange)>,
+    // Structs declared so far, so `P {}` and `P { x }` can be told
+    // apart from a name followed by a block
+    struct_names: HashSet<Name>,
 }
 
 #[derive(Debug, Fail, PartialEq, Clone, Serialize, Deserialize)]
@@ -46,6 +52,10 @@ pub enum ParseError {
     ComplexCallee { location: LocationRange },
     #[fail(display = "Tuple index must be positive")]
     InvalidTupleIndex { location: LocationRange },
+    #[fail(
+        display = "Comparisons can't be chained. Use parentheses, like (a < b) == c, or &&, like a < b && b < c"
+    )]
+    ChainedComparison { location: LocationRange },
 }
 
 impl ParseError {
@@ -67,6 +77,7 @@ impl ParseError {
             ParseError::TypeSigMandatory { location } => *location,
             ParseError::ComplexCallee { location } => *location,
             ParseError::InvalidTupleIndex { location } => *location,
+            ParseError::ChainedComparison { location } => *location,
         }
     }
 }
@@ -77,12 +88,24 @@ impl From<LexicalError> for ParseError {
     }
 }
 
+// The keywords that can start a top level declaration
+fn declaration_keyword(token: TokenD) -> Option<Token> {
+    match token {
+        TokenD::Struct => Some(Token::Struct),
+        TokenD::Fn => Some(Token::Fn),
+        TokenD::Let => Some(Token::Let),
+        TokenD::Export => Some(Token::Export),
+        _ => None,
+    }
+}
+
 impl<'input> Parser<'input> {
     pub fn new(lexer: Lexer) -> Parser {
         Parser {
             lexer,
             errors: Vec::new(),
             pushedback_tokens: Vec::new(),
+            struct_names: HashSet::new(),
         }
     }
 
@@ -122,6 +145,24 @@ impl<'input> Parser<'input> {
         self.pushedback_tokens.push(token);
     }
 
+    // Looks at the next two tokens without consuming them
+    fn peek2(&mut self) -> Result<(Option<TokenD>, Option<TokenD>), ParseError> {
+        let first = self.bump()?;
+        let second = self.bump()?;
+        let discriminant = |span: &Option<(Token, LocationRange)>| {
+            span.as_ref().map(|(token, _)| -> TokenD { token.into() })
+        };
+        let peeked = (discriminant(&first), discriminant(&second));
+        // Pushed back tokens come out last in, first out
+        if let Some(span) = second {
+            self.pushback(span);
+        }
+        if let Some(span) = first {
+            self.pushback(span);
+        }
+        Ok(peeked)
+    }
+
     fn match_one(
         &mut self,
         lookahead: TokenD,
@@ -162,6 +203,17 @@ impl<'input> Parser<'input> {
         tok
     }
 
+    // Whether every token has been consumed
+    pub fn is_at_end(&mut self) -> Result<bool, ParseError> {
+        match self.bump()? {
+            Some(tok) => {
+                self.pushback(tok);
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
     #[allow(dead_code)]
     fn peek(&mut self) -> Result<(), ParseError> {
         let tok = self.bump()?;
@@ -183,6 +235,42 @@ impl<'input> Parser<'input> {
         Ok(())
     }
 
+    // Skips ahead after an error in a struct to where the next
+    // declaration plausibly starts, so a missing RBrace doesn't eat
+    // the rest of the file. Stops after a closing brace or semicolon
+    // at the depth we started at, or before a struct, fn, let or
+    // export at that depth
+    fn recover_to_declaration(&mut self, err: &ParseError) -> Result<(), ParseError> {
+        // If the error was at one of those keywords we're already there,
+        // but it got consumed so put it back
+        if let ParseError::UnexpectedToken {
+            token: _,
+            token_type,
+            expected_tokens: _,
+            location,
+        } = err
+        {
+            if let Some(token) = declaration_keyword(*token_type) {
+                self.pushback((token, *location));
+                return Ok(());
+            }
+        }
+        let mut depth = 0;
+        while let Some((token, location)) = self.bump()? {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace | Token::Semicolon if depth == 0 => return Ok(()),
+                Token::RBrace => depth -= 1,
+                Token::Struct | Token::Fn | Token::Let | Token::Export if depth == 0 => {
+                    self.pushback((token, location));
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     fn lookup_op_token(&mut self, token: Token, location: LocationRange) -> Result<Op, ParseError> {
         match token {
             Token::EqualEqual => Ok(Op::EqualEqual),
@@ -195,6 +283,13 @@ impl<'input> Parser<'input> {
             Token::GreaterEqual => Ok(Op::GreaterEqual),
             Token::Less => Ok(Op::Less),
             Token::LessEqual => Ok(Op::LessEqual),
+            Token::AmpAmp => Ok(Op::And),
+            Token::PipePipe => Ok(Op::Or),
+            Token::Amp => Ok(Op::BitAnd),
+            Token::Pipe => Ok(Op::BitOr),
+            Token::Caret => Ok(Op::BitXor),
+            Token::LessLess => Ok(Op::Shl),
+            Token::GreaterGreater => Ok(Op::Shr),
             _ => Err(ParseError::InvalidOp { location, token }),
         }
     }
@@ -207,11 +302,8 @@ impl<'input> Parser<'input> {
                 match self.type_def(left) {
                     Ok(def) => type_defs.push(def),
                     Err(err) => {
+                        self.recover_to_declaration(&err)?;
                         self.errors.push(err);
-                        // Our recover token for type defs is RBrace. This isn't ideal
-                        // cause if the bug is that there is no RBrace, then we basically
-                        // fail at parsing the rest of the code. But w/e
-                        self.recover_from_error(TokenD::RBrace)?;
                     }
                 }
             } else {
@@ -220,11 +312,13 @@ impl<'input> Parser<'input> {
                     Ok(None) => {
                         let mut errors = Vec::new();
                         std::mem::swap(&mut errors, &mut self.errors);
-                        return Ok(Program {
+                        let program = Program {
                             stmts,
                             type_defs,
                             errors,
-                        });
+                        };
+                        check_ranges(&program);
+                        return Ok(program);
                     }
                     Err(err) => {
                         self.errors.push(err);
@@ -253,6 +347,7 @@ impl<'input> Parser<'input> {
 
     fn type_def(&mut self, left: LocationRange) -> Result<Loc<TypeDef>, ParseError> {
         let (id, _) = self.id()?;
+        self.struct_names.insert(id);
         self.expect(TokenD::LBrace, "type definition")?;
         let (fields, right) = self.comma::<(Name, Loc<TypeSig>)>(
             &Self::record_type_field,
@@ -278,6 +373,10 @@ impl<'input> Parser<'input> {
             Some((Token::Fn, loc)) => Some(self.function(loc)),
             Some((Token::Let, loc)) => Some(self.let_stmt(loc)),
             Some((Token::Return, loc)) => Some(self.return_stmt(loc)),
+            Some((Token::Export, loc)) => Some(self.export_stmt(loc)),
+            Some((Token::While, loc)) => Some(self.while_stmt(loc)),
+            Some((Token::Break, loc)) => Some(self.jump_stmt(Stmt::Break, loc)),
+            Some((Token::Continue, loc)) => Some(self.jump_stmt(Stmt::Continue, loc)),
             Some((Token::If, loc)) => {
                 let if_expr = self.if_expr(loc)?;
                 Some(Ok(Loc {
@@ -285,13 +384,54 @@ impl<'input> Parser<'input> {
                     inner: Stmt::Expr(if_expr),
                 }))
             }
+            Some((Token::LBrace, loc)) => Some(self.block_stmt(loc)),
             Some((Token::Ident(id), loc)) => {
                 if self.match_one(TokenD::Equal)?.is_some() {
+                    let mut targets = vec![id];
+                    // a = b = x assigns right to left
+                    while let Some((token, target_loc)) = self.match_one(TokenD::Ident)? {
+                        match token {
+                            Token::Ident(target) if self.match_one(TokenD::Equal)?.is_some() => {
+                                targets.push(target)
+                            }
+                            token => {
+                                self.pushback((token, target_loc));
+                                break;
+                            }
+                        }
+                    }
+                    let rhs = self.expr()?;
+                    self.expect(TokenD::Semicolon, "assignment statement")?;
+                    let location = LocationRange(loc.0, rhs.location.1);
+                    let inner = if targets.len() == 1 {
+                        Stmt::Asgn(id, rhs)
+                    } else {
+                        Stmt::ChainedAsgn(targets, rhs)
+                    };
+                    Some(Ok(Loc { location, inner }))
+                } else if let Some((token, op_loc)) = self.match_multiple(vec![
+                    Token::PlusEqual,
+                    Token::MinusEqual,
+                    Token::TimesEqual,
+                    Token::DivEqual,
+                ])? {
+                    let op = match token {
+                        Token::PlusEqual => Op::Plus,
+                        Token::MinusEqual => Op::Minus,
+                        Token::TimesEqual => Op::Times,
+                        Token::DivEqual => Op::Div,
+                        token => {
+                            return Err(ParseError::InvalidOp {
+                                token,
+                                location: op_loc,
+                            })
+                        }
+                    };
                     let rhs = self.expr()?;
                     self.expect(TokenD::Semicolon, "assignment statement")?;
                     Some(Ok(Loc {
                         location: LocationRange(loc.0, rhs.location.1),
-                        inner: Stmt::Asgn(id, rhs),
+                        inner: Stmt::OpAsgn(id, op, rhs),
                     }))
                 } else {
                     self.pushback((Token::Ident(id), loc));
@@ -329,6 +469,31 @@ impl<'input> Parser<'input> {
         }
     }
 
+    // A block at the start of a statement is a statement of its own,
+    // unless it ends in a value, in which case it's an expression
+    // statement like any other
+    fn block_stmt(&mut self, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
+        let block = self.expr_block(left)?;
+        if let Expr::Block(stmts, None) = block.inner {
+            self.match_one(TokenD::Semicolon)?;
+            return Ok(Loc {
+                location: block.location,
+                inner: Stmt::Block(stmts),
+            });
+        }
+        if self.is_at_end()? {
+            return Ok(Loc {
+                location: block.location,
+                inner: Stmt::Expr(block),
+            });
+        }
+        let (_, right) = self.expect(TokenD::Semicolon, "expression statement")?;
+        Ok(Loc {
+            location: LocationRange(block.location.0, right.1),
+            inner: Stmt::Expr(block),
+        })
+    }
+
     fn return_stmt(&mut self, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
         let expr = self.expr()?;
         let (_, right) = self.expect(TokenD::Semicolon, "return statement")?;
@@ -338,6 +503,54 @@ impl<'input> Parser<'input> {
         })
     }
 
+    fn while_stmt(&mut self, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
+        // Same as if, no records or blocks in the cond
+        let cond = self.logical_or()?;
+        let (_, block_left) = self.expect(TokenD::LBrace, "while loop")?;
+        let body = self.expr_block(block_left)?;
+        // Allowed so a loop reads the same inside a block as outside
+        self.match_one(TokenD::Semicolon)?;
+        Ok(Loc {
+            location: LocationRange(left.0, body.location.1),
+            inner: Stmt::While(cond, Box::new(body)),
+        })
+    }
+
+    // break or continue
+    fn jump_stmt(&mut self, stmt: Stmt, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
+        let (_, right) = self.expect(TokenD::Semicolon, "loop jump")?;
+        Ok(Loc {
+            location: LocationRange(left.0, right.1),
+            inner: stmt,
+        })
+    }
+
+    fn export_stmt(&mut self, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
+        let stmt = match self.bump()? {
+            Some((Token::Fn, loc)) => self.function(loc)?,
+            Some((Token::Let, loc)) => self.let_stmt(loc)?,
+            Some((token, location)) => {
+                return Err(ParseError::UnexpectedToken {
+                    token: token_to_string(&self.lexer.name_table, &token),
+                    token_type: token.into(),
+                    location,
+                    expected_tokens: expected_tokens_to_string(&[TokenD::Fn, TokenD::Let]),
+                })
+            }
+            None => {
+                return Err(ParseError::EndOfFile {
+                    expected_tokens: expected_tokens_to_string(&[TokenD::Fn, TokenD::Let]),
+                    expected_rule: "export statement".to_string(),
+                    location: LocationRange(self.lexer.get_location(), self.lexer.get_location()),
+                })
+            }
+        };
+        Ok(Loc {
+            location: LocationRange(left.0, stmt.location.1),
+            inner: Stmt::Export(Box::new(stmt)),
+        })
+    }
+
     fn let_stmt(&mut self, left: LocationRange) -> Result<Loc<Stmt>, ParseError> {
         let (id, id_loc) = self.id()?;
         let (type_sig, _) = self
@@ -354,6 +567,19 @@ impl<'input> Parser<'input> {
 
     fn expression_stmt(&mut self) -> Result<Loc<Stmt>, ParseError> {
         let expr = self.expr()?;
+        if let Expr::Field(..) = &expr.inner {
+            if self.match_one(TokenD::Equal)?.is_some() {
+                return self.field_asgn(expr);
+            }
+        }
+        // The last statement in a file can leave off its semicolon,
+        // so scripts can end with the value they evaluate to
+        if self.is_at_end()? {
+            return Ok(Loc {
+                location: expr.location,
+                inner: Stmt::Expr(expr),
+            });
+        }
         let (_, right) = self.expect(TokenD::Semicolon, "expression statement")?;
         Ok(Loc {
             location: LocationRange(expr.location.0, right.1),
@@ -361,29 +587,59 @@ impl<'input> Parser<'input> {
         })
     }
 
+    fn field_asgn(&mut self, lhs: Loc<Expr>) -> Result<Loc<Stmt>, ParseError> {
+        let left = lhs.location.0;
+        let (record, field) = match lhs.inner {
+            Expr::Field(record, field) => (*record, field),
+            _ => unreachable!("field_asgn called on a non-field expression"),
+        };
+        let rhs = self.expr()?;
+        let (_, right) = self.expect(TokenD::Semicolon, "assignment statement")?;
+        Ok(Loc {
+            location: LocationRange(left, right.1),
+            inner: Stmt::FieldAsgn(record, field, rhs),
+        })
+    }
+
     pub fn expr(&mut self) -> Result<Loc<Expr>, ParseError> {
         match self.bump()? {
             Some((Token::LBrace, left)) => self.expr_block(left),
             Some((Token::If, left)) => self.if_expr(left),
             Some((Token::Ident(id), left)) => {
-                if self.match_one(TokenD::LBrace)?.is_some() {
-                    self.record_literal(id, left)
-                } else {
-                    self.pushback((Token::Ident(id), left));
-                    self.equality()
+                // Only commit to a record literal if the braces start
+                // with `field:` or `field,`, or if the name is a struct
+                // we've seen and the braces are `{}` or `{ field }`.
+                // Otherwise the brace belongs to a block that comes
+                // after the name
+                if let Some(brace) = self.match_one(TokenD::LBrace)? {
+                    let is_struct = self.struct_names.contains(&id);
+                    match self.peek2()? {
+                        (Some(TokenD::Ident), Some(TokenD::Colon))
+                        | (Some(TokenD::Ident), Some(TokenD::Comma)) => {
+                            return self.record_literal(id, left)
+                        }
+                        (Some(TokenD::Ident), Some(TokenD::RBrace)) | (Some(TokenD::RBrace), _)
+                            if is_struct =>
+                        {
+                            return self.record_literal(id, left)
+                        }
+                        _ => self.pushback(brace),
+                    }
                 }
+                self.pushback((Token::Ident(id), left));
+                self.logical_or()
             }
             Some(span) => {
                 self.pushback(span);
-                self.equality()
+                self.logical_or()
             }
-            None => self.equality(),
+            None => self.logical_or(),
         }
     }
 
     fn if_expr(&mut self, left: LocationRange) -> Result<Loc<Expr>, ParseError> {
         // Yeah...I'm not allowing functions or blocks in the cond spot
-        let cond = self.equality()?;
+        let cond = self.logical_or()?;
         let (_, block_left) = self.expect(TokenD::LBrace, "if expression")?;
         let then_block = self.expr_block(block_left)?;
         let else_block = if let Some((_, else_left)) = self.match_one(TokenD::Else)? {
@@ -414,11 +670,31 @@ impl<'input> Parser<'input> {
                 });
             }
             // If we're undeniably starting a statement then
-            // parse it and push onto the vec
-            if let Some(span) =
-                self.match_multiple(vec![Token::Let, Token::Return, Token::While, Token::Export])?
-            {
-                self.pushback(span);
+            // parse it and push onto the vec. That includes
+            // assignments, which start with `x =` or `x +=`
+            let is_asgn = match self.peek2()? {
+                (Some(TokenD::Ident), Some(next)) => [
+                    TokenD::Equal,
+                    TokenD::PlusEqual,
+                    TokenD::MinusEqual,
+                    TokenD::TimesEqual,
+                    TokenD::DivEqual,
+                ]
+                .contains(&next),
+                _ => false,
+            };
+            let starts_stmt = self.match_multiple(vec![
+                Token::Let,
+                Token::Return,
+                Token::While,
+                Token::Break,
+                Token::Continue,
+                Token::Export,
+            ])?;
+            if is_asgn || starts_stmt.is_some() {
+                if let Some(span) = starts_stmt {
+                    self.pushback(span);
+                }
                 let stmt = self.stmt()?.ok_or(ParseError::EndOfFile {
                     expected_tokens: expected_tokens_to_string(&vec![
                         TokenD::Fn,
@@ -434,6 +710,31 @@ impl<'input> Parser<'input> {
             } else {
                 // Otherwise we could either be in an expr stmt or an ending expr situation
                 let expr = self.expr()?;
+                // Same as expression_stmt, `a.b = x` only shows
+                // itself once we're past the field
+                if let Expr::Field(..) = &expr.inner {
+                    if self.match_one(TokenD::Equal)?.is_some() {
+                        stmts.push(self.field_asgn(expr)?);
+                        continue;
+                    }
+                }
+                // A nested block without a value is a block statement,
+                // unless it's the last thing in this block
+                let (next, _) = self.peek2()?;
+                let is_last = next == Some(TokenD::RBrace) || next == Some(TokenD::Semicolon);
+                let expr = match expr {
+                    Loc {
+                        location,
+                        inner: Expr::Block(block_stmts, None),
+                    } if !is_last => {
+                        stmts.push(Loc {
+                            location,
+                            inner: Stmt::Block(block_stmts),
+                        });
+                        continue;
+                    }
+                    expr => expr,
+                };
                 if let Some((_, right)) = self.match_one(TokenD::Semicolon)? {
                     stmts.push(Loc {
                         location: LocationRange(expr.location.0, right.1),
@@ -466,8 +767,11 @@ impl<'input> Parser<'input> {
         self.expect(TokenD::LParen, "function parameters")?;
         let (params, params_loc) =
             self.comma(&Self::func_params, "function parameters", Token::RParen)?;
-        self.expect(TokenD::Arrow, "function return type")?;
-        let return_type = self.type_()?;
+        // Without a return type, the typechecker infers it from the body
+        let return_type = match self.match_one(TokenD::Arrow)? {
+            Some(_) => Some(self.type_()?),
+            None => None,
+        };
         let token = self.bump()?;
         let body = match token {
             Some((Token::LBrace, left)) => self.expr_block(left)?,
@@ -509,6 +813,40 @@ impl<'input> Parser<'input> {
         })
     }
 
+    fn logical_or(&mut self) -> Result<Loc<Expr>, ParseError> {
+        let mut expr = self.logical_and()?;
+        while let Some((token, loc)) = self.match_one(TokenD::PipePipe)? {
+            let op = self.lookup_op_token(token, loc)?;
+            let rhs = self.logical_and()?;
+            expr = Loc {
+                location: LocationRange(expr.location.0, rhs.location.1),
+                inner: Expr::BinOp {
+                    op,
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(expr)
+    }
+
+    fn logical_and(&mut self) -> Result<Loc<Expr>, ParseError> {
+        let mut expr = self.equality()?;
+        while let Some((token, loc)) = self.match_one(TokenD::AmpAmp)? {
+            let op = self.lookup_op_token(token, loc)?;
+            let rhs = self.equality()?;
+            expr = Loc {
+                location: LocationRange(expr.location.0, rhs.location.1),
+                inner: Expr::BinOp {
+                    op,
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(expr)
+    }
+
     fn equality(&mut self) -> Result<Loc<Expr>, ParseError> {
         let lhs = self.comparison()?;
         if let Some((token, loc)) =
@@ -530,15 +868,23 @@ impl<'input> Parser<'input> {
     }
 
     fn comparison(&mut self) -> Result<Loc<Expr>, ParseError> {
-        let lhs = self.addition()?;
-        if let Some((token, loc)) = self.match_multiple(vec![
+        let comparison_ops = vec![
             Token::GreaterEqual,
             Token::Greater,
             Token::Less,
             Token::LessEqual,
-        ])? {
+        ];
+        let lhs = self.bit_or()?;
+        if let Some((token, loc)) = self.match_multiple(comparison_ops.clone())? {
             let op = self.lookup_op_token(token, loc)?;
-            let rhs = self.addition()?;
+            let rhs = self.bit_or()?;
+            // a < b < c would otherwise fail later on as comparing
+            // a bool with c, which is a lot less clear
+            if let Some((_, next_loc)) = self.match_multiple(comparison_ops)? {
+                return Err(ParseError::ChainedComparison {
+                    location: LocationRange(lhs.location.0, next_loc.1),
+                });
+            }
             Ok(Loc {
                 location: LocationRange(lhs.location.0, rhs.location.1),
                 inner: Expr::BinOp {
@@ -552,6 +898,54 @@ impl<'input> Parser<'input> {
         }
     }
 
+    // Bitwise ops bind tighter than comparisons, like in Rust, so
+    // `x & 1 == 0` compares the masked value:
+    //   bit_or := bit_xor ("|" bit_xor)*
+    //   bit_xor := bit_and ("^" bit_and)*
+    //   bit_and := shift ("&" shift)*
+    //   shift := addition (("<<" | ">>") addition)*
+    fn bit_or(&mut self) -> Result<Loc<Expr>, ParseError> {
+        self.left_assoc(vec![Token::Pipe], &Self::bit_xor)
+    }
+
+    fn bit_xor(&mut self) -> Result<Loc<Expr>, ParseError> {
+        self.left_assoc(vec![Token::Caret], &Self::bit_and)
+    }
+
+    fn bit_and(&mut self) -> Result<Loc<Expr>, ParseError> {
+        self.left_assoc(vec![Token::Amp], &Self::shift)
+    }
+
+    fn shift(&mut self) -> Result<Loc<Expr>, ParseError> {
+        self.left_assoc(
+            vec![Token::LessLess, Token::GreaterGreater],
+            &Self::addition,
+        )
+    }
+
+    // Parses one or more operands joined by any of the operator
+    // tokens, grouping to the left
+    fn left_assoc(
+        &mut self,
+        tokens: Vec<Token>,
+        operand: &dyn Fn(&mut Self) -> Result<Loc<Expr>, ParseError>,
+    ) -> Result<Loc<Expr>, ParseError> {
+        let mut expr = operand(self)?;
+        while let Some((token, loc)) = self.match_multiple(tokens.clone())? {
+            let op = self.lookup_op_token(token, loc)?;
+            let rhs = operand(self)?;
+            expr = Loc {
+                location: LocationRange(expr.location.0, rhs.location.1),
+                inner: Expr::BinOp {
+                    op,
+                    lhs: Box::new(expr),
+                    rhs: Box::new(rhs),
+                },
+            };
+        }
+        Ok(expr)
+    }
+
     fn addition(&mut self) -> Result<Loc<Expr>, ParseError> {
         let mut expr = self.multiplication()?;
         while let Some((token, loc)) = self.match_multiple(vec![Token::Plus, Token::Minus])? {
@@ -570,10 +964,10 @@ impl<'input> Parser<'input> {
     }
 
     fn multiplication(&mut self) -> Result<Loc<Expr>, ParseError> {
-        let mut expr = self.unary()?;
+        let mut expr = self.cast()?;
         while let Some((token, loc)) = self.match_multiple(vec![Token::Times, Token::Div])? {
             let op = self.lookup_op_token(token, loc)?;
-            let rhs = self.unary()?;
+            let rhs = self.cast()?;
             expr = Loc {
                 location: LocationRange(expr.location.0, rhs.location.1),
                 inner: Expr::BinOp {
@@ -586,6 +980,20 @@ impl<'input> Parser<'input> {
         Ok(expr)
     }
 
+    // expr as type. Binds looser than unary ops, so -x as float
+    // is (-x) as float
+    fn cast(&mut self) -> Result<Loc<Expr>, ParseError> {
+        let mut expr = self.unary()?;
+        while self.match_one(TokenD::As)?.is_some() {
+            let type_sig = self.type_()?;
+            expr = Loc {
+                location: LocationRange(expr.location.0, type_sig.location.1),
+                inner: Expr::Cast(Box::new(expr), type_sig),
+            }
+        }
+        Ok(expr)
+    }
+
     fn unary(&mut self) -> Result<Loc<Expr>, ParseError> {
         if let Some((token, left)) = self.match_multiple(vec![Token::Bang, Token::Minus])? {
             let op = match token {
@@ -701,6 +1109,10 @@ impl<'input> Parser<'input> {
                     value: Value::Bool(true),
                 },
             }),
+            Token::None => Ok(Loc {
+                location,
+                inner: Expr::Primary { value: Value::None },
+            }),
             Token::False => Ok(Loc {
                 location,
                 inner: Expr::Primary {
@@ -725,7 +1137,15 @@ impl<'input> Parser<'input> {
                     value: Value::String(s),
                 },
             }),
-            // Parsing tuple or grouping
+            Token::Char(c) => Ok(Loc {
+                location,
+                inner: Expr::Primary {
+                    value: Value::Char(c),
+                },
+            }),
+            // Parsing tuple or grouping. A comma after the first
+            // expr makes it a tuple, so `(1,)` is a one element
+            // tuple while `(1)` is just 1
             Token::LParen => {
                 let expr = self.expr()?;
                 if self.match_one(TokenD::Comma)?.is_some() {
@@ -742,6 +1162,14 @@ impl<'input> Parser<'input> {
                     Ok(expr)
                 }
             }
+            Token::LBracket => {
+                let (elems, right) =
+                    self.comma::<Loc<Expr>>(&Self::expr, "array", Token::RBracket)?;
+                Ok(Loc {
+                    location: LocationRange(location.0, right.1),
+                    inner: Expr::Array(elems),
+                })
+            }
             Token::Ident(name) => Ok(Loc {
                 location,
                 inner: Expr::Var { name },
@@ -784,15 +1212,15 @@ impl<'input> Parser<'input> {
 
     fn record_field(&mut self) -> Result<(Name, Loc<Expr>), ParseError> {
         let (field_name, name_loc) = self.id()?;
-        // If we find a comma, we treat `foo,` as `foo: foo,`
-        let expr = if self.match_one(TokenD::Comma)?.is_some() {
+        // Without a colon we treat `foo` as `foo: foo`. The comma
+        // after it is left for `comma`
+        let expr = if self.match_one(TokenD::Colon)?.is_some() {
+            self.expr()?
+        } else {
             Loc {
                 location: name_loc,
                 inner: Expr::Var { name: field_name },
             }
-        } else {
-            self.expect(TokenD::Colon, "record")?;
-            self.expr()?
         };
         Ok((field_name, expr))
     }
@@ -807,7 +1235,25 @@ impl<'input> Parser<'input> {
         }
     }
 
+    // Parses a bare type signature like `[(int, float)]`, without
+    // the leading colon
+    pub fn parse_type_sig(&mut self) -> Result<Loc<TypeSig>, ParseError> {
+        self.type_()
+    }
+
+    // A type followed by any number of ?s, e.g. int??
     fn type_(&mut self) -> Result<Loc<TypeSig>, ParseError> {
+        let mut sig = self.base_type()?;
+        while let Some((_, right)) = self.match_one(TokenD::Question)? {
+            sig = Loc {
+                location: LocationRange(sig.location.0, right.1),
+                inner: TypeSig::Optional(Box::new(sig)),
+            };
+        }
+        Ok(sig)
+    }
+
+    fn base_type(&mut self) -> Result<Loc<TypeSig>, ParseError> {
         let token = self.bump()?;
         match token {
             Some((Token::Ident(name), location)) => {
@@ -828,7 +1274,13 @@ impl<'input> Parser<'input> {
             Some((Token::LParen, left)) => {
                 let (entries, right) =
                     self.comma::<Loc<TypeSig>>(&Self::type_, "type", Token::RParen)?;
-                if entries.len() == 0 {
+                if self.match_one(TokenD::Arrow)?.is_some() {
+                    let return_type = self.type_()?;
+                    Ok(Loc {
+                        location: LocationRange(left.0, return_type.location.1),
+                        inner: TypeSig::Arrow(entries, Box::new(return_type)),
+                    })
+                } else if entries.is_empty() {
                     Ok(Loc {
                         location: LocationRange(left.0, right.1),
                         inner: TypeSig::Empty,
@@ -854,6 +1306,10 @@ impl<'input> Parser<'input> {
         }
     }
 
+    // Parses a comma separated list up to and including the end token:
+    //   list := end | elem ("," elem)* ","? end
+    // so a trailing comma is allowed but a lone comma isn't. Returns
+    // the location of the end token
     fn comma<T: Debug>(
         &mut self,
         parse_fn: &dyn Fn(&mut Self) -> Result<T, ParseError>,
@@ -861,10 +1317,10 @@ impl<'input> Parser<'input> {
         end_token: Token,
     ) -> Result<(Vec<T>, LocationRange), ParseError> {
         let mut elems: Vec<T> = Vec::new();
-        if let Some((_, right)) = self.match_one((&end_token).into())? {
-            return Ok((elems, right));
-        }
         loop {
+            if let Some((_, right)) = self.match_one((&end_token).into())? {
+                return Ok((elems, right));
+            }
             elems.push(parse_fn(self)?);
             if let Some((_, right)) = self.match_one((&end_token).into())? {
                 return Ok((elems, right));
@@ -876,9 +1332,10 @@ impl<'input> Parser<'input> {
 
 #[cfg(test)]
 mod tests {
-    use ast::{Expr, Loc, Op, Stmt, TypeSig, Value};
-    use lexer::{Lexer, Location, LocationRange};
-    use parser::{ParseError, Parser};
+    use crate::ast::{check_ranges, Expr, Loc, Op, Program, Stmt, TypeSig, UnaryOp, Value};
+    use crate::lexer::{Lexer, Location, LocationRange, TokenD};
+    use crate::parser::{ParseError, Parser};
+    use crate::utils::NameTable;
     use std::ffi::OsStr;
     use std::fs;
     use std::fs::File;
@@ -913,31 +1370,31 @@ mod tests {
     fn literal() -> Result<(), failure::Error> {
         let expected = vec![
             Loc {
-                location: LocationRange(Location(1, 1), Location(1, 3)),
+                location: LocationRange(Location(0), Location(2)),
                 inner: Expr::Primary {
                     value: Value::Integer(10),
                 },
             },
             Loc {
-                location: LocationRange(Location(1, 4), Location(1, 8)),
+                location: LocationRange(Location(3), Location(7)),
                 inner: Expr::Primary {
                     value: Value::Float(10.2),
                 },
             },
             Loc {
-                location: LocationRange(Location(1, 9), Location(1, 13)),
+                location: LocationRange(Location(8), Location(12)),
                 inner: Expr::Primary {
                     value: Value::Bool(true),
                 },
             },
             Loc {
-                location: LocationRange(Location(1, 14), Location(1, 19)),
+                location: LocationRange(Location(13), Location(18)),
                 inner: Expr::Primary {
                     value: Value::Bool(false),
                 },
             },
             Loc {
-                location: LocationRange(Location(1, 20), Location(1, 27)),
+                location: LocationRange(Location(19), Location(26)),
                 inner: Expr::Primary {
                     value: Value::String("hello".into()),
                 },
@@ -954,26 +1411,28 @@ mod tests {
 
     #[test]
     fn id() -> Result<(), ParseError> {
+        // Ids for new names start after the builtins
+        let foo = NameTable::new().insert("foo".to_string());
         let expected = vec![
             Loc {
-                location: LocationRange(Location(1, 1), Location(1, 4)),
-                inner: Expr::Var { name: 0 },
+                location: LocationRange(Location(0), Location(3)),
+                inner: Expr::Var { name: foo },
             },
             Loc {
-                location: LocationRange(Location(1, 5), Location(1, 8)),
-                inner: Expr::Var { name: 1 },
+                location: LocationRange(Location(4), Location(7)),
+                inner: Expr::Var { name: foo + 1 },
             },
             Loc {
-                location: LocationRange(Location(1, 9), Location(1, 12)),
-                inner: Expr::Var { name: 1 },
+                location: LocationRange(Location(8), Location(11)),
+                inner: Expr::Var { name: foo + 1 },
             },
             Loc {
-                location: LocationRange(Location(1, 13), Location(1, 16)),
-                inner: Expr::Var { name: 2 },
+                location: LocationRange(Location(12), Location(15)),
+                inner: Expr::Var { name: foo + 2 },
             },
             Loc {
-                location: LocationRange(Location(1, 17), Location(1, 20)),
-                inner: Expr::Var { name: 3 },
+                location: LocationRange(Location(16), Location(19)),
+                inner: Expr::Var { name: foo + 3 },
             },
         ];
         let source = "foo bar bar baz bat";
@@ -982,64 +1441,31 @@ mod tests {
         for i in 0..5 {
             assert_eq!(expected[i], parser.primary()?);
         }
-        assert_eq!("foo", parser.lexer.name_table.get_str(&0));
-        assert_eq!("bar", parser.lexer.name_table.get_str(&1));
-        assert_eq!("baz", parser.lexer.name_table.get_str(&2));
-        assert_eq!("bat", parser.lexer.name_table.get_str(&3));
-        Ok(())
-    }
-
-    #[test]
-    fn pattern() -> Result<(), ParseError> {
-        let expected = vec![
-            Pat::Id(0, None, LocationRange(Location(1, 1), Location(1, 4))),
-            Pat::Id(
-                1,
-                Some(TypeSig::Name(2)),
-                LocationRange(Location(1, 5), Location(1, 13)),
-            ),
-            Pat::Tuple(
-                vec![
-                    Pat::Id(0, None, LocationRange(Location(1, 15), Location(1, 18))),
-                    Pat::Id(1, None, LocationRange(Location(1, 20), Location(1, 23))),
-                ],
-                LocationRange(Location(1, 14), Location(1, 24)),
-            ),
-            Pat::Record(
-                vec![0, 1, 2],
-                Some(TypeSig::Name(3)),
-                LocationRange(Location(1, 26), Location(1, 46)),
-            ),
-        ];
-        let source = "foo bar: int (foo, bar) { foo, bar, baz }: A";
-        let lexer = Lexer::new(&source);
-        let mut parser = Parser::new(lexer);
-        for i in 0..3 {
-            assert_eq!(expected[i], parser.pattern()?);
-        }
-        assert_eq!("foo", parser.lexer.name_table.get_str(&0));
-        assert_eq!("bar", parser.lexer.name_table.get_str(&1));
+        assert_eq!("foo", parser.lexer.name_table.get_str(&foo));
+        assert_eq!("bar", parser.lexer.name_table.get_str(&(foo + 1)));
+        assert_eq!("baz", parser.lexer.name_table.get_str(&(foo + 2)));
+        assert_eq!("bat", parser.lexer.name_table.get_str(&(foo + 3)));
         Ok(())
     }
 
     #[test]
     fn arithmetic() -> Result<(), ParseError> {
         let expected = Loc {
-            location: LocationRange(Location(1, 1), Location(1, 16)),
+            location: LocationRange(Location(0), Location(15)),
             inner: Expr::BinOp {
                 op: Op::Plus,
                 lhs: Box::new(Loc {
-                    location: LocationRange(Location(1, 1), Location(1, 7)),
+                    location: LocationRange(Location(0), Location(6)),
                     inner: Expr::BinOp {
                         op: Op::Times,
                         lhs: Box::new(Loc {
-                            location: LocationRange(Location(1, 1), Location(1, 3)),
+                            location: LocationRange(Location(0), Location(2)),
                             inner: Expr::Primary {
                                 value: Value::Integer(10),
                             },
                         }),
                         rhs: Box::new(Loc {
-                            location: LocationRange(Location(1, 6), Location(1, 7)),
+                            location: LocationRange(Location(5), Location(6)),
                             inner: Expr::Primary {
                                 value: Value::Integer(2),
                             },
@@ -1047,21 +1473,21 @@ mod tests {
                     },
                 }),
                 rhs: Box::new(Loc {
-                    location: LocationRange(Location(1, 10), Location(1, 16)),
+                    location: LocationRange(Location(9), Location(15)),
                     inner: Expr::BinOp {
                         op: Op::Div,
                         lhs: Box::new(Loc {
-                            location: LocationRange(Location(1, 10), Location(1, 11)),
+                            location: LocationRange(Location(9), Location(10)),
                             inner: Expr::Primary {
                                 value: Value::Integer(3),
                             },
                         }),
                         rhs: Box::new(Loc {
-                            location: LocationRange(Location(1, 14), Location(1, 16)),
+                            location: LocationRange(Location(13), Location(15)),
                             inner: Expr::UnaryOp {
-                                op: Op::Minus,
+                                op: UnaryOp::Minus,
                                 rhs: Box::new(Loc {
-                                    location: LocationRange(Location(1, 15), Location(1, 16)),
+                                    location: LocationRange(Location(14), Location(15)),
                                     inner: Expr::Primary {
                                         value: Value::Integer(4),
                                     },
@@ -1081,36 +1507,319 @@ mod tests {
 
     #[test]
     fn function() -> Result<(), ParseError> {
+        let foo = NameTable::new().insert("foo".to_string());
+        let (a, int) = (foo + 1, foo + 2);
         let expected = Loc {
-            location: LocationRange(Location(1, 1), Location(1, 12)),
-            inner: Expr::Function {
-                params: Pat::Id(0, None, LocationRange(Location(1, 2), Location(1, 3))),
-                return_type: None,
-                body: Box::new(Loc {
-                    location: LocationRange(Location(1, 7), Location(1, 12)),
-                    inner: Stmt::Return(Loc {
-                        location: LocationRange(Location(1, 7), Location(1, 12)),
-                        inner: Expr::BinOp {
-                            op: Op::Plus,
-                            lhs: Box::new(Loc {
-                                location: LocationRange(Location(1, 7), Location(1, 8)),
-                                inner: Expr::Var { name: 0 },
-                            }),
-                            rhs: Box::new(Loc {
-                                location: LocationRange(Location(1, 11), Location(1, 12)),
-                                inner: Expr::Primary {
-                                    value: Value::Integer(1),
-                                },
-                            }),
+            location: LocationRange(Location(0), Location(31)),
+            inner: Stmt::Function {
+                name: foo,
+                params: vec![Loc {
+                    location: LocationRange(Location(7), Location(13)),
+                    inner: (
+                        a,
+                        Loc {
+                            location: LocationRange(Location(10), Location(13)),
+                            inner: TypeSig::Name(int),
                         },
-                    }),
+                    ),
+                }],
+                return_type: Some(Loc {
+                    location: LocationRange(Location(18), Location(21)),
+                    inner: TypeSig::Name(int),
+                }),
+                body: Box::new(Loc {
+                    location: LocationRange(Location(22), Location(31)),
+                    inner: Expr::Block(
+                        vec![],
+                        Some(Box::new(Loc {
+                            location: LocationRange(Location(24), Location(29)),
+                            inner: Expr::BinOp {
+                                op: Op::Plus,
+                                lhs: Box::new(Loc {
+                                    location: LocationRange(Location(24), Location(25)),
+                                    inner: Expr::Var { name: a },
+                                }),
+                                rhs: Box::new(Loc {
+                                    location: LocationRange(Location(28), Location(29)),
+                                    inner: Expr::Primary {
+                                        value: Value::Integer(1),
+                                    },
+                                }),
+                            },
+                        })),
+                    ),
                 }),
             },
         };
-        let source = "\\a => a + 1";
+        let source = "fn foo(a: int) -> int { a + 1 }";
         let lexer = Lexer::new(&source);
         let mut parser = Parser::new(lexer);
-        assert_eq!(expected, parser.expr()?);
+        assert_eq!(Some(expected), parser.stmt()?);
+        Ok(())
+    }
+
+    #[test]
+    fn arrow_type() -> Result<(), ParseError> {
+        let mut name_table = NameTable::new();
+        let (int, bool_) = (
+            name_table.insert("int".to_string()),
+            name_table.insert("bool".to_string()),
+        );
+        let expected = Loc {
+            location: LocationRange(Location(0), Location(13)),
+            inner: TypeSig::Arrow(
+                vec![Loc {
+                    location: LocationRange(Location(1), Location(4)),
+                    inner: TypeSig::Name(int),
+                }],
+                Box::new(Loc {
+                    location: LocationRange(Location(9), Location(13)),
+                    inner: TypeSig::Name(bool_),
+                }),
+            ),
+        };
+        let mut parser = Parser::new(Lexer::new("(int) -> bool"));
+        assert_eq!(parser.type_()?, expected);
+
+        let mut parser = Parser::new(Lexer::new("(int, float)"));
+        match parser.type_()?.inner {
+            TypeSig::Tuple(entries) => assert_eq!(entries.len(), 2),
+            sig => panic!("expected tuple type, got {:?}", sig),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chained_comparison() {
+        let parse = |source: &str| Parser::new(Lexer::new(source)).expr();
+        assert_eq!(
+            parse("1 < 2 < 3"),
+            Err(ParseError::ChainedComparison {
+                location: LocationRange(Location(0), Location(7)),
+            })
+        );
+        assert!(parse("1 >= 2 > 3").is_err());
+        assert!(parse("(1 < 2) == true").is_ok());
+        assert!(parse("1 < 2 && 2 < 3").is_ok());
+    }
+
+    #[test]
+    fn parse_type_sig() -> Result<(), ParseError> {
+        let mut name_table = NameTable::new();
+        let (int, float) = (
+            name_table.insert("int".to_string()),
+            name_table.insert("float".to_string()),
+        );
+        let expected = Loc {
+            location: LocationRange(Location(0), Location(14)),
+            inner: TypeSig::Array(Box::new(Loc {
+                location: LocationRange(Location(1), Location(13)),
+                inner: TypeSig::Tuple(vec![
+                    Loc {
+                        location: LocationRange(Location(2), Location(5)),
+                        inner: TypeSig::Name(int),
+                    },
+                    Loc {
+                        location: LocationRange(Location(7), Location(12)),
+                        inner: TypeSig::Name(float),
+                    },
+                ]),
+            })),
+        };
+        let mut parser = Parser::new(Lexer::new("[(int, float)]"));
+        assert_eq!(parser.parse_type_sig()?, expected);
+        assert!(parser.is_at_end()?);
+        Ok(())
+    }
+
+    #[test]
+    fn export() -> Result<(), ParseError> {
+        let mut parser = Parser::new(Lexer::new("export let x: int = 1;"));
+        match parser.stmt()?.map(|stmt| stmt.inner) {
+            Some(Stmt::Export(inner)) => match inner.inner {
+                Stmt::Def(..) => {}
+                stmt => panic!("expected def, got {:?}", stmt),
+            },
+            stmt => panic!("expected export, got {:?}", stmt),
+        }
+
+        let mut parser = Parser::new(Lexer::new("export 1 + 2;"));
+        let program = parser.program()?;
+        assert!(program.stmts.is_empty());
+        match &program.errors[..] {
+            [ParseError::UnexpectedToken { token, .. }] => assert_eq!(token, "1"),
+            errors => panic!("expected unexpected token error, got {:?}", errors),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn struct_missing_rbrace() -> Result<(), ParseError> {
+        let source = "struct P {
+  x: int
+fn f(a: int) -> int { a + 1 }
+struct Q { y: int z: int }
+let b: int = f(1);";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program()?;
+        assert!(program.type_defs.is_empty());
+        match &program.errors[..] {
+            [ParseError::UnexpectedToken {
+                token_type: TokenD::Fn,
+                ..
+            }, ParseError::UnexpectedToken {
+                token_type: TokenD::Ident,
+                ..
+            }] => {}
+            errors => panic!("expected two unexpected token errors, got {:?}", errors),
+        }
+        match &program.stmts[..] {
+            [Loc {
+                inner: Stmt::Function { .. },
+                ..
+            }, Loc {
+                inner: Stmt::Def(..),
+                ..
+            }] => {}
+            stmts => panic!("expected a function and a def, got {:?}", stmts),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sample_ranges_are_forwards() -> Result<(), failure::Error> {
+        // program() runs check_ranges itself in debug builds, so this
+        // panics if any node in the samples has an inverted range
+        for entry in fs::read_dir("tests")? {
+            let path = entry?.path();
+            if path.extension() == Some(OsStr::new("brg")) {
+                let source = fs::read_to_string(&path)?;
+                let program = Parser::new(Lexer::new(&source)).program()?;
+                check_ranges(&program);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[should_panic(expected = "Inverted range")]
+    fn inverted_range_panics() {
+        let program = Program {
+            stmts: vec![Loc {
+                location: LocationRange(Location(5), Location(2)),
+                inner: Stmt::Expr(Loc {
+                    location: LocationRange(Location(0), Location(1)),
+                    inner: Expr::Primary {
+                        value: Value::Integer(1),
+                    },
+                }),
+            }],
+            type_defs: Vec::new(),
+            errors: Vec::new(),
+        };
+        check_ranges(&program);
+    }
+
+    #[test]
+    fn record_literal_or_block() -> Result<(), ParseError> {
+        let mut parser = Parser::new(Lexer::new("Point { x: 1, y: 2 }"));
+        match parser.expr()?.inner {
+            Expr::Record { fields, .. } => assert_eq!(fields.len(), 2),
+            expr => panic!("expected record, got {:?}", expr),
+        }
+
+        // The name is its own expression and the braces are a block
+        let mut parser = Parser::new(Lexer::new("x { 1; }"));
+        match parser.expr()?.inner {
+            Expr::Var { .. } => {}
+            expr => panic!("expected var, got {:?}", expr),
+        }
+        match parser.expr()?.inner {
+            Expr::Block(stmts, None) => assert_eq!(stmts.len(), 1),
+            expr => panic!("expected block, got {:?}", expr),
+        }
+
+        let mut parser = Parser::new(Lexer::new("x { y }"));
+        parser.expr()?;
+        match parser.expr()?.inner {
+            Expr::Block(stmts, Some(_)) => assert!(stmts.is_empty()),
+            expr => panic!("expected block, got {:?}", expr),
+        }
+
+        // Once the name is a struct, `{}` and `{ x }` are records
+        let source = "struct P { x: int }\nlet a: P = P {};\nlet x: int = 1;\nlet b: P = P { x };";
+        let program = Parser::new(Lexer::new(source)).program()?;
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let fields: Vec<_> = program
+            .stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.inner {
+                Stmt::Def(_, _, rhs) => match &rhs.inner {
+                    Expr::Record { fields, .. } => Some(fields.len()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(fields, vec![0, 1]);
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_commas() -> Result<(), ParseError> {
+        let parse = |source: &str| Parser::new(Lexer::new(source)).expr();
+        for source in &["(1, 2)", "(1, 2,)"] {
+            match parse(source)?.inner {
+                Expr::Tuple(elems) => assert_eq!(elems.len(), 2),
+                expr => panic!("expected tuple, got {:?}", expr),
+            }
+        }
+        match parse("(1,)")?.inner {
+            Expr::Tuple(elems) => assert_eq!(elems.len(), 1),
+            expr => panic!("expected tuple, got {:?}", expr),
+        }
+        match parse("(1)")?.inner {
+            Expr::Primary { .. } => {}
+            expr => panic!("expected grouping, got {:?}", expr),
+        }
+        for source in &["[1, 2]", "[1, 2,]"] {
+            match parse(source)?.inner {
+                Expr::Array(elems) => assert_eq!(elems.len(), 2),
+                expr => panic!("expected array, got {:?}", expr),
+            }
+        }
+        for source in &["f(1, 2)", "f(1, 2,)"] {
+            match parse(source)?.inner {
+                Expr::Call { args, .. } => assert_eq!(args.len(), 2),
+                expr => panic!("expected call, got {:?}", expr),
+            }
+        }
+        let records = [
+            "P { x: 1, y: 2 }",
+            "P { x: 1, y: 2, }",
+            "P { x, y }",
+            "P { x, y, }",
+        ];
+        for source in &records {
+            match parse(source)?.inner {
+                Expr::Record { fields, .. } => assert_eq!(fields.len(), 2),
+                expr => panic!("expected record, got {:?}", expr),
+            }
+        }
+        let functions = [
+            "fn f(x: int, y: int) -> int { x }",
+            "fn f(x: int, y: int,) -> int { x }",
+        ];
+        for source in &functions {
+            let program = Parser::new(Lexer::new(source)).program()?;
+            match &program.stmts[0].inner {
+                Stmt::Function { params, .. } => assert_eq!(params.len(), 2),
+                stmt => panic!("expected function, got {:?}", stmt),
+            }
+        }
+        // A comma needs something before it
+        assert!(parse("[,]").is_err());
+        assert!(parse("f(1,,)").is_err());
         Ok(())
     }
 }
@@ -1,10 +1,181 @@
-use crate::ast::{Type, TypeId};
+use crate::ast::{Name, ProgramT, Type, TypeId};
 use crate::lexer::{Token, TokenD};
+use crate::runtime::{IError, Memory, MemorySnapshot, Var, VarPointer};
+use crate::treewalker::{field_offset, read_char, read_optional, read_string};
 use crate::utils::{NameTable, TypeTable};
 use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+// JSON dump of a typechecked program for tooling. The shape is
+// { "program": ProgramT, "types": [string] } where types[id] is the
+// resolved name of the type with that TypeId, so consumers don't
+// need their own copy of the type table
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct TypedAstDump {
+    pub program: ProgramT,
+    pub types: Vec<String>,
+}
+
+pub fn typed_ast_json(
+    program: ProgramT,
+    name_table: &NameTable,
+    type_table: &TypeTable,
+) -> Result<String, serde_json::Error> {
+    let types = (0..type_table.len())
+        .map(|type_id| type_to_string(name_table, type_table, type_id))
+        .collect();
+    serde_json::to_string_pretty(&TypedAstDump { program, types })
+}
+
+// Lists the variables in scope, then the stack and heap vars in a
+// snapshot with their bytes, for the debugger. Memory doesn't know
+// what its vars hold, so the walker's variables give the types:
+// a var that a variable points to gets decoded as that type
+pub fn render_snapshot(
+    snapshot: &MemorySnapshot,
+    name_table: &NameTable,
+    type_table: &TypeTable,
+    variables: &[(Name, TypeId, u64)],
+) -> String {
+    let mut out = String::new();
+    if !variables.is_empty() {
+        out.push_str("variables:\n");
+        for (name, type_id, value) in variables {
+            out.push_str(&format!(
+                "  {}: {} = {}\n",
+                name_table.get_str(name),
+                type_to_string(name_table, type_table, *type_id),
+                format_word(type_table, *value, *type_id)
+            ));
+        }
+    }
+    let printer = SnapshotPrinter {
+        name_table,
+        type_table,
+        variables,
+    };
+    printer.render_vars(&mut out, true, snapshot.stack_data, snapshot.stack_vars);
+    printer.render_vars(&mut out, false, snapshot.heap_data, snapshot.heap_vars);
+    out
+}
+
+struct SnapshotPrinter<'a> {
+    name_table: &'a NameTable,
+    type_table: &'a TypeTable,
+    variables: &'a [(Name, TypeId, u64)],
+}
+
+impl<'a> SnapshotPrinter<'a> {
+    fn render_vars(&self, out: &mut String, is_stack: bool, data: &[u8], vars: &[Var]) {
+        out.push_str(if is_stack { "stack:\n" } else { "heap:\n" });
+        if vars.is_empty() {
+            out.push_str("  (empty)\n");
+        }
+        for (i, var) in vars.iter().enumerate() {
+            let bytes = &data[var.idx..var.upper()];
+            let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).join(" ");
+            // Var pointers count from 1
+            let owner = self.variables.iter().find(|(_, _, value)| {
+                let ptr: VarPointer = (*value).into();
+                ptr.is_stack() == is_stack && ptr.var_idx() == i + 1
+            });
+            let value = match owner {
+                Some((name, type_id, _)) => format!(
+                    "  {}: {}",
+                    self.name_table.get_str(name),
+                    self.format_var(*var, bytes, *type_id)
+                ),
+                None => String::new(),
+            };
+            out.push_str(&format!(
+                "  #{:<3} {:>3} bytes  {}{}\n",
+                i + 1,
+                var.len,
+                hex,
+                value
+            ));
+        }
+    }
+
+    // Decodes a var's bytes given the type of the variable pointing
+    // at it. Falls back to just the type if the layout doesn't fit
+    fn format_var(&self, var: Var, bytes: &[u8], type_id: TypeId) -> String {
+        let decoded = match self.type_table.resolve(type_id) {
+            Type::String => std::str::from_utf8(&bytes[..bytes.len().saturating_sub(1)])
+                .ok()
+                .map(|string| format!("{:?}", string)),
+            Type::Tuple(types) => self
+                .format_words(bytes, types)
+                .map(|words| format!("({})", words.join(", "))),
+            Type::Record(fields) => {
+                let types: Vec<_> = fields.iter().map(|(_, type_id)| *type_id).collect();
+                self.format_words(bytes, &types).map(|words| {
+                    let fields = fields.iter().zip(words).map(|((name, _), word)| {
+                        format!("{}: {}", self.name_table.get_str(name), word)
+                    });
+                    format!("{{ {} }}", fields.format(", "))
+                })
+            }
+            Type::Array(elem_type) => {
+                let types = vec![*elem_type; var.meta as usize];
+                self.format_words(bytes, &types)
+                    .map(|words| format!("[{}]", words.join(", ")))
+            }
+            Type::Optional(payload_type) if bytes.len() == 9 => {
+                if bytes[0] == 0 {
+                    Some("none".to_string())
+                } else {
+                    self.format_words(&bytes[1..], &[*payload_type])
+                        .map(|words| words.join(""))
+                }
+            }
+            _ => None,
+        };
+        let type_str = type_to_string(self.name_table, self.type_table, type_id);
+        match decoded {
+            Some(decoded) => format!("{} = {}", type_str, decoded),
+            None => type_str,
+        }
+    }
+
+    fn format_words(&self, bytes: &[u8], types: &[TypeId]) -> Option<Vec<String>> {
+        if bytes.len() != types.len() * 8 {
+            return None;
+        }
+        let words = bytes.chunks(8).zip(types).map(|(chunk, type_id)| {
+            let mut word = [0; 8];
+            word.copy_from_slice(chunk);
+            format_word(self.type_table, u64::from_ne_bytes(word), *type_id)
+        });
+        Some(words.collect())
+    }
+}
+
+// Shows a single word as the given type. Anything that lives in
+// memory is shown as the var it points to
+fn format_word(type_table: &TypeTable, word: u64, type_id: TypeId) -> String {
+    match type_table.resolve(type_id) {
+        Type::Unit => "()".to_string(),
+        Type::Int => (word as i64).to_string(),
+        Type::Float => f64::from_bits(word).to_string(),
+        Type::Bool => (word != 0).to_string(),
+        Type::Char => match read_char(word) {
+            Ok(c) => format!("{:?}", c),
+            Err(_) => format!("<invalid char {}>", word),
+        },
+        _ => {
+            let ptr: VarPointer = word.into();
+            let region = if ptr.is_stack() { "stack" } else { "heap" };
+            format!("{} #{}", region, ptr.var_idx())
+        }
+    }
+}
 
 pub fn type_to_string(name_table: &NameTable, type_table: &TypeTable, type_id: TypeId) -> String {
-    match type_table.get_type(type_id) {
+    if let Some(name) = type_table.struct_name(type_id) {
+        return name_table.get_str(&name).to_string();
+    }
+    match type_table.resolve(type_id) {
         Type::Unit => "()".to_string(),
         Type::Int => "int".to_string(),
         Type::Float => "float".to_string(),
@@ -12,6 +183,7 @@ pub fn type_to_string(name_table: &NameTable, type_table: &TypeTable, type_id: T
         Type::Char => "char".to_string(),
         Type::String => "string".to_string(),
         Type::Array(type_id) => format!("[{}]", type_to_string(name_table, type_table, *type_id)),
+        Type::Optional(type_id) => format!("{}?", type_to_string(name_table, type_table, *type_id)),
         Type::Arrow(params, return_type) => {
             let params_str = params
                 .iter()
@@ -47,6 +219,7 @@ pub fn token_to_string(name_table: &NameTable, token: &Token) -> String {
     match token {
         Token::False => "false".to_string(),
         Token::True => "true".to_string(),
+        Token::None => "none".to_string(),
         Token::Else => "else".to_string(),
         Token::Export => "export".to_string(),
         Token::For => "for".to_string(),
@@ -55,7 +228,11 @@ pub fn token_to_string(name_table: &NameTable, token: &Token) -> String {
         Token::Struct => "struct".to_string(),
         Token::Let => "let".to_string(),
         Token::While => "while".to_string(),
-        Token::Fn => "fun".to_string(),
+        Token::Break => "break".to_string(),
+        Token::Continue => "continue".to_string(),
+        Token::Fn => "fn".to_string(),
+        Token::Char(c) => format!("{:?}", c),
+        Token::As => "as".to_string(),
         Token::Ident(i) => format!("<{}>", name_table.get_str(i)),
         Token::Float(f) => format!("{}", f),
         Token::Integer(i) => format!("{}", i),
@@ -67,16 +244,20 @@ pub fn token_to_string(name_table: &NameTable, token: &Token) -> String {
         Token::RParen => ")".to_string(),
         Token::Semicolon => ";".to_string(),
         Token::Colon => ":".to_string(),
+        Token::Question => "?".to_string(),
         Token::Comma => ",".to_string(),
         Token::Dot => ".".to_string(),
         Token::Amp => "&".to_string(),
         Token::AmpAmp => "&&".to_string(),
         Token::Pipe => "|".to_string(),
         Token::PipePipe => "||".to_string(),
+        Token::Caret => "^".to_string(),
         Token::Greater => ">".to_string(),
         Token::GreaterEqual => ">=".to_string(),
+        Token::GreaterGreater => ">>".to_string(),
         Token::Less => "<".to_string(),
         Token::LessEqual => "<=".to_string(),
+        Token::LessLess => "<<".to_string(),
         Token::Bang => "!".to_string(),
         Token::BangEqual => "!=".to_string(),
         Token::Equal => "=".to_string(),
@@ -96,6 +277,208 @@ pub fn token_to_string(name_table: &NameTable, token: &Token) -> String {
     }
 }
 
-pub fn expected_tokens_to_string(tokens: &Vec<TokenD>) -> String {
+pub fn expected_tokens_to_string(tokens: &[TokenD]) -> String {
     tokens.iter().map(|token| format!("{}", token)).join(", ")
 }
+
+// Renders a value the way print shows it. Tuples, records and
+// arrays hold their elements as words, so nested ones are
+// pointers that we follow
+pub fn format_value<Tag: Copy>(
+    memory: &Memory<Tag>,
+    name_table: &NameTable,
+    type_table: &TypeTable,
+    value: u64,
+    type_id: TypeId,
+) -> Result<String, IError> {
+    let format_words = |types: &[TypeId]| -> Result<Vec<String>, IError> {
+        let ptr: VarPointer = value.into();
+        let mut entries = Vec::new();
+        for (pos, type_id) in types.iter().enumerate() {
+            let word = memory.get_var(ptr.with_offset(field_offset(pos)?))?;
+            entries.push(format_value(
+                memory, name_table, type_table, word, *type_id,
+            )?);
+        }
+        Ok(entries)
+    };
+    match type_table.get_type(type_id) {
+        Type::Int => Ok(format!("{}", value as i64)),
+        Type::Float => Ok(format!("{}", f64::from_bits(value))),
+        Type::Bool => Ok(format!("{}", value != 0)),
+        Type::String => Ok(read_string(memory, value)?.to_string()),
+        Type::Char => Ok(read_char(value)?.to_string()),
+        Type::Unit => Ok("()".to_string()),
+        Type::Tuple(types) => Ok(format!("({})", format_words(types)?.join(", "))),
+        Type::Record(fields) => {
+            let types: Vec<_> = fields.iter().map(|(_, type_id)| *type_id).collect();
+            let entries = fields
+                .iter()
+                .zip(format_words(&types)?)
+                .map(|((name, _), entry)| format!("{}: {}", name_table.get_str(name), entry))
+                .join(", ");
+            match type_table.struct_name(type_id) {
+                Some(name) => Ok(format!("{} {{ {} }}", name_table.get_str(&name), entries)),
+                None => Ok(format!("{{ {} }}", entries)),
+            }
+        }
+        Type::Array(elem_type) => {
+            let len = memory.get_var_len(value.into())? as usize / 8;
+            Ok(format!(
+                "[{}]",
+                format_words(&vec![*elem_type; len])?.join(", ")
+            ))
+        }
+        Type::Optional(type_id) => match read_optional(memory, value)? {
+            Some(payload) => Ok(format!(
+                "some({})",
+                format_value(memory, name_table, type_table, payload, *type_id)?
+            )),
+            None => Ok("none".to_string()),
+        },
+        Type::Solved(type_id) => format_value(memory, name_table, type_table, value, *type_id),
+        Type::Any | Type::Arrow(_, _) => Err(IError::new(
+            "UnsupportedValue",
+            format!(
+                "Cannot print a value of type {}",
+                type_to_string(name_table, type_table, type_id)
+            ),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::Type;
+    use crate::lexer::Lexer;
+    use crate::lexer::{Token, TokenD};
+    use crate::parser::Parser;
+    use crate::printer::{
+        expected_tokens_to_string, render_snapshot, token_to_string, type_to_string,
+        typed_ast_json, TypedAstDump,
+    };
+    use crate::runtime::{IError, InMemoryIO, Memory};
+    use crate::treewalker::{TreeWalker, DEFAULT_MAX_CALL_DEPTH};
+    use crate::typechecker::TypeChecker;
+    use crate::utils::{NameTable, TypeTable, BOOL_INDEX, FLOAT_INDEX, INT_INDEX, STR_INDEX};
+
+    #[test]
+    fn typed_ast_json_round_trip() -> Result<(), failure::Error> {
+        let source =
+            "let x: (int, float) = (1, 2.5);\nfn f(a: int) -> int { a + 1 }\nprint(f(x.0));";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program()?;
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        let json = typed_ast_json(
+            program_t.clone(),
+            typechecker.get_name_table(),
+            typechecker.get_type_table(),
+        )?;
+        let dump: TypedAstDump = serde_json::from_str(&json)?;
+        assert_eq!(dump.program, program_t);
+        assert_eq!(dump.types[INT_INDEX], "int");
+        assert_eq!(dump.types[STR_INDEX], "string");
+        assert!(dump.types.contains(&"(int, float)".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn types() {
+        let mut name_table = NameTable::new();
+        let mut type_table = TypeTable::new();
+        let x = name_table.insert("x".to_string());
+        let y = name_table.insert("y".to_string());
+        let tuple = type_table.insert(Type::Tuple(vec![INT_INDEX, STR_INDEX]));
+        let array = type_table.insert(Type::Array(tuple));
+        let record = type_table.insert(Type::Record(vec![(x, FLOAT_INDEX), (y, array)]));
+        let arrow = type_table.insert(Type::Arrow(vec![record, BOOL_INDEX], INT_INDEX));
+        let to_string = |type_id| type_to_string(&name_table, &type_table, type_id);
+        assert_eq!(to_string(tuple), "(int, string)");
+        assert_eq!(to_string(array), "[(int, string)]");
+        assert_eq!(to_string(record), "{ x: float, y: [(int, string)] }");
+        assert_eq!(
+            to_string(arrow),
+            "({ x: float, y: [(int, string)] }, bool) => int"
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        let mut name_table = NameTable::new();
+        name_table.insert("padding".to_string());
+        let foo = name_table.insert("foo".to_string());
+        assert_eq!(token_to_string(&name_table, &Token::Ident(foo)), "<foo>");
+        assert_eq!(token_to_string(&name_table, &Token::Fn), "fn");
+        assert_eq!(
+            token_to_string(&name_table, &Token::String("hi".to_string())),
+            "\"hi\""
+        );
+        assert_eq!(
+            expected_tokens_to_string(&[TokenD::Ident, TokenD::Comma, TokenD::RParen]),
+            format!("{}, {}, {}", TokenD::Ident, TokenD::Comma, TokenD::RParen)
+        );
+        assert_eq!(expected_tokens_to_string(&[]), "");
+    }
+
+    #[test]
+    fn renders_snapshot() {
+        let mut memory = Memory::new();
+        let ptr = memory.add_stack_var(8, ());
+        memory.set(ptr, 42i64, ()).unwrap();
+        let name_table = NameTable::new();
+        let rendered = render_snapshot(&memory.snapshot(), &name_table, &TypeTable::new(), &[]);
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(lines[0], "stack:");
+        assert_eq!(lines[1], "  #1     8 bytes  2a 00 00 00 00 00 00 00");
+        assert_eq!(&lines[2..], &["heap:", "  (empty)"]);
+    }
+
+    #[test]
+    fn renders_snapshot_variables() -> Result<(), IError> {
+        let source =
+            "let x: int = 42;\nlet y: float = 1.5;\nlet s: string = \"hi\";\nlet t: (int, float) = (1, 2.5);";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().expect("failed to parse");
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let mut treewalker = TreeWalker::with_io(
+            functions,
+            name_table.clone(),
+            type_table.clone(),
+            DEFAULT_MAX_CALL_DEPTH,
+            InMemoryIO::new(),
+        );
+        treewalker.interpret_program(program_t)?;
+        let rendered = render_snapshot(
+            &treewalker.memory().snapshot(),
+            &name_table,
+            &type_table,
+            &treewalker.variables(),
+        );
+        let lines: Vec<_> = rendered.lines().collect();
+        assert_eq!(
+            &lines[..5],
+            &[
+                "variables:",
+                "  x: int = 42",
+                "  y: float = 1.5",
+                "  s: string = heap #1",
+                "  t: (int, float) = heap #2",
+            ],
+            "{}",
+            rendered
+        );
+        assert!(lines[8].ends_with("  s: string = \"hi\""), "{}", rendered);
+        assert!(
+            lines[9].ends_with("  t: (int, float) = (1, 2.5)"),
+            "{}",
+            rendered
+        );
+        Ok(())
+    }
+}
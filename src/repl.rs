@@ -0,0 +1,503 @@
+use crate::ast::{Expr, ExprT, Loc};
+use crate::lexer::Lexer;
+use crate::parser::{ParseError, Parser};
+use crate::printer::{render_snapshot, type_to_string};
+use crate::runtime::IError;
+use crate::treewalker::TreeWalker;
+use crate::typechecker::{TypeChecker, TypeError};
+use crate::utils::{NameTable, TypeTable};
+use codespan_reporting::diagnostic::Diagnostic;
+use codespan_reporting::files::SimpleFile;
+use codespan_reporting::term;
+use codespan_reporting::term::termcolor::{ColorChoice, StandardStream};
+use failure::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, stdin, stdout, BufRead, Write};
+
+#[derive(Debug, PartialEq)]
+enum InputKind {
+    Expr,
+    Code,
+    // Ran out of tokens partway through, so more lines are needed
+    Incomplete,
+    Invalid,
+}
+
+fn classify(input: &str) -> InputKind {
+    let mut parser = Parser::new(Lexer::new(input));
+    if parser.expr().is_ok() && parser.is_at_end() == Ok(true) {
+        return InputKind::Expr;
+    }
+    let mut parser = Parser::new(Lexer::new(input));
+    match parser.program() {
+        Ok(program) if program.errors.is_empty() => InputKind::Code,
+        Ok(program) if program.errors.iter().all(is_end_of_file) => InputKind::Incomplete,
+        Err(err) if is_end_of_file(&err) => InputKind::Incomplete,
+        _ => InputKind::Invalid,
+    }
+}
+
+// Returns the rest of the input if it starts with one of the command names
+fn strip_command<'a>(input: &'a str, names: &[&str]) -> Option<&'a str> {
+    let input = input.trim_start();
+    names.iter().find_map(|name| input.strip_prefix(name))
+}
+
+fn is_end_of_file(err: &ParseError) -> bool {
+    matches!(err, ParseError::EndOfFile { .. })
+}
+
+// Holds onto the typechecker and treewalker between lines so
+// that definitions from earlier lines can be used in later ones
+pub struct Repl {
+    typechecker: TypeChecker,
+    treewalker: TreeWalker,
+    // How many snapshots :step has walked through the memory history.
+    // Goes back to the start whenever something new is evaluated
+    history_steps: usize,
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            typechecker: TypeChecker::new(NameTable::new()),
+            treewalker: TreeWalker::new(HashMap::new(), NameTable::new(), TypeTable::new()),
+            history_steps: 0,
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
+        let writer = StandardStream::stderr(ColorChoice::Always);
+        let config = codespan_reporting::term::Config::default();
+        let stdin = stdin();
+        let mut reader = stdin.lock();
+        while let Some(input) = self.read_input(&mut reader)? {
+            if input.trim().is_empty() {
+                continue;
+            }
+            if let Some(expr) = strip_command(&input, &[":type ", ":t "]) {
+                match self.type_of(expr) {
+                    Ok(type_str) => println!("{}", type_str),
+                    // Locations are relative to the text after the command
+                    Err(diagnostic) => {
+                        let file = SimpleFile::new("<repl>", expr);
+                        term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+                    }
+                }
+                continue;
+            }
+            if let Some(path) = strip_command(&input, &[":load "]) {
+                let path = path.trim();
+                match self.load(path) {
+                    Ok((code, diagnostics)) => {
+                        let file = SimpleFile::new(path, &code);
+                        for diagnostic in diagnostics {
+                            term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+                        }
+                    }
+                    Err(err) => println!("couldn't load {}: {}", path, err),
+                }
+                continue;
+            }
+            match input.trim() {
+                ":mem" => {
+                    print!(
+                        "{}",
+                        render_snapshot(
+                            &self.treewalker.memory().snapshot(),
+                            self.typechecker.get_name_table(),
+                            self.typechecker.get_type_table(),
+                            &self.treewalker.variables()
+                        )
+                    );
+                    continue;
+                }
+                ":step" => {
+                    println!("{}", self.step_history(true));
+                    continue;
+                }
+                ":back" => {
+                    println!("{}", self.step_history(false));
+                    continue;
+                }
+                _ => {}
+            }
+            self.history_steps = 0;
+            let result = match classify(&input) {
+                InputKind::Expr => self.eval_expr(&input),
+                InputKind::Code => self.eval_code(&input),
+                // If it doesn't parse either way, guess from the last
+                // character which parse the user was going for
+                InputKind::Incomplete | InputKind::Invalid => match input.trim().chars().last() {
+                    Some(';') | Some('}') => self.eval_code(&input),
+                    _ => self.eval_expr(&input),
+                },
+            };
+            // Runtime errors get rendered like any other diagnostic
+            // instead of debug printed
+            let diagnostics = match result {
+                Ok(diagnostics) => diagnostics,
+                Err(err) => vec![(&err).into()],
+            };
+            let file = SimpleFile::new("<repl>", &input);
+            for diagnostic in diagnostics {
+                term::emit(&mut writer.lock(), &config, &file, &diagnostic)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Reads lines until they form a complete expression or program,
+    // or until a blank line forces evaluation. Returns None at EOF
+    fn read_input(&self, reader: &mut impl BufRead) -> io::Result<Option<String>> {
+        let mut input = String::new();
+        print!("> ");
+        loop {
+            stdout().flush()?;
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Ok(if input.is_empty() { None } else { Some(input) });
+            }
+            let is_blank = line.trim().is_empty();
+            input.push_str(&line);
+            if is_blank || classify(&input) != InputKind::Incomplete {
+                return Ok(Some(input));
+            }
+            print!("... ");
+        }
+    }
+
+    // Parses with the REPL's name table so names line up with
+    // the ones the typechecker has already seen
+    fn parse<T>(&mut self, code: &str, parse_fn: impl FnOnce(&mut Parser) -> T) -> T {
+        let lexer = Lexer::with_name_table(code, self.typechecker.take_name_table());
+        let mut parser = Parser::new(lexer);
+        let res = parse_fn(&mut parser);
+        self.typechecker.restore_name_table(parser.get_name_table());
+        res
+    }
+
+    // Runs a sequence of statements. Lines with parse errors are
+    // not run at all so they can't leave behind half a definition
+    pub fn eval_code(&mut self, code: &str) -> Result<Vec<Diagnostic<()>>, IError> {
+        let program = match self.parse(code, |parser| parser.program()) {
+            Ok(program) => program,
+            Err(err) => return Ok(vec![(&err).into()]),
+        };
+        if !program.errors.is_empty() {
+            return Ok(program.errors.iter().map(|err| err.into()).collect());
+        }
+        let program_t = self.typechecker.check_program(program);
+        let diagnostics = program_t.errors.iter().map(|err| err.into()).collect();
+        let functions = self.typechecker.take_functions();
+        self.treewalker.add_functions(functions);
+        self.sync_tables();
+        // Whatever earlier lines allocated and no longer use is garbage
+        self.treewalker.reset_memory()?;
+        self.treewalker.interpret_program(program_t)?;
+        Ok(diagnostics)
+    }
+
+    // Runs a file as if it were typed in, so its definitions stick
+    // around. Gives back the contents to render diagnostics against
+    fn load(&mut self, path: &str) -> io::Result<(String, Vec<Diagnostic<()>>)> {
+        let code = fs::read_to_string(path)?;
+        self.history_steps = 0;
+        let diagnostics = match self.eval_code(&code) {
+            Ok(diagnostics) => diagnostics,
+            Err(err) => vec![(&err).into()],
+        };
+        Ok((code, diagnostics))
+    }
+
+    // Typechecks an expression without running it
+    pub fn type_of(&mut self, code: &str) -> Result<String, Diagnostic<()>> {
+        let expr = match self.parse(code, |parser| parser.expr()) {
+            Ok(expr) => expr,
+            Err(err) => return Err((&err).into()),
+        };
+        let expr_t = match self.check_expr(expr) {
+            Ok(expr_t) => expr_t,
+            Err(err) => return Err((&err).into()),
+        };
+        Ok(type_to_string(
+            self.typechecker.get_name_table(),
+            self.typechecker.get_type_table(),
+            expr_t.inner.get_type(),
+        ))
+    }
+
+    pub fn eval_expr(&mut self, code: &str) -> Result<Vec<Diagnostic<()>>, IError> {
+        match self.show_expr(code)? {
+            Ok(output) => {
+                println!("{}", output);
+                Ok(Vec::new())
+            }
+            Err(diagnostic) => Ok(vec![diagnostic]),
+        }
+    }
+
+    // Runs an expression and shows its value along with its type, like `3 : int`
+    fn show_expr(&mut self, code: &str) -> Result<Result<String, Diagnostic<()>>, IError> {
+        let expr = match self.parse(code, |parser| parser.expr()) {
+            Ok(expr) => expr,
+            Err(err) => return Ok(Err((&err).into())),
+        };
+        let expr_t = match self.check_expr(expr) {
+            Ok(expr_t) => expr_t,
+            Err(err) => return Ok(Err((&err).into())),
+        };
+        self.sync_tables();
+        self.treewalker.reset_memory()?;
+        let value = self.treewalker.format_expr(&expr_t)?;
+        let type_str = type_to_string(
+            self.typechecker.get_name_table(),
+            self.typechecker.get_type_table(),
+            expr_t.inner.get_type(),
+        );
+        Ok(Ok(format!("{} : {}", value, type_str)))
+    }
+
+    // Typechecks an expression, rolling back any types it added
+    // to the type table if it fails
+    fn check_expr(&mut self, expr: Loc<Expr>) -> Result<Loc<ExprT>, TypeError> {
+        let types_len = self.typechecker.get_type_table().len();
+        let mut res = self.typechecker.expr(expr);
+        // Errors we recovered from inside the expression fail it too
+        if let Some(err) = self.typechecker.take_errors().into_iter().next() {
+            res = Err(err);
+        }
+        if res.is_err() {
+            self.typechecker.get_type_table_mut().truncate(types_len);
+        }
+        res
+    }
+
+    // Moves one snapshot forwards or backwards through the memory
+    // history and renders the memory at that point
+    fn step_history(&mut self, forwards: bool) -> String {
+        let memory = self.treewalker.memory();
+        // The first snapshot is before any history, so there's one
+        // more snapshot than there are actions
+        let total = memory.history.len() + 1;
+        if forwards && self.history_steps < total {
+            self.history_steps += 1;
+        } else if !forwards && self.history_steps > 1 {
+            self.history_steps -= 1;
+        } else {
+            return "no more history in that direction".to_string();
+        }
+        let mut walker = memory.forwards_walker();
+        let mut snapshot = None;
+        for _ in 0..self.history_steps {
+            snapshot = walker.next();
+        }
+        match snapshot {
+            Some(snapshot) => format!(
+                "step {}/{}\n{}",
+                self.history_steps,
+                total,
+                // Scopes aren't part of the history, so past
+                // snapshots only show the raw bytes
+                render_snapshot(
+                    &snapshot,
+                    self.typechecker.get_name_table(),
+                    self.typechecker.get_type_table(),
+                    &[]
+                )
+            ),
+            None => "no more history in that direction".to_string(),
+        }
+    }
+
+    // Gives the treewalker the typechecker's latest tables
+    fn sync_tables(&mut self) {
+        self.treewalker.set_tables(
+            self.typechecker.get_name_table().clone(),
+            self.typechecker.get_type_table().clone(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repl::{strip_command, Repl};
+    use std::io::Cursor;
+    use std::{env, fs};
+
+    #[test]
+    fn persists_variables() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_code("let x: int = 1;").unwrap().is_empty());
+        assert!(repl.eval_code("print(x);").unwrap().is_empty());
+        assert!(repl.eval_expr("x + 1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn redefines_variables() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_code("let x: int = 1;").unwrap().is_empty());
+        assert!(repl.eval_code("let x: float = 2.5;").unwrap().is_empty());
+        assert!(repl.eval_code("let y: float = x;").unwrap().is_empty());
+    }
+
+    #[test]
+    fn persists_functions() {
+        let mut repl = Repl::new();
+        let code = "fn double(x: int) -> int { x * 2 }";
+        assert!(repl.eval_code(code).unwrap().is_empty());
+        assert!(repl
+            .eval_code("let y: int = double(21);")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reclaims_memory() {
+        let mut repl = Repl::new();
+        let code = "struct P { s: string }\n\
+                    let p: P = P { s: \"a\" + \"b\" };\n\
+                    let q: P = p;\n\
+                    fn f(x: int) -> int { x + 1 }";
+        assert!(repl.eval_code(code).unwrap().is_empty());
+        assert!(repl.eval_code("q.s = q.s + \"c\";").unwrap().is_empty());
+        // p and q still share the record after their memory moved
+        assert_eq!(repl.show_expr("p.s").unwrap().unwrap(), "abc : string");
+        assert_eq!(repl.show_expr("f(1)").unwrap().unwrap(), "2 : int");
+        // Only the record and its string are left
+        repl.treewalker.reset_memory().unwrap();
+        assert_eq!(repl.treewalker.memory().heap.vars.len(), 2);
+        assert!(repl.treewalker.memory().history.is_empty());
+    }
+
+    #[test]
+    fn loads_files() {
+        let path = env::temp_dir().join(format!("bridge_load_{}.brg", std::process::id()));
+        fs::write(&path, "fn double(x: int) -> int { x * 2 }\n").unwrap();
+        let mut repl = Repl::new();
+        let (_, diagnostics) = repl.load(path.to_str().unwrap()).unwrap();
+        assert!(diagnostics.is_empty());
+        assert_eq!(repl.show_expr("double(21)").unwrap().unwrap(), "42 : int");
+        fs::remove_file(&path).unwrap();
+        assert!(repl.load(path.to_str().unwrap()).is_err());
+    }
+
+    #[test]
+    fn parse_error_keeps_state() {
+        let mut repl = Repl::new();
+        assert!(repl.eval_code("let x: int = 1;").unwrap().is_empty());
+        assert!(!repl.eval_code("let y: int = ;").unwrap().is_empty());
+        assert!(repl.eval_code("print(x);").unwrap().is_empty());
+    }
+
+    #[test]
+    fn multi_line_input() {
+        let repl = Repl::new();
+        let mut reader = Cursor::new("fn f(x: int) -> int {\n  x * 2\n}\nf(2)\n");
+        let input = repl.read_input(&mut reader).unwrap().unwrap();
+        assert_eq!(input, "fn f(x: int) -> int {\n  x * 2\n}\n");
+        let input = repl.read_input(&mut reader).unwrap().unwrap();
+        assert_eq!(input, "f(2)\n");
+        assert_eq!(repl.read_input(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn blank_line_forces_evaluation() {
+        let repl = Repl::new();
+        let mut reader = Cursor::new("let x: int =\n\n1;\n");
+        let input = repl.read_input(&mut reader).unwrap().unwrap();
+        assert_eq!(input, "let x: int =\n\n");
+    }
+
+    #[test]
+    fn type_command() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.type_of("1 + 2.0").unwrap(), "float");
+        assert!(repl
+            .eval_code("let x: (int, bool) = (1, true);")
+            .unwrap()
+            .is_empty());
+        assert_eq!(repl.type_of("x").unwrap(), "(int, bool)");
+        assert!(repl.type_of("y").is_err());
+    }
+
+    #[test]
+    fn shows_value_and_type() {
+        let mut repl = Repl::new();
+        assert_eq!(repl.show_expr("1 + 2").unwrap().unwrap(), "3 : int");
+        assert!(repl.eval_code("let x: float = 2.5;").unwrap().is_empty());
+        assert_eq!(repl.show_expr("x * 2.0").unwrap().unwrap(), "5 : float");
+        assert!(repl.show_expr("1 + true").unwrap().is_err());
+    }
+
+    #[test]
+    fn pretty_prints_values() {
+        let mut repl = Repl::new();
+        let code = "struct Point { x: int, y: int }\nlet p: Point = Point { x: 1, y: 2 };";
+        assert!(repl.eval_code(code).unwrap().is_empty());
+        let shown = repl.show_expr("p").unwrap().unwrap();
+        assert!(shown.starts_with("Point { x: 1, y: 2 } : "), "{}", shown);
+        let shown = repl.show_expr("([p], \"s\", 'c', 1.5)").unwrap().unwrap();
+        assert!(
+            shown.starts_with("([Point { x: 1, y: 2 }], s, c, 1.5) : "),
+            "{}",
+            shown
+        );
+    }
+
+    #[test]
+    fn failed_check_rolls_back_types() {
+        let mut repl = Repl::new();
+        let types_len = repl.typechecker.get_type_table().len();
+        assert!(repl.show_expr("(1, [2.0]) + \"a\"").unwrap().is_err());
+        assert!(repl
+            .type_of("{ let x: (int, int) = (1, 2); x + \"b\" }")
+            .is_err());
+        assert_eq!(repl.typechecker.get_type_table().len(), types_len);
+        assert_eq!(
+            repl.show_expr("(1, 2)").unwrap().unwrap(),
+            "(1, 2) : (int, int)"
+        );
+    }
+
+    #[test]
+    fn walks_memory_history() {
+        let mut repl = Repl::new();
+        assert!(repl
+            .eval_code("let t: (int, int) = (7, 9);")
+            .unwrap()
+            .is_empty());
+        let expected: Vec<u8> = [7u64, 9u64]
+            .iter()
+            .flat_map(|word| word.to_ne_bytes().to_vec())
+            .collect();
+        let memory = repl.treewalker.memory();
+        let mut walker = memory.forwards_walker();
+        let mut found = false;
+        while let Some(snapshot) = walker.next() {
+            if snapshot.heap_data == &expected[..] {
+                found = true;
+            }
+        }
+        assert!(found);
+
+        assert!(repl.step_history(true).starts_with("step 1/"));
+        assert!(repl.step_history(true).starts_with("step 2/"));
+        assert!(repl.step_history(false).starts_with("step 1/"));
+        assert_eq!(
+            repl.step_history(false),
+            "no more history in that direction"
+        );
+    }
+
+    #[test]
+    fn strips_commands() {
+        assert_eq!(
+            strip_command(":type 1 + 2", &[":type ", ":t "]),
+            Some("1 + 2")
+        );
+        assert_eq!(strip_command(":t x\n", &[":type ", ":t "]), Some("x\n"));
+        assert_eq!(strip_command("x + 1", &[":type ", ":t "]), None);
+    }
+}
@@ -1,12 +1,15 @@
+use crate::lexer::LocationRange;
 use crate::utils::*;
 use core::{fmt, mem, str};
 use serde::{Deserialize, Serialize};
-use std::io::{Stderr, Stdout, Write};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Stderr, Stdin, Stdout, Write};
 
 #[derive(Debug)]
 pub struct IError {
     pub short_name: String,
     pub message: String,
+    // Where in the source the error happened, if we know
+    pub location: Option<LocationRange>,
 }
 
 impl IError {
@@ -14,8 +17,20 @@ impl IError {
         Self {
             short_name: short_name.to_string(),
             message,
+            location: None,
         }
     }
+
+    pub fn with_location(mut self, location: LocationRange) -> Self {
+        self.location = Some(location);
+        self
+    }
+}
+
+impl fmt::Display for IError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.short_name, self.message)
+    }
 }
 
 macro_rules! error {
@@ -34,6 +49,8 @@ macro_rules! err {
 pub struct Var {
     pub idx: usize,
     pub len: u32, // len in bytes
+    // Element count for tuples, records and arrays, or the
+    // byte length for strings
     pub meta: u32,
 }
 
@@ -200,12 +217,16 @@ impl VarBuffer {
 
         let begin = var.idx + ptr.offset() as usize;
         let var_slice = &self.data[begin..(begin + len as usize)];
-        return Ok(unsafe { *(var_slice.as_ptr() as *const T) });
+        return Ok(unsafe { std::ptr::read_unaligned(var_slice.as_ptr() as *const T) });
     }
 
     pub fn add_var(&mut self, len: u32) -> u32 {
+        self.add_var_with_meta(len, 0)
+    }
+
+    pub fn add_var_with_meta(&mut self, len: u32, meta: u32) -> u32 {
         let idx = self.data.len();
-        self.vars.push(Var { idx, len, meta: 0 });
+        self.vars.push(Var { idx, len, meta });
         self.data.resize(idx + len as usize, 0);
         let var_idx = self.vars.len() as u32; // TODO Check for overflow
         return var_idx;
@@ -228,7 +249,7 @@ impl VarBuffer {
 
         let begin = var.idx + ptr.offset() as usize;
         let to_bytes = &mut self.data[begin..(begin + len as usize)];
-        let previous_value = unsafe { *(to_bytes.as_ptr() as *const T) };
+        let previous_value = unsafe { std::ptr::read_unaligned(to_bytes.as_ptr() as *const T) };
         to_bytes.copy_from_slice(any_as_u8_slice(&t));
         return Ok(previous_value);
     }
@@ -286,6 +307,28 @@ impl<Tag: Copy> Memory<Tag> {
         }
     }
 
+    // Drops every var along with the history, as if freshly created
+    pub fn reset(&mut self) {
+        self.stack = VarBuffer::new();
+        self.heap = VarBuffer::new();
+        self.clear_history();
+    }
+
+    // Forgets how memory got to its current state, keeping the state
+    pub fn clear_history(&mut self) {
+        self.historical_data.clear();
+        self.history.clear();
+    }
+
+    pub fn snapshot(&self) -> MemorySnapshot<'_> {
+        MemorySnapshot {
+            stack_data: &self.stack.data,
+            stack_vars: &self.stack.vars,
+            heap_data: &self.heap.data,
+            heap_vars: &self.heap.vars,
+        }
+    }
+
     pub fn push_history(&mut self, kind: MAKind, tag: Tag) {
         self.history.push(MemoryAction { kind, tag });
     }
@@ -315,6 +358,33 @@ impl<Tag: Copy> Memory<Tag> {
         return Ok(&buffer.data[(var.idx + ptr.offset() as usize)..(var.idx + var.len as usize)]);
     }
 
+    // Length in bytes of the var the pointer points into
+    pub fn get_var_len(&self, ptr: VarPointer) -> Result<u32, IError> {
+        Ok(self.get_var_info(ptr)?.len)
+    }
+
+    // The var's meta, see Var
+    pub fn get_var_meta(&self, ptr: VarPointer) -> Result<u32, IError> {
+        Ok(self.get_var_info(ptr)?.meta)
+    }
+
+    fn get_var_info(&self, ptr: VarPointer) -> Result<Var, IError> {
+        let buffer = if ptr.is_stack() {
+            &self.stack
+        } else {
+            &self.heap
+        };
+
+        if ptr.var_idx() == 0 {
+            return Err(invalid_ptr(ptr));
+        }
+
+        match buffer.vars.get(ptr.var_idx() - 1) {
+            Some(var) => Ok(*var),
+            None => Err(invalid_ptr(ptr)),
+        }
+    }
+
     #[inline]
     pub fn get_slice(&self, ptr: VarPointer, len: u32) -> Result<&[u8], IError> {
         let buffer;
@@ -392,7 +462,12 @@ impl<Tag: Copy> Memory<Tag> {
 
     #[inline]
     pub fn add_heap_var(&mut self, len: u32, tag: Tag) -> VarPointer {
-        let ptr = VarPointer::new_heap(self.heap.add_var(len), 0);
+        self.add_heap_var_with_meta(len, 0, tag)
+    }
+
+    #[inline]
+    pub fn add_heap_var_with_meta(&mut self, len: u32, meta: u32, tag: Tag) -> VarPointer {
+        let ptr = VarPointer::new_heap(self.heap.add_var_with_meta(len, meta), 0);
         self.push_history(MAKind::AllocHeapVar { len }, tag);
         return ptr;
     }
@@ -723,7 +798,7 @@ impl<Tag: Copy> Memory<Tag> {
         self.historical_data.extend_from_slice(from_bytes);
         let value_end = self.historical_data.len();
 
-        let out = unsafe { *(from_bytes.as_ptr() as *const T) };
+        let out = unsafe { std::ptr::read_unaligned(from_bytes.as_ptr() as *const T) };
         self.stack.data.resize(lower, 0);
         self.push_history(
             MAKind::PopStack {
@@ -996,20 +1071,45 @@ fn test_walker() {
         VarBuffer::new(),
     );
     assert_eq!(walker.prev().unwrap(), expected.snapshot());
-    panic!();
+}
+
+#[test]
+fn test_reset() {
+    let mut memory = Memory::new();
+    let ptr = memory.add_heap_var(8, 0);
+    memory.set(ptr, 10u64, 0).expect("should not fail");
+    memory.add_stack_var(4, 0);
+    assert!(!memory.heap.data.is_empty());
+
+    memory.reset();
+    assert!(memory.heap.data.is_empty());
+    assert!(memory.stack.data.is_empty());
+    assert!(memory.historical_data.is_empty());
+    assert!(memory.history.is_empty());
+    assert!(memory.get_var::<u64>(ptr).is_err());
 }
 
 pub trait RuntimeIO {
+    type In: BufRead;
     type Out: Write;
     type Log: Write;
     type Err: Write;
 
+    fn input(&mut self) -> &mut Self::In;
     fn out(&mut self) -> &mut Self::Out;
     fn log(&mut self) -> &mut Self::Log;
     fn err(&mut self) -> &mut Self::Err;
+
+    // Called with the outcome of every assert, along with the failure
+    // message if it failed. Returns true if a failure should be recorded
+    // and skipped instead of stopping the program
+    fn record_assertion(&mut self, location: LocationRange, failure: Option<&str>) -> bool {
+        false
+    }
 }
 
 pub struct InMemoryIO {
+    pub input: Cursor<Vec<u8>>,
     pub out: StringWriter,
     pub log: StringWriter,
     pub err: StringWriter,
@@ -1017,7 +1117,13 @@ pub struct InMemoryIO {
 
 impl InMemoryIO {
     pub fn new() -> Self {
+        Self::with_input("")
+    }
+
+    // Lets tests feed read_line a fixed input
+    pub fn with_input(input: &str) -> Self {
         Self {
+            input: Cursor::new(input.as_bytes().to_vec()),
             out: StringWriter::new(),
             log: StringWriter::new(),
             err: StringWriter::new(),
@@ -1026,10 +1132,14 @@ impl InMemoryIO {
 }
 
 impl RuntimeIO for InMemoryIO {
+    type In = Cursor<Vec<u8>>;
     type Out = StringWriter;
     type Log = StringWriter;
     type Err = StringWriter;
 
+    fn input(&mut self) -> &mut Cursor<Vec<u8>> {
+        return &mut self.input;
+    }
     fn out(&mut self) -> &mut StringWriter {
         return &mut self.out;
     }
@@ -1041,24 +1151,89 @@ impl RuntimeIO for InMemoryIO {
     }
 }
 
+// Stdout is only line buffered, so we buffer it again for the
+// interpreter's flush policy to decide when output goes out
 pub struct DefaultIO {
-    pub out: Stdout,
-    pub log: StringWriter,
+    pub input: BufReader<Stdin>,
+    pub out: BufWriter<Stdout>,
+    pub log: Stderr,
     pub err: Stderr,
 }
 
+impl DefaultIO {
+    pub fn new() -> Self {
+        Self {
+            input: BufReader::new(std::io::stdin()),
+            out: BufWriter::new(std::io::stdout()),
+            log: std::io::stderr(),
+            err: std::io::stderr(),
+        }
+    }
+}
+
 impl RuntimeIO for DefaultIO {
-    type Out = Stdout;
-    type Log = StringWriter;
+    type In = BufReader<Stdin>;
+    type Out = BufWriter<Stdout>;
+    type Log = Stderr;
     type Err = Stderr;
 
-    fn out(&mut self) -> &mut Stdout {
+    fn input(&mut self) -> &mut BufReader<Stdin> {
+        return &mut self.input;
+    }
+    fn out(&mut self) -> &mut BufWriter<Stdout> {
         return &mut self.out;
     }
-    fn log(&mut self) -> &mut StringWriter {
+    fn log(&mut self) -> &mut Stderr {
         return &mut self.log;
     }
     fn err(&mut self) -> &mut Stderr {
         return &mut self.err;
     }
 }
+
+// Used for running a file as a test suite. Passes output through to
+// the inner IO, but collects every assert instead of stopping at the
+// first failure
+pub struct TestIO<IO: RuntimeIO> {
+    pub inner: IO,
+    pub passed: usize,
+    pub failures: Vec<(LocationRange, String)>,
+}
+
+impl<IO: RuntimeIO> TestIO<IO> {
+    pub fn new(inner: IO) -> Self {
+        Self {
+            inner,
+            passed: 0,
+            failures: Vec::new(),
+        }
+    }
+}
+
+impl<IO: RuntimeIO> RuntimeIO for TestIO<IO> {
+    type In = IO::In;
+    type Out = IO::Out;
+    type Log = IO::Log;
+    type Err = IO::Err;
+
+    fn input(&mut self) -> &mut IO::In {
+        self.inner.input()
+    }
+    fn out(&mut self) -> &mut IO::Out {
+        self.inner.out()
+    }
+    fn log(&mut self) -> &mut IO::Log {
+        self.inner.log()
+    }
+    fn err(&mut self) -> &mut IO::Err {
+        self.inner.err()
+    }
+
+    fn record_assertion(&mut self, location: LocationRange, failure: Option<&str>) -> bool {
+        match failure {
+            Some(message) => self.failures.push((location, message.to_string())),
+            None => self.passed += 1,
+        }
+        true
+    }
+}
@@ -1,4 +1,7 @@
 use crate::ast::{Name, TypeId};
+use crate::printer::type_to_string;
+use crate::utils::{NameTable, TypeTable};
+use itertools::Itertools;
 use std::collections::HashMap;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -113,4 +116,51 @@ impl SymbolTable {
             },
         );
     }
+
+    // Renders the scopes as a tree, each one indented under its
+    // parent with its variables sorted by name
+    pub fn dump_scopes(&self, name_table: &NameTable, type_table: &TypeTable) -> String {
+        let mut out = String::new();
+        self.dump_scope(0, 0, name_table, type_table, &mut out);
+        out
+    }
+
+    fn dump_scope(
+        &self,
+        index: usize,
+        depth: usize,
+        name_table: &NameTable,
+        type_table: &TypeTable,
+        out: &mut String,
+    ) {
+        let indent = "  ".repeat(depth);
+        let scope = &self.scopes[index];
+        let kind = match (scope.parent, scope.is_function_scope) {
+            (None, _) => "global",
+            (_, true) => "function",
+            (_, false) => "block",
+        };
+        out.push_str(&format!("{}scope {} ({})\n", indent, index, kind));
+        let symbols = scope
+            .symbols
+            .iter()
+            .map(|(name, entry)| (name_table.get_str(name), entry))
+            .sorted_by_key(|(name, _)| *name);
+        for (name, entry) in symbols {
+            out.push_str(&format!(
+                "{}  {}: {}\n",
+                indent,
+                name,
+                type_to_string(name_table, type_table, entry.var_type)
+            ));
+        }
+        for (child, _) in self
+            .scopes
+            .iter()
+            .enumerate()
+            .filter(|(_, child)| child.parent == Some(index))
+        {
+            self.dump_scope(child, depth + 1, name_table, type_table, out);
+        }
+    }
 }
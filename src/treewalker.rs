@@ -1,8 +1,14 @@
-use crate::ast::{ExprT, Function, Loc, Name, Op, ProgramT, StmtT, UnaryOp, Value};
-use crate::lexer::LocationRange;
+use crate::ast::{ExprT, Function, Loc, Name, Op, ProgramT, StmtT, Type, TypeId, UnaryOp, Value};
+use crate::lexer::{Location, LocationRange};
+use crate::printer::{format_value, type_to_string};
 use crate::runtime::*;
 use crate::utils::*;
+use codespan_reporting::files::{Files, SimpleFile};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // macro_rules! error {
 //     ($arg1:tt,$($arg:tt)*) => {
@@ -16,38 +22,534 @@ macro_rules! err {
     };
 }
 
+// Builtin float -> float functions. Like in Rust, sqrt of a negative
+// number is NaN rather than an error
+fn float_builtin(callee: Name) -> Option<fn(f64) -> f64> {
+    match callee {
+        SQRT_INDEX => Some(f64::sqrt),
+        SIN_INDEX => Some(f64::sin),
+        COS_INDEX => Some(f64::cos),
+        FLOOR_INDEX => Some(f64::floor),
+        CEIL_INDEX => Some(f64::ceil),
+        _ => None,
+    }
+}
+
+// Seed for when the user doesn't give one
+fn time_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// How deep calls can nest before we bail out with a StackOverflow
+// instead of overflowing the host stack
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// Byte offset of the field at `pos` in a tuple or record
+pub fn field_offset(pos: usize) -> Result<u32, IError> {
+    u32::try_from(pos)
+        .ok()
+        .and_then(|pos| pos.checked_mul(8))
+        .ok_or_else(|| IError::new("InvalidOffset", format!("Field {} is out of range", pos)))
+}
+
+// Bytes needed for `count` elements of `elem_size` bytes each. Errors
+// instead of wrapping if that's more than `max_size`
+fn allocation_size(count: usize, elem_size: u32, max_size: u32) -> Result<u32, IError> {
+    u32::try_from(count)
+        .ok()
+        .and_then(|count| count.checked_mul(elem_size))
+        .filter(|size| *size <= max_size)
+        .ok_or_else(|| {
+            IError::new(
+                "AllocationTooLarge",
+                format!("Cannot allocate {} elements of {} bytes", count, elem_size),
+            )
+        })
+}
+
+fn int_div(l: i64, r: i64, location: LocationRange) -> Result<u64, IError> {
+    if r == 0 {
+        let err = IError::new("DivisionByZero", format!("Cannot divide {} by zero", l));
+        return Err(err.with_location(location));
+    }
+    Ok(l.wrapping_div(r) as u64)
+}
+
+// << and >> on ints. Shifting by 64 or more isn't defined, and a
+// negative amount is almost certainly a bug, so both are errors
+fn int_shift(l: i64, r: i64, op: &Op, location: LocationRange) -> Result<u64, IError> {
+    if !(0..64).contains(&r) {
+        let err = IError::new(
+            "InvalidShift",
+            format!("Cannot shift {} by {}; the amount must be 0 to 63", l, r),
+        );
+        return Err(err.with_location(location));
+    }
+    match op {
+        Op::Shl => Ok((l << r) as u64),
+        _ => Ok((l >> r) as u64),
+    }
+}
+
+// Chars are stored as their code point
+pub fn read_char(value: u64) -> Result<char, IError> {
+    match u32::try_from(value).ok().and_then(char::from_u32) {
+        Some(c) => Ok(c),
+        None => err!("InvalidChar", "{} is not a valid char", value),
+    }
+}
+
+// Optionals are a heap var with a one byte tag, 1 for some, then the
+// payload word. none leaves the payload zeroed
+const OPTIONAL_SIZE: u32 = 9;
+
+pub fn read_optional<Tag: Copy>(memory: &Memory<Tag>, value: u64) -> Result<Option<u64>, IError> {
+    let ptr: VarPointer = value.into();
+    if memory.get_var::<u8>(ptr)? == 0 {
+        Ok(None)
+    } else {
+        Ok(Some(memory.get_var(ptr.with_offset(1))?))
+    }
+}
+
+// Reads a string allocated by interpret_value, minus its null terminator
+pub fn read_string<Tag: Copy>(memory: &Memory<Tag>, value: u64) -> Result<&str, IError> {
+    let ptr: VarPointer = value.into();
+    let bytes = memory.get_var_slice(ptr)?;
+    let bytes = &bytes[..bytes.len() - 1];
+    Ok(unsafe { std::str::from_utf8_unchecked(bytes) })
+}
+
 struct Scope {
     variables: HashMap<Name, u64>,
+    // Only kept so the debugger can show the variables
+    types: HashMap<Name, TypeId>,
+}
+
+// A function the host provides. Gets its args as Values and
+// returns one back
+pub type NativeFunction = Box<dyn Fn(&[Value]) -> Result<Value, IError>>;
+
+// A jump out of the statements being run, passed up
+// until it reaches the call or loop it's meant for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Signal {
+    Return(u64),
+    Break,
+    Continue,
+}
+
+// When print output gets flushed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushPolicy {
+    // After every print, so output interleaves properly with stderr
+    EachPrint,
+    // Only once the program is done, for throughput
+    Batch,
+}
+
+fn stmt_kind(stmt: &StmtT) -> &'static str {
+    match stmt {
+        StmtT::Def(..) => "def",
+        StmtT::Asgn(..) | StmtT::ChainedAsgn(..) | StmtT::TupleFieldAsgn(..) => "assign",
+        StmtT::Expr(..) => "expr",
+        StmtT::Return(..) => "return",
+        StmtT::Function(..) => "function",
+        StmtT::Block(..) => "block",
+        StmtT::While(..) => "while",
+        StmtT::Break => "break",
+        StmtT::Continue => "continue",
+    }
 }
 
-pub struct TreeWalker {
+pub struct TreeWalker<IO: RuntimeIO = DefaultIO> {
     memory: Memory<LocationRange>,
     scopes: Vec<Scope>,
-    functions: HashMap<Name, Function>,
+    // Shared so calls can hold onto a function without
+    // borrowing the walker or cloning every function
+    functions: Rc<HashMap<Name, Function>>,
+    // Used to render type names at runtime, e.g. for type_name
+    name_table: NameTable,
+    type_table: TypeTable,
+    call_depth: usize,
+    max_call_depth: usize,
+    rng: Rng,
+    // Whether int overflow is an error instead of wrapping
+    checked_arithmetic: bool,
+    // Number of statements and expressions interpreted so far,
+    // if we're counting them
+    steps: Option<u64>,
+    io: IO,
+    flush_policy: FlushPolicy,
+    // Host functions, whose signatures live in the typechecker
+    natives: HashMap<Name, NativeFunction>,
+    // Bytes printed so far, and how many we allow if there's a cap
+    output_len: usize,
+    output_limit: Option<usize>,
+    // Set when a statement in a block expression jumps, since
+    // expressions only return values. The blocks around it stop
+    // early until something takes the signal
+    signal: Option<Signal>,
+    // Whether to log each statement and call before running it
+    trace: bool,
+    // The source being run, so trace lines can say line:col
+    source_file: Option<SimpleFile<String, String>>,
 }
 
 impl TreeWalker {
-    pub fn new(functions: HashMap<Name, Function>) -> Self {
+    pub fn new(
+        functions: impl IntoIterator<Item = (Name, Function)>,
+        name_table: NameTable,
+        type_table: TypeTable,
+    ) -> Self {
+        Self::with_max_call_depth(functions, name_table, type_table, DEFAULT_MAX_CALL_DEPTH)
+    }
+
+    pub fn with_max_call_depth(
+        functions: impl IntoIterator<Item = (Name, Function)>,
+        name_table: NameTable,
+        type_table: TypeTable,
+        max_call_depth: usize,
+    ) -> Self {
+        TreeWalker::with_io(
+            functions,
+            name_table,
+            type_table,
+            max_call_depth,
+            DefaultIO::new(),
+        )
+    }
+}
+
+impl<IO: RuntimeIO> TreeWalker<IO> {
+    pub fn with_io(
+        functions: impl IntoIterator<Item = (Name, Function)>,
+        name_table: NameTable,
+        type_table: TypeTable,
+        max_call_depth: usize,
+        io: IO,
+    ) -> Self {
         TreeWalker {
             memory: Memory::new(),
             scopes: vec![Scope {
                 variables: HashMap::new(),
+                types: HashMap::new(),
             }],
-            functions,
+            functions: Rc::new(functions.into_iter().collect()),
+            name_table,
+            type_table,
+            call_depth: 0,
+            max_call_depth,
+            rng: Rng::new(time_seed()),
+            checked_arithmetic: false,
+            steps: None,
+            io,
+            flush_policy: FlushPolicy::EachPrint,
+            natives: HashMap::new(),
+            output_len: 0,
+            output_limit: None,
+            signal: None,
+            trace: false,
+            source_file: None,
+        }
+    }
+
+    // The name has to be registered with the typechecker too, or
+    // calls to it won't check
+    pub fn register_native(&mut self, name: &str, function: NativeFunction) {
+        let name = self.name_table.insert(name.to_string());
+        self.natives.insert(name, function);
+    }
+
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    // Caps how many bytes print can write in total
+    pub fn with_output_limit(mut self, output_limit: usize) -> Self {
+        self.output_limit = Some(output_limit);
+        self
+    }
+
+    // Lets the host get at whatever the program wrote
+    pub fn io(&self) -> &IO {
+        &self.io
+    }
+
+    // The runtime memory along with its history, for the debugger
+    pub fn memory(&self) -> &Memory<LocationRange> {
+        &self.memory
+    }
+
+    // The variables in scope with their types and values, inner
+    // scopes first. Shadowed variables are left out
+    pub fn variables(&self) -> Vec<(Name, TypeId, u64)> {
+        let mut variables: Vec<(Name, TypeId, u64)> = Vec::new();
+        for scope in self.scopes.iter().rev() {
+            let mut names: Vec<_> = scope.variables.keys().copied().collect();
+            names.sort();
+            for name in names {
+                if variables.iter().any(|(seen, _, _)| *seen == name) {
+                    continue;
+                }
+                if let Some(type_id) = scope.types.get(&name) {
+                    variables.push((name, *type_id, scope.variables[&name]));
+                }
+            }
+        }
+        variables
+    }
+
+    fn flush_output(&mut self) -> Result<(), IError> {
+        self.io
+            .out()
+            .flush()
+            .map_err(|err| IError::new("OutputError", format!("Failed to flush output: {}", err)))
+    }
+
+    pub fn with_checked_arithmetic(mut self, checked_arithmetic: bool) -> Self {
+        self.checked_arithmetic = checked_arithmetic;
+        self
+    }
+
+    // Makes rand and rand_int reproducible across runs
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Rng::new(seed);
+    }
+
+    // Counts every statement and expression interpreted, as a
+    // rough measure of how much work a program does
+    pub fn with_step_count(mut self) -> Self {
+        self.steps = Some(0);
+        self
+    }
+
+    // Writes a line to the log for every statement and call we
+    // run, e.g. `[trace] main.brg:1:1 def`, or `[trace] (0---12) def`
+    // without a source file
+    pub fn with_trace(mut self, trace: bool) -> Self {
+        self.trace = trace;
+        self
+    }
+
+    pub fn with_source_file(mut self, file_name: &str, source: &str) -> Self {
+        self.source_file = Some(SimpleFile::new(file_name.to_string(), source.to_string()));
+        self
+    }
+
+    fn trace(&mut self, location: LocationRange, kind: &str) -> Result<(), IError> {
+        if !self.trace {
+            return Ok(());
         }
+        let position = match &self.source_file {
+            Some(file) => match file.location((), (location.0).0) {
+                Some(start) => format!(
+                    "{}:{}:{}",
+                    file.name(),
+                    start.line_number,
+                    start.column_number
+                ),
+                None => location.to_string(),
+            },
+            None => location.to_string(),
+        };
+        writeln!(self.io.log(), "[trace] {} {}", position, kind)
+            .map_err(|err| IError::new("OutputError", format!("Failed to trace: {}", err)))
+    }
+
+    pub fn step_count(&self) -> Option<u64> {
+        self.steps
+    }
+
+    fn step(&mut self) {
+        if let Some(steps) = &mut self.steps {
+            *steps += 1;
+        }
+    }
+
+    // Replaces the tables with ones that know about newly checked
+    // types and names, i.e. ones from a later REPL line
+    pub fn set_tables(&mut self, name_table: NameTable, type_table: TypeTable) {
+        self.name_table = name_table;
+        self.type_table = type_table;
+    }
+
+    // Frees runtime memory that nothing in scope can reach, keeping
+    // functions and variables. Whatever the variables point at is
+    // copied over to a fresh memory, and the history starts over
+    pub fn reset_memory(&mut self) -> Result<(), IError> {
+        let old_memory = std::mem::replace(&mut self.memory, Memory::new());
+        let mut copies = HashMap::new();
+        for scope_idx in 0..self.scopes.len() {
+            let mut names: Vec<_> = self.scopes[scope_idx].variables.keys().copied().collect();
+            names.sort();
+            for name in names {
+                let scope = &self.scopes[scope_idx];
+                let value = scope.variables[&name];
+                let value = match scope.types.get(&name) {
+                    Some(type_id) => self.copy_value(&old_memory, value, *type_id, &mut copies)?,
+                    None => value,
+                };
+                self.scopes[scope_idx].variables.insert(name, value);
+            }
+        }
+        self.memory.clear_history();
+        Ok(())
+    }
+
+    // Copies a value from `old_memory` into the walker's memory,
+    // following pointers by type. `copies` maps old pointers to new ones
+    // so values shared between variables stay shared
+    fn copy_value(
+        &mut self,
+        old_memory: &Memory<LocationRange>,
+        value: u64,
+        type_id: TypeId,
+        copies: &mut HashMap<u64, u64>,
+    ) -> Result<u64, IError> {
+        let type_ = self.type_table.resolve(type_id).clone();
+        let fields = match &type_ {
+            Type::String => Vec::new(),
+            Type::Tuple(types) => types.clone(),
+            Type::Record(fields) => fields.iter().map(|(_, type_id)| *type_id).collect(),
+            Type::Array(elem_type) => {
+                let count = old_memory.get_var_meta(value.into())?;
+                vec![*elem_type; count as usize]
+            }
+            Type::Optional(payload_type) => match read_optional(old_memory, value)? {
+                Some(_) => vec![*payload_type],
+                None => Vec::new(),
+            },
+            _ => return Ok(value),
+        };
+        if let Some(copy) = copies.get(&value) {
+            return Ok(*copy);
+        }
+        // Bring the bytes over as is, then fix up the pointers in them
+        let old_ptr: VarPointer = value.into();
+        let bytes = old_memory.get_var_slice(old_ptr)?;
+        let meta = old_memory.get_var_meta(old_ptr)?;
+        let location = LocationRange(Location(0), Location(0));
+        let ptr = self
+            .memory
+            .add_heap_var_with_meta(bytes.len() as u32, meta, location);
+        self.memory.write_bytes(ptr, bytes, location)?;
+        copies.insert(value, ptr.into());
+        // Optionals keep their payload after the tag byte
+        let start = if let Type::Optional(_) = type_ { 1 } else { 0 };
+        for (idx, field_type) in fields.into_iter().enumerate() {
+            let offset = start + field_offset(idx)?;
+            let field = old_memory.get_var(old_ptr.with_offset(offset))?;
+            let field = self.copy_value(old_memory, field, field_type, copies)?;
+            self.memory.set(ptr.with_offset(offset), field, location)?;
+        }
+        Ok(ptr.into())
+    }
+
+    // Adds newly checked functions, i.e. ones defined on a later REPL line
+    pub fn add_functions(&mut self, functions: impl IntoIterator<Item = (Name, Function)>) {
+        Rc::make_mut(&mut self.functions).extend(functions);
     }
 
     pub fn interpret_program(&mut self, program: ProgramT) -> Result<(), IError> {
         for stmt in program.stmts {
-            if let Some(val) = self.interpret_stmt(&stmt)? {
-                return err!(
-                    "InvalidReturn",
-                    "return in place there shouldn't be a return"
-                );
+            match self.interpret_stmt(&stmt) {
+                Ok(None) => {}
+                Ok(Some(_)) => {
+                    return err!(
+                        "InvalidReturn",
+                        "return in place there shouldn't be a return"
+                    );
+                }
+                Err(err) => {
+                    // Drop any scopes left over from the failed statement
+                    // so the global scope is usable for the next program
+                    self.scopes.truncate(1);
+                    self.signal = None;
+                    self.flush_output()?;
+                    return Err(err);
+                }
             }
         }
 
-        Ok(())
+        self.flush_output()
+    }
+
+    // Like interpret_program, but returns the value of the last top level
+    // expression statement, or unit if there isn't one
+    pub fn eval_program(&mut self, program: ProgramT) -> Result<Value, IError> {
+        let mut last_value = None;
+        for stmt in program.stmts {
+            let res = match &stmt.inner {
+                StmtT::Expr(expr) => self.interpret_expr(expr).map(|value| {
+                    last_value = Some((value, expr.inner.get_type()));
+                    None
+                }),
+                _ => self.interpret_stmt(&stmt),
+            };
+            match res {
+                Ok(None) => {}
+                Ok(Some(_)) => {
+                    return err!(
+                        "InvalidReturn",
+                        "return in place there shouldn't be a return"
+                    );
+                }
+                Err(err) => {
+                    self.scopes.truncate(1);
+                    self.signal = None;
+                    self.flush_output()?;
+                    return Err(err);
+                }
+            }
+        }
+
+        self.flush_output()?;
+        match last_value {
+            Some((value, type_)) => self.to_value(value, type_),
+            None => Ok(Value::Empty),
+        }
+    }
+
+    // Converts a raw value of the given type back into a Value,
+    // following pointers for strings, tuples and records
+    fn to_value(&self, value: u64, type_: TypeId) -> Result<Value, IError> {
+        match self.type_table.get_type(type_) {
+            Type::Int => Ok(Value::Integer(value as i64)),
+            Type::Float => Ok(Value::Float(f64::from_bits(value))),
+            Type::Bool => Ok(Value::Bool(value != 0)),
+            Type::String => Ok(Value::String(self.read_string(value)?.to_string())),
+            Type::Char => Ok(Value::Char(read_char(value)?)),
+            Type::Unit => Ok(Value::Empty),
+            Type::Solved(type_) => self.to_value(value, *type_),
+            Type::Tuple(types) => {
+                let types = types.clone();
+                self.tuple_value(value, &types)
+            }
+            Type::Record(fields) => {
+                let types: Vec<_> = fields.iter().map(|(_, type_)| *type_).collect();
+                self.tuple_value(value, &types)
+            }
+            _ => err!(
+                "UnsupportedValue",
+                "Cannot convert a value of type {} to a Value",
+                type_to_string(&self.name_table, &self.type_table, type_)
+            ),
+        }
+    }
+
+    fn tuple_value(&self, value: u64, types: &[TypeId]) -> Result<Value, IError> {
+        let ptr: VarPointer = value.into();
+        let mut values = Vec::new();
+        for (idx, type_) in types.iter().enumerate() {
+            let field = self.memory.get_var(ptr.with_offset(field_offset(idx)?))?;
+            values.push(self.to_value(field, *type_)?);
+        }
+        Ok(Value::Tuple(values))
     }
 
     fn lookup_in_scope(&self, name: &Name) -> Option<u64> {
@@ -60,43 +562,125 @@ impl TreeWalker {
         None
     }
 
-    fn update_in_scope(&mut self, name: &Name, value: u64) {
+    fn update_in_scope(
+        &mut self,
+        name: &Name,
+        value: u64,
+        location: LocationRange,
+    ) -> Result<(), IError> {
         for scope in self.scopes.iter_mut().rev() {
             if let Some(val) = scope.variables.get_mut(name) {
                 *val = value;
-                return;
+                return Ok(());
             }
         }
 
-        panic!("assigned to variable that doesn't exist");
+        Err(self.internal_error(
+            location,
+            format!(
+                "Assigned to variable '{}' that doesn't exist",
+                self.name_str(*name)
+            ),
+        ))
+    }
+
+    // The typechecker should rule these out, so if one happens
+    // it's a bug in the typechecker or the walker
+    fn internal_error(&self, location: LocationRange, message: String) -> IError {
+        IError::new("InternalError", message).with_location(location)
+    }
+
+    // Name for error messages. Doesn't assume the name is in the
+    // table since it's used when the AST is malformed
+    fn name_str(&self, name: Name) -> String {
+        match self.name_table.try_get_str(&name) {
+            Some(name_str) => name_str.to_string(),
+            None => format!("<name {}>", name),
+        }
     }
 
-    // returns whether or not to return
-    fn interpret_stmt(&mut self, stmt: &Loc<StmtT>) -> Result<Option<u64>, IError> {
+    // Returns the signal if the statement jumps
+    fn interpret_stmt(&mut self, stmt: &Loc<StmtT>) -> Result<Option<Signal>, IError> {
+        self.step();
+        if self.trace {
+            self.trace(stmt.location, stmt_kind(&stmt.inner))?;
+        }
         match &stmt.inner {
             StmtT::Def(name, rhs) => {
                 let rhs_val = self.interpret_expr(rhs)?;
-                self.scopes
-                    .last_mut()
-                    .unwrap()
-                    .variables
-                    .insert(*name, rhs_val);
+                match self.scopes.last_mut() {
+                    Some(scope) => {
+                        scope.types.insert(*name, rhs.inner.get_type());
+                        scope.variables.insert(*name, rhs_val)
+                    }
+                    None => {
+                        let message = "No scope to define variable in".to_string();
+                        return Err(self.internal_error(stmt.location, message));
+                    }
+                };
             }
             StmtT::Asgn(name, rhs) => {
                 let rhs_val = self.interpret_expr(rhs)?;
-                self.update_in_scope(name, rhs_val);
+                self.update_in_scope(name, rhs_val, stmt.location)?;
+            }
+            StmtT::ChainedAsgn(names, rhs) => {
+                let rhs_val = self.interpret_expr(rhs)?;
+                for name in names.iter().rev() {
+                    self.update_in_scope(name, rhs_val, stmt.location)?;
+                }
+            }
+            StmtT::TupleFieldAsgn(tuple, pos, rhs) => {
+                let ptr: VarPointer = self.interpret_expr(tuple)?.into();
+                let rhs_val = self.interpret_expr(rhs)?;
+                let field_ptr = self.field_ptr(ptr, *pos, stmt.location)?;
+                self.memory.set(field_ptr, rhs_val, stmt.location)?;
             }
             StmtT::Expr(expr) => {
                 self.interpret_expr(expr)?;
             }
             StmtT::Function(_) => {}
-            StmtT::Return(expr) => return Ok(Some(self.interpret_expr(expr)?)),
+            StmtT::Return(expr) => {
+                let value = self.interpret_expr(expr)?;
+                return Ok(Some(self.signal.take().unwrap_or(Signal::Return(value))));
+            }
+            StmtT::Block(stmts) => {
+                self.scopes.push(Scope {
+                    variables: HashMap::new(),
+                    types: HashMap::new(),
+                });
+                for stmt in stmts {
+                    if let Some(signal) = self.interpret_stmt(stmt)? {
+                        self.scopes.pop();
+                        return Ok(Some(signal));
+                    }
+                }
+                self.scopes.pop();
+            }
+            StmtT::While(cond, body) => loop {
+                let cond_val = self.interpret_expr(cond)?;
+                if let Some(signal) = self.signal.take() {
+                    return Ok(Some(signal));
+                }
+                if cond_val == 0 {
+                    break;
+                }
+                self.interpret_expr(body)?;
+                match self.signal.take() {
+                    Some(Signal::Break) => break,
+                    Some(Signal::Continue) | None => {}
+                    Some(signal) => return Ok(Some(signal)),
+                }
+            },
+            StmtT::Break => return Ok(Some(Signal::Break)),
+            StmtT::Continue => return Ok(Some(Signal::Continue)),
         }
 
-        Ok(None)
+        // A jump from inside one of the statement's expressions
+        Ok(self.signal.take())
     }
 
     pub fn interpret_expr(&mut self, expr: &Loc<ExprT>) -> Result<u64, IError> {
+        self.step();
         match &expr.inner {
             ExprT::Primary { value, type_: _ } => self.interpret_value(value, expr.location),
             ExprT::BinOp {
@@ -106,34 +690,78 @@ impl TreeWalker {
                 type_,
             } => {
                 let l = self.interpret_expr(lhs)?;
+                // Short circuit, so the rhs only runs if the lhs
+                // doesn't already decide the result
+                match op {
+                    Op::And if l == 0 => return Ok(0),
+                    Op::Or if l != 0 => return Ok(1),
+                    Op::And | Op::Or => return self.interpret_expr(rhs),
+                    _ => {}
+                }
                 let r = self.interpret_expr(rhs)?;
                 let (l_i, r_i) = (l as i64, r as i64);
                 let (l_f, r_f) = (f64::from_bits(l), f64::from_bits(r));
 
-                let result = match (op, lhs.inner.get_type(), rhs.inner.get_type()) {
-                    (Op::Plus, INT_INDEX, INT_INDEX) => (l_i + r_i) as u64,
+                // Operands typed as solved type variables still go
+                // through the arms for the type they were solved to.
+                // Any is never solved, so it ends up in the error below
+                let lhs_type = self.type_table.resolve_id(lhs.inner.get_type());
+                let rhs_type = self.type_table.resolve_id(rhs.inner.get_type());
+                let result = match (op, lhs_type, rhs_type) {
+                    (Op::Plus, INT_INDEX, INT_INDEX) => self.int_arith(
+                        l_i,
+                        r_i,
+                        i64::checked_add,
+                        i64::wrapping_add,
+                        op,
+                        expr.location,
+                    )?,
                     (Op::Plus, FLOAT_INDEX, INT_INDEX) => (l_f + r_i as f64).to_bits(),
                     (Op::Plus, INT_INDEX, FLOAT_INDEX) => (l_i as f64 + r_f).to_bits(),
                     (Op::Plus, FLOAT_INDEX, FLOAT_INDEX) => (l_f + r_f).to_bits(),
+                    (Op::Plus, STR_INDEX, STR_INDEX) => self.concat_strings(l, r, expr.location)?,
 
-                    (Op::Minus, INT_INDEX, INT_INDEX) => (l_i - r_i) as u64,
+                    (Op::Minus, INT_INDEX, INT_INDEX) => self.int_arith(
+                        l_i,
+                        r_i,
+                        i64::checked_sub,
+                        i64::wrapping_sub,
+                        op,
+                        expr.location,
+                    )?,
                     (Op::Minus, FLOAT_INDEX, INT_INDEX) => (l_f - r_i as f64).to_bits(),
                     (Op::Minus, INT_INDEX, FLOAT_INDEX) => (l_i as f64 - r_f).to_bits(),
                     (Op::Minus, FLOAT_INDEX, FLOAT_INDEX) => (l_f - r_f).to_bits(),
 
-                    (Op::Div, INT_INDEX, INT_INDEX) => (l_i / r_i) as u64,
+                    (Op::Div, INT_INDEX, INT_INDEX) => int_div(l_i, r_i, expr.location)?,
                     (Op::Div, FLOAT_INDEX, INT_INDEX) => (l_f / r_i as f64).to_bits(),
                     (Op::Div, INT_INDEX, FLOAT_INDEX) => (l_i as f64 / r_f).to_bits(),
                     (Op::Div, FLOAT_INDEX, FLOAT_INDEX) => (l_f / r_f).to_bits(),
 
-                    (Op::Times, INT_INDEX, INT_INDEX) => (l_i * r_i) as u64,
+                    (Op::Times, INT_INDEX, INT_INDEX) => self.int_arith(
+                        l_i,
+                        r_i,
+                        i64::checked_mul,
+                        i64::wrapping_mul,
+                        op,
+                        expr.location,
+                    )?,
                     (Op::Times, FLOAT_INDEX, INT_INDEX) => (l_f * r_i as f64).to_bits(),
                     (Op::Times, INT_INDEX, FLOAT_INDEX) => (l_i as f64 * r_f).to_bits(),
                     (Op::Times, FLOAT_INDEX, FLOAT_INDEX) => (l_f * r_f).to_bits(),
 
-                    // TODO should negative zero be equal to zero?
-                    (Op::BangEqual, _, _) => (l != r) as u64,
-                    (Op::EqualEqual, _, _) => (l == r) as u64,
+                    (Op::BitAnd, INT_INDEX, INT_INDEX) => l & r,
+                    (Op::BitOr, INT_INDEX, INT_INDEX) => l | r,
+                    // Bools are 0 or 1, so these work as logical ops
+                    (Op::BitAnd, BOOL_INDEX, BOOL_INDEX) => l & r,
+                    (Op::BitOr, BOOL_INDEX, BOOL_INDEX) => l | r,
+                    (Op::BitXor, INT_INDEX, INT_INDEX) => l ^ r,
+                    (Op::Shl, INT_INDEX, INT_INDEX) | (Op::Shr, INT_INDEX, INT_INDEX) => {
+                        int_shift(l_i, r_i, op, expr.location)?
+                    }
+
+                    (Op::BangEqual, _, _) => !self.values_equal(l, r, lhs_type)? as u64,
+                    (Op::EqualEqual, _, _) => self.values_equal(l, r, lhs_type)? as u64,
 
                     (Op::Greater, INT_INDEX, INT_INDEX) => (l_i > r_i) as u64,
                     (Op::Greater, FLOAT_INDEX, INT_INDEX) => (l_f > r_i as f64) as u64,
@@ -155,7 +783,17 @@ impl TreeWalker {
                     (Op::LessEqual, INT_INDEX, FLOAT_INDEX) => ((l_i as f64) <= r_f) as u64,
                     (Op::LessEqual, FLOAT_INDEX, FLOAT_INDEX) => (l_f <= r_f) as u64,
 
-                    _ => panic!("unexpected combination of operand types"),
+                    (op, lhs_type, rhs_type) => {
+                        return Err(self.internal_error(
+                            expr.location,
+                            format!(
+                                "Cannot apply {} to {} and {}",
+                                op,
+                                type_to_string(&self.name_table, &self.type_table, lhs_type),
+                                type_to_string(&self.name_table, &self.type_table, rhs_type)
+                            ),
+                        ))
+                    }
                 };
 
                 return Ok(result);
@@ -178,49 +816,174 @@ impl TreeWalker {
             } => {
                 self.scopes.push(Scope {
                     variables: HashMap::new(),
+                    types: HashMap::new(),
                 });
 
                 for stmt in stmts {
-                    self.interpret_stmt(stmt)?;
+                    if let Some(signal) = self.interpret_stmt(stmt)? {
+                        self.signal = Some(signal);
+                        break;
+                    }
                 }
 
-                if let Some(expr) = end_expr {
-                    let val = self.interpret_expr(expr)?;
-                    return Ok(val);
-                } else {
-                    self.scopes.pop();
-                    return Ok(0);
-                }
+                let val = match end_expr {
+                    Some(expr) if self.signal.is_none() => self.interpret_expr(expr)?,
+                    _ => 0,
+                };
+                self.scopes.pop();
+                return Ok(val);
             }
             ExprT::Call {
                 callee,
                 args,
-                type_: _,
+                type_,
             } => {
+                if self.trace {
+                    let kind = format!("call {}", self.name_table.get_str(callee));
+                    self.trace(expr.location, &kind)?;
+                }
                 if *callee == PRINT_INDEX {
                     for arg in args {
                         self.print_expr(arg)?;
                     }
                     return Ok(0);
+                } else if let Some(float_fn) = float_builtin(*callee) {
+                    let arg = f64::from_bits(self.interpret_expr(&args[0])?);
+                    return Ok(float_fn(arg).to_bits());
+                } else if let Some(val) =
+                    self.overloaded_builtin(*callee, args, *type_, expr.location)?
+                {
+                    return Ok(val);
+                } else if *callee == SOME_INDEX {
+                    let payload = self.interpret_expr(&args[0])?;
+                    return self.alloc_optional(Some(payload), expr.location);
+                } else if *callee == UNWRAP_INDEX {
+                    let value = self.interpret_expr(&args[0])?;
+                    return match read_optional(&self.memory, value)? {
+                        Some(payload) => Ok(payload),
+                        None => Err(IError::new("UnwrapNone", "Unwrapped a none".to_string())
+                            .with_location(expr.location)),
+                    };
+                } else if *callee == LEN_INDEX {
+                    let ptr: VarPointer = self.interpret_expr(&args[0])?.into();
+                    let len = self.memory.get_var_len(ptr)?;
+                    // Strings have a null terminator, arrays are 8 byte words
+                    let len = if args[0].inner.get_type() == STR_INDEX {
+                        len - 1
+                    } else {
+                        len / 8
+                    };
+                    return Ok(len as u64);
+                } else if *callee == REQUIRE_INDEX {
+                    if self.interpret_expr(&args[0])? == 0 {
+                        let message = self.interpret_expr(&args[1])?;
+                        let message = self.read_string(message)?.to_string();
+                        // Points at the condition, like assert does
+                        let err = IError::new("RequireFailed", message);
+                        return Err(err.with_location(args[0].location));
+                    }
+                    return Ok(0);
+                } else if *callee == ASSERT_INDEX {
+                    let location = args[0].location;
+                    if self.interpret_expr(&args[0])? == 0 {
+                        let message = match args.get(1) {
+                            Some(arg) => {
+                                let message = self.interpret_expr(arg)?;
+                                self.read_string(message)?.to_string()
+                            }
+                            None => "assertion failed".to_string(),
+                        };
+                        if !self.io.record_assertion(location, Some(&message)) {
+                            let err = IError::new("AssertionFailed", message);
+                            return Err(err.with_location(location));
+                        }
+                    } else {
+                        self.io.record_assertion(location, None);
+                    }
+                    return Ok(0);
+                } else if *callee == RAND_INDEX {
+                    return Ok(self.rng.next_f64().to_bits());
+                } else if *callee == RAND_INT_INDEX {
+                    let lo = self.interpret_expr(&args[0])? as i64;
+                    let hi = self.interpret_expr(&args[1])? as i64;
+                    if lo >= hi {
+                        return err!(
+                            "InvalidRange",
+                            "rand_int needs lo < hi, got {} and {}",
+                            lo,
+                            hi
+                        );
+                    }
+                    return Ok(self.rng.next_in_range(lo, hi) as u64);
+                } else if *callee == READ_LINE_INDEX {
+                    // Hands back the line without its newline, or an
+                    // empty string once the input runs out
+                    let mut line = String::new();
+                    self.io.input().read_line(&mut line).map_err(|err| {
+                        IError::new("InputError", format!("Failed to read line: {}", err))
+                    })?;
+                    if line.ends_with('\n') {
+                        line.pop();
+                        if line.ends_with('\r') {
+                            line.pop();
+                        }
+                    }
+                    return self.interpret_value(&Value::String(line), expr.location);
+                } else if *callee == TYPE_NAME_INDEX {
+                    // Types are static so we don't need the value, but
+                    // the argument still has to run for its side effects
+                    self.interpret_expr(&args[0])?;
+                    let type_name = type_to_string(
+                        &self.name_table,
+                        &self.type_table,
+                        args[0].inner.get_type(),
+                    );
+                    return self.interpret_value(&Value::String(type_name), expr.location);
                 } else {
-                    let functions = self.functions.clone();
-                    let func = functions
-                        .get(&callee)
-                        .expect("Internal error: function is not defined");
-                    self.scopes.push(Scope {
-                        variables: HashMap::new(),
-                    });
-
-                    for (i, param) in func.params.iter().enumerate() {
-                        let name = param.inner.0;
-                        let arg_val = self.interpret_expr(&args[i])?;
-                        let current_scope = self.scopes.last_mut().unwrap();
-                        current_scope.variables.insert(name, arg_val);
+                    let functions = Rc::clone(&self.functions);
+                    let func = match functions.get(callee) {
+                        Some(func) => func,
+                        None if self.natives.contains_key(callee) => {
+                            return self.call_native(*callee, args, expr.location)
+                        }
+                        None => {
+                            return Err(self.internal_error(
+                                expr.location,
+                                format!("Function '{}' is not defined", self.name_str(*callee)),
+                            ))
+                        }
+                    };
+
+                    // Evaluate args in the caller's scope before
+                    // the params can shadow anything
+                    let mut variables = HashMap::new();
+                    let mut types = HashMap::new();
+                    for (param, arg) in func.params.iter().zip(args) {
+                        variables.insert(param.inner.0, self.interpret_expr(arg)?);
+                        types.insert(param.inner.0, param.inner.1);
                     }
 
-                    let val = self.interpret_expr(&func.body)?;
+                    if self.call_depth >= self.max_call_depth {
+                        return err!(
+                            "StackOverflow",
+                            "Exceeded maximum call depth of {}",
+                            self.max_call_depth
+                        );
+                    }
+                    self.call_depth += 1;
+                    self.scopes.push(Scope { variables, types });
+                    let val = self.interpret_expr(&func.body);
                     self.scopes.pop();
-                    return Ok(val);
+                    self.call_depth -= 1;
+                    let val = val?;
+                    return match self.signal.take() {
+                        Some(Signal::Return(value)) => Ok(value),
+                        Some(_) => Err(self.internal_error(
+                            expr.location,
+                            "break or continue escaped a function".to_string(),
+                        )),
+                        None => Ok(val),
+                    };
                 }
             }
             ExprT::Tuple(entries, _) => {
@@ -230,58 +993,318 @@ impl TreeWalker {
                     values.push(self.interpret_expr(value)?);
                 }
 
-                let ptr = self
-                    .memory
-                    .add_heap_var(values.len() as u32 * 8, expr.location);
-                for (idx, value) in values.iter().enumerate() {
-                    self.memory
-                        .set(ptr.with_offset(idx as u32 * 8), value, expr.location)?;
+                return self.alloc_words(values, expr.location, u32::MAX);
+            }
+            ExprT::Record {
+                name,
+                fields,
+                type_,
+            } => {
+                let mut values = Vec::new();
+                for field in fields {
+                    values.push(self.interpret_expr(field)?);
+                }
+                return self.alloc_words(values, expr.location, u32::MAX);
+            }
+            ExprT::Array(entries, _) => {
+                // Stored just like a tuple. The length comes from the
+                // size of the heap var
+                let mut values = Vec::new();
+                for value in entries {
+                    values.push(self.interpret_expr(value)?);
                 }
 
-                return Ok(ptr.into());
+                return self.alloc_words(values, expr.location, u32::MAX);
             }
             ExprT::TupleField(tuple, pos, _) => {
-                let pos = (*pos) as u32;
                 let ptr: VarPointer = self.interpret_expr(tuple)?.into();
-                return Ok(self.memory.get_var(ptr.with_offset(pos))?);
+                let field_ptr = self.field_ptr(ptr, *pos, expr.location)?;
+                return self.memory.get_var(field_ptr);
+            }
+            ExprT::Var { name, type_: _ } => match self.lookup_in_scope(name) {
+                Some(value) => Ok(value),
+                None => Err(self.internal_error(
+                    expr.location,
+                    format!("Variable '{}' is not defined", self.name_str(*name)),
+                )),
+            },
+            ExprT::Error(_) => Err(self.internal_error(
+                expr.location,
+                "Cannot run an expression that failed to typecheck".to_string(),
+            )),
+            ExprT::Cast(lhs, to) => {
+                let value = self.interpret_expr(lhs)?;
+                let from = lhs.inner.get_type();
+                Ok(if from == INT_INDEX && *to == FLOAT_INDEX {
+                    (value as i64 as f64).to_bits()
+                } else if from == FLOAT_INDEX && *to == INT_INDEX {
+                    f64::from_bits(value) as i64 as u64
+                } else {
+                    value
+                })
             }
-            ExprT::Var { name, type_: _ } => Ok(self
-                .lookup_in_scope(name)
-                .expect("Internal error: variable is not defined")),
             ExprT::UnaryOp { op, rhs, type_: _ } => {
                 let r = self.interpret_expr(rhs)?;
                 let r_i = r as i64;
                 match op {
-                    UnaryOp::Minus => return Ok((-r_i) as u64),
+                    UnaryOp::Minus if rhs.inner.get_type() == FLOAT_INDEX => {
+                        Ok((-f64::from_bits(r)).to_bits())
+                    }
+                    UnaryOp::Minus => self.int_neg(r_i, expr.location),
                     UnaryOp::Not => Ok(if r == 0 { 1 } else { 0 }),
                 }
             }
         }
     }
 
+    // Runs abs, min, max or to_string, whichever overload the typechecker
+    // picked. Returns None if the callee isn't one of them
+    fn overloaded_builtin(
+        &mut self,
+        callee: Name,
+        args: &[Loc<ExprT>],
+        type_: TypeId,
+        location: LocationRange,
+    ) -> Result<Option<u64>, IError> {
+        if ![ABS_INDEX, MIN_INDEX, MAX_INDEX, TO_STRING_INDEX].contains(&callee) {
+            return Ok(None);
+        }
+        let mut values = Vec::new();
+        for arg in args {
+            values.push(self.interpret_expr(arg)?);
+        }
+        let val = match (callee, type_) {
+            (ABS_INDEX, INT_INDEX) => (values[0] as i64).wrapping_abs() as u64,
+            (ABS_INDEX, _) => f64::from_bits(values[0]).abs().to_bits(),
+            (MIN_INDEX, INT_INDEX) => (values[0] as i64).min(values[1] as i64) as u64,
+            (MIN_INDEX, _) => f64::from_bits(values[0])
+                .min(f64::from_bits(values[1]))
+                .to_bits(),
+            (MAX_INDEX, INT_INDEX) => (values[0] as i64).max(values[1] as i64) as u64,
+            (MAX_INDEX, _) => f64::from_bits(values[0])
+                .max(f64::from_bits(values[1]))
+                .to_bits(),
+            _ => {
+                let string = match args[0].inner.get_type() {
+                    FLOAT_INDEX => f64::from_bits(values[0]).to_string(),
+                    BOOL_INDEX => (values[0] != 0).to_string(),
+                    _ => (values[0] as i64).to_string(),
+                };
+                self.interpret_value(&Value::String(string), location)?
+            }
+        };
+        Ok(Some(val))
+    }
+
     pub fn print_expr(&mut self, expr: &Loc<ExprT>) -> Result<(), IError> {
+        let output = format!("{}\n", self.format_expr(expr)?);
+        // Write as much as fits under the limit, then stop the program
+        let (output, exceeded) = match self.output_limit {
+            Some(limit) if self.output_len + output.len() > limit => {
+                (&output.as_bytes()[..limit - self.output_len], true)
+            }
+            _ => (output.as_bytes(), false),
+        };
+        self.io
+            .out()
+            .write_all(output)
+            .map_err(|err| IError::new("OutputError", format!("Failed to print: {}", err)))?;
+        self.output_len += output.len();
+        if self.flush_policy == FlushPolicy::EachPrint {
+            self.flush_output()?;
+        }
+        if exceeded {
+            return Err(IError::new(
+                "OutputLimitExceeded",
+                format!("Output is over the limit of {} bytes", self.output_len),
+            )
+            .with_location(expr.location));
+        }
+        Ok(())
+    }
+
+    // Evaluates the expression and renders its value the way print does
+    pub fn format_expr(&mut self, expr: &Loc<ExprT>) -> Result<String, IError> {
         let value = self.interpret_expr(expr)?;
-        match expr.inner.get_type() {
-            INT_INDEX => println!("{}", value as i64),
-            FLOAT_INDEX => println!("{}", f64::from_bits(value)),
-            STR_INDEX => {
-                let ptr: VarPointer = value.into();
-                let string = self.memory.get_var_slice(ptr)?;
-                let string = unsafe { std::str::from_utf8_unchecked(string) };
-                println!("{}", string);
+        format_value(
+            &self.memory,
+            &self.name_table,
+            &self.type_table,
+            value,
+            expr.inner.get_type(),
+        )
+    }
+
+    // Puts the values in a new heap var one word apiece, like
+    // tuples and arrays are laid out. Errors if the var would
+    // be bigger than `max_size` bytes
+    fn alloc_words(
+        &mut self,
+        values: Vec<u64>,
+        location: LocationRange,
+        max_size: u32,
+    ) -> Result<u64, IError> {
+        let size = allocation_size(values.len(), 8, max_size)?;
+        let ptr = self
+            .memory
+            .add_heap_var_with_meta(size, values.len() as u32, location);
+        for (idx, value) in values.into_iter().enumerate() {
+            self.memory
+                .set(ptr.with_offset(field_offset(idx)?), value, location)?;
+        }
+        Ok(ptr.into())
+    }
+
+    // Points at a field of a tuple or record. Every value is stored as a
+    // single word, so fields are 8 bytes apart. Checks against the element
+    // count stored with the var so a bad index can't read the next var
+    fn field_ptr(
+        &self,
+        ptr: VarPointer,
+        pos: usize,
+        location: LocationRange,
+    ) -> Result<VarPointer, IError> {
+        let count = self.memory.get_var_meta(ptr)?;
+        if pos >= count as usize {
+            return err!(
+                "IndexOutOfBounds",
+                "Field {} is out of bounds for {} elements",
+                pos,
+                count
+            )
+            .map_err(|err: IError| err.with_location(location));
+        }
+        Ok(ptr.with_offset(field_offset(pos)?))
+    }
+
+    // Int +, - and *. Wraps on overflow unless checked arithmetic is on
+    fn int_arith(
+        &self,
+        l: i64,
+        r: i64,
+        checked: fn(i64, i64) -> Option<i64>,
+        wrapping: fn(i64, i64) -> i64,
+        op: &Op,
+        location: LocationRange,
+    ) -> Result<u64, IError> {
+        if !self.checked_arithmetic {
+            return Ok(wrapping(l, r) as u64);
+        }
+        match checked(l, r) {
+            Some(res) => Ok(res as u64),
+            None => {
+                let err = IError::new("IntegerOverflow", format!("{} {} {} overflows", l, op, r));
+                Err(err.with_location(location))
             }
-            UNIT_INDEX => println!("()"),
-            BOOL_INDEX => println!("{}", value != 0),
-            id => panic!("invalid type id: {}", id),
         }
+    }
 
-        Ok(())
+    // Like int_arith. Only -i64::MIN overflows
+    fn int_neg(&self, r: i64, location: LocationRange) -> Result<u64, IError> {
+        if !self.checked_arithmetic {
+            return Ok(r.wrapping_neg() as u64);
+        }
+        match r.checked_neg() {
+            Some(res) => Ok(res as u64),
+            None => {
+                let err = IError::new("IntegerOverflow", format!("-({}) overflows", r));
+                Err(err.with_location(location))
+            }
+        }
+    }
+
+    fn read_string(&self, value: u64) -> Result<&str, IError> {
+        read_string(&self.memory, value)
+    }
+
+    fn call_native(
+        &mut self,
+        callee: Name,
+        args: &[Loc<ExprT>],
+        location: LocationRange,
+    ) -> Result<u64, IError> {
+        let mut values = Vec::new();
+        for arg in args {
+            let value = self.interpret_expr(arg)?;
+            values.push(self.to_value(value, arg.inner.get_type())?);
+        }
+        let result = (self.natives[&callee])(&values)?;
+        self.interpret_value(&result, location)
+    }
+
+    // Equality on two values of the same type. Heap values compare by
+    // what they point to, not the pointer: strings byte-wise, and tuples,
+    // records and arrays entry by entry. Floats compare as floats so
+    // -0.0 == 0.0 and NaN != NaN. Everything else compares bitwise
+    fn values_equal(&self, l: u64, r: u64, type_id: TypeId) -> Result<bool, IError> {
+        let entry_types = match self.type_table.resolve(type_id) {
+            Type::Float => return Ok(f64::from_bits(l) == f64::from_bits(r)),
+            Type::String => {
+                return Ok(read_string(&self.memory, l)? == read_string(&self.memory, r)?)
+            }
+            Type::Tuple(types) => types.clone(),
+            Type::Record(fields) => fields.iter().map(|(_, type_)| *type_).collect(),
+            Type::Optional(type_) => {
+                return match (
+                    read_optional(&self.memory, l)?,
+                    read_optional(&self.memory, r)?,
+                ) {
+                    (Some(l), Some(r)) => self.values_equal(l, r, *type_),
+                    (l, r) => Ok(l.is_none() && r.is_none()),
+                };
+            }
+            Type::Array(elem_type) => {
+                let (l_ptr, r_ptr): (VarPointer, VarPointer) = (l.into(), r.into());
+                let len = self.memory.get_var_len(l_ptr)?;
+                if len != self.memory.get_var_len(r_ptr)? {
+                    return Ok(false);
+                }
+                vec![*elem_type; len as usize / 8]
+            }
+            _ => return Ok(l == r),
+        };
+        let (l_ptr, r_ptr): (VarPointer, VarPointer) = (l.into(), r.into());
+        for (idx, entry_type) in entry_types.into_iter().enumerate() {
+            let offset = field_offset(idx)?;
+            let l_entry = self.memory.get_var(l_ptr.with_offset(offset))?;
+            let r_entry = self.memory.get_var(r_ptr.with_offset(offset))?;
+            if !self.values_equal(l_entry, r_entry, entry_type)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn alloc_optional(
+        &mut self,
+        payload: Option<u64>,
+        location: LocationRange,
+    ) -> Result<u64, IError> {
+        let ptr = self.memory.add_heap_var(OPTIONAL_SIZE, location);
+        if let Some(payload) = payload {
+            self.memory.set(ptr, 1u8, location)?;
+            self.memory.set(ptr.with_offset(1), payload, location)?;
+        }
+        Ok(ptr.into())
+    }
+
+    // Allocates a new string holding both strings one after the other
+    fn concat_strings(&mut self, l: u64, r: u64, location: LocationRange) -> Result<u64, IError> {
+        let mut bytes = read_string(&self.memory, l)?.as_bytes().to_vec();
+        bytes.extend_from_slice(read_string(&self.memory, r)?.as_bytes());
+        bytes.push(0);
+        let size = allocation_size(bytes.len(), 1, u32::MAX)?;
+        let ptr = self.memory.add_heap_var_with_meta(size, size - 1, location);
+        self.memory.write_bytes(ptr, &bytes, location)?;
+        Ok(ptr.into())
     }
 
     fn interpret_value(&mut self, value: &Value, location: LocationRange) -> Result<u64, IError> {
         match value {
             Value::Integer(i) => return Ok(*i as u64),
+            Value::Char(c) => return Ok(*c as u64),
             Value::Empty => return Ok(0),
+            Value::None => return self.alloc_optional(None, location),
             Value::Float(f) => return Ok(f.to_bits()),
             Value::Bool(val) => {
                 if *val {
@@ -297,19 +1320,15 @@ impl TreeWalker {
                     values.push(self.interpret_value(value, location)?);
                 }
 
-                let ptr = self.memory.add_heap_var(values.len() as u32 * 8, location);
-                for (idx, value) in values.iter().enumerate() {
-                    self.memory
-                        .set(ptr.with_offset(idx as u32 * 8), value, location)?;
-                }
-
-                return Ok(ptr.into());
+                return self.alloc_words(values, location, u32::MAX);
             }
             Value::String(string) => {
                 let str_value = string.as_bytes();
-                let str_len = str_value.len() as u32; // TODO check for overflow
+                // One more for the null terminator
+                let size = allocation_size(str_value.len() + 1, 1, u32::MAX)?;
+                let str_len = size - 1;
 
-                let ptr = self.memory.add_heap_var(str_len + 1, location);
+                let ptr = self.memory.add_heap_var_with_meta(size, str_len, location);
                 self.memory.write_bytes(ptr, str_value, location)?;
                 let mut end_ptr = ptr;
                 end_ptr.set_offset(str_len);
@@ -319,3 +1338,791 @@ impl TreeWalker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{ExprT, Loc, ProgramT, Type, TypeId, Value};
+    use crate::lexer::{Lexer, Location, LocationRange};
+    use crate::parser::Parser;
+    use crate::printer::format_value;
+    use crate::runtime::{DefaultIO, IError, InMemoryIO, RuntimeIO, VarPointer};
+    use crate::treewalker::{allocation_size, FlushPolicy, TreeWalker, DEFAULT_MAX_CALL_DEPTH};
+    use crate::typechecker::TypeChecker;
+    use crate::utils::{NameTable, TypeTable, ANY_INDEX, INT_INDEX};
+    use std::collections::HashMap;
+    use std::thread;
+
+    // Typechecks a program and sets up a walker to run it
+    fn check_program<IO: RuntimeIO>(
+        source: &str,
+        max_call_depth: usize,
+        io: IO,
+    ) -> (ProgramT, TreeWalker<IO>) {
+        let lexer = Lexer::new(source);
+        let mut parser = Parser::new(lexer);
+        let program = parser.program().expect("failed to parse");
+        assert!(program.errors.is_empty(), "{:?}", program.errors);
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let treewalker = TreeWalker::with_io(functions, name_table, type_table, max_call_depth, io);
+        (program_t, treewalker)
+    }
+
+    fn run_program_with_walker(
+        source: &str,
+        max_call_depth: usize,
+    ) -> (TreeWalker, Result<(), IError>) {
+        let (program_t, mut treewalker) = check_program(source, max_call_depth, DefaultIO::new());
+        let res = treewalker.interpret_program(program_t);
+        (treewalker, res)
+    }
+
+    fn run_program(source: &str, max_call_depth: usize) -> Result<(), IError> {
+        run_program_with_walker(source, max_call_depth).1
+    }
+
+    // Looks up a variable left in the global scope after running a program
+    fn global(treewalker: &TreeWalker, name: &str) -> u64 {
+        let name = treewalker.name_table.get_id(name).unwrap();
+        treewalker.lookup_in_scope(name).unwrap()
+    }
+
+    // Typechecks an expression and sets up a walker to run it
+    fn check_expr(source: &str) -> (Loc<ExprT>, TreeWalker) {
+        let lexer = Lexer::new(source);
+        let mut parser = Parser::new(lexer);
+        let expr = parser.expr().expect("failed to parse");
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let expr_t = typechecker.expr(expr).expect("failed to typecheck");
+        let functions = typechecker.take_functions();
+        let (_, name_table, type_table) = typechecker.get_tables();
+        (expr_t, TreeWalker::new(functions, name_table, type_table))
+    }
+
+    fn eval_with_walker(source: &str) -> (TreeWalker, Result<u64, IError>) {
+        let (expr_t, mut treewalker) = check_expr(source);
+        let res = treewalker.interpret_expr(&expr_t);
+        (treewalker, res)
+    }
+
+    fn eval(source: &str) -> Result<u64, IError> {
+        eval_with_walker(source).1
+    }
+
+    fn eval_string(source: &str) -> Result<String, IError> {
+        let (treewalker, res) = eval_with_walker(source);
+        Ok(treewalker.read_string(res?)?.to_string())
+    }
+
+    #[test]
+    fn float_builtins() -> Result<(), IError> {
+        assert_eq!(eval("sqrt(4.0) == 2.0")?, 1);
+        assert_eq!(eval("floor(2.7) == 2.0")?, 1);
+        assert_eq!(eval("ceil(2.2) == 3.0")?, 1);
+        assert_eq!(eval("sin(0.0) == 0.0")?, 1);
+        assert_eq!(eval("cos(0.0) == 1.0")?, 1);
+        assert!(f64::from_bits(eval("sqrt(0.0 - 1.0)")?).is_nan());
+        Ok(())
+    }
+
+    #[test]
+    fn recursion_depth_limit() {
+        // Debug build frames are big, so give the walker the same
+        // stack that main does
+        let test = || {
+            let source = "fn forever(x: int) -> int { forever(x + 1) }\nlet y: int = forever(0);";
+            let err = run_program(source, 50).unwrap_err();
+            assert_eq!(err.short_name, "StackOverflow");
+
+            let source = "fn count(x: int) -> int { if x == 0 { 0 } else { count(x - 1) } }\n\
+                          let y: int = count(50);";
+            assert!(run_program(source, 50).is_err());
+            assert!(run_program(source, 51).is_ok());
+        };
+        thread::Builder::new()
+            .stack_size(crate::STACK_SIZE)
+            .spawn(test)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn field_assignment() -> Result<(), IError> {
+        let source = "struct Point { x: int, y: int }\n\
+                      let p: Point = Point { x: 1, y: 2 };\n\
+                      p.y = 5;\n\
+                      let x: int = p.x;\n\
+                      let y: int = p.y;";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res?;
+        assert_eq!(global(&treewalker, "x"), 1);
+        assert_eq!(global(&treewalker, "y"), 5);
+
+        // Inside a function body and a loop body
+        let source = "struct P { x: int }\n\
+                      fn bump(p: P) -> int { p.x = 5; p.x }\n\
+                      let p: P = P { x: 1 };\n\
+                      let a: int = bump(p);\n\
+                      let i: int = 0;\n\
+                      while i < 3 { p.x = p.x + i; i = i + 1; }\n\
+                      let b: int = p.x;";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res?;
+        assert_eq!(global(&treewalker, "a"), 5);
+        assert_eq!(global(&treewalker, "b"), 8);
+        Ok(())
+    }
+
+    #[test]
+    fn nested_tuple_field() -> Result<(), IError> {
+        assert_eq!(eval("((1, 2), 3).0.1")?, 2);
+        assert_eq!(eval("((1, 2), 3).1")?, 3);
+        assert_eq!(eval("(1, (2, (3, 4))).1.1.0")?, 3);
+        Ok(())
+    }
+
+    fn eval_formatted(source: &str) -> Result<String, IError> {
+        let (expr_t, mut treewalker) = check_expr(source);
+        let value = treewalker.interpret_expr(&expr_t)?;
+        format_value(
+            &treewalker.memory,
+            &treewalker.name_table,
+            &treewalker.type_table,
+            value,
+            expr_t.inner.get_type(),
+        )
+    }
+
+    #[test]
+    fn format_values() -> Result<(), IError> {
+        assert_eq!(eval_formatted("(1, (2, 3))")?, "(1, (2, 3))");
+        assert_eq!(
+            eval_formatted("(\"a\", (true, 2.5), [1, 2])")?,
+            "(a, (true, 2.5), [1, 2])"
+        );
+        assert_eq!(eval_formatted("[[1], [2, 3]]")?, "[[1], [2, 3]]");
+        assert_eq!(eval_formatted("0 - 3")?, "-3");
+        Ok(())
+    }
+
+    #[test]
+    fn format_record() -> Result<(), IError> {
+        let source = "struct P { x: int, y: int }\nlet p: P = P { x: 1, y: 2 };";
+        let (program_t, mut treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, DefaultIO::new());
+        let (_, type_id) = program_t.named_types[0];
+        treewalker.interpret_program(program_t)?;
+        let formatted = format_value(
+            &treewalker.memory,
+            &treewalker.name_table,
+            &treewalker.type_table,
+            global(&treewalker, "p"),
+            type_id,
+        )?;
+        assert_eq!(formatted, "P { x: 1, y: 2 }");
+
+        // Nested records get their names too, however they were built
+        let source = "struct A { b: B }\nstruct B { x: int }\nfn make() -> B { B { x: 1 } }\nprint(A { b: make() });";
+        let (program_t, mut treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "A { b: B { x: 1 } }\n");
+        Ok(())
+    }
+
+    #[test]
+    fn step_count() -> Result<(), IError> {
+        let (expr_t, treewalker) = check_expr("1 + 2");
+        assert_eq!(treewalker.step_count(), None);
+        let mut treewalker = treewalker.with_step_count();
+        treewalker.interpret_expr(&expr_t)?;
+        // The addition and its two operands
+        assert_eq!(treewalker.step_count(), Some(3));
+
+        // The block, the let and its value, then the addition and
+        // its two operands
+        let (expr_t, treewalker) = check_expr("{ let x: int = 1; x + 2 }");
+        let mut treewalker = treewalker.with_step_count();
+        treewalker.interpret_expr(&expr_t)?;
+        assert_eq!(treewalker.step_count(), Some(6));
+        Ok(())
+    }
+
+    #[test]
+    fn bitwise_ops() -> Result<(), IError> {
+        assert_eq!(eval("6 & 3")?, 2);
+        assert_eq!(eval("6 | 3")?, 7);
+        assert_eq!(eval("6 ^ 3")?, 5);
+        assert_eq!(eval("1 << 4")?, 16);
+        assert_eq!(eval("(0 - 16) >> 2")? as i64, -4);
+        assert_eq!(eval("1 | 2 & 3 << 1")?, 3);
+        assert_eq!(eval("5 & 1 == 1")?, 1);
+        assert_eq!(eval("6 | 1")?, 7);
+        assert_eq!(eval("true & false")?, 0);
+        assert_eq!(eval("true | false")?, 1);
+
+        let (expr_t, mut treewalker) = check_expr("1 << 64");
+        let err = treewalker.interpret_expr(&expr_t).unwrap_err();
+        assert_eq!(err.short_name, "InvalidShift");
+        let (expr_t, mut treewalker) = check_expr("1 >> (0 - 1)");
+        let err = treewalker.interpret_expr(&expr_t).unwrap_err();
+        assert_eq!(err.short_name, "InvalidShift");
+        Ok(())
+    }
+
+    #[test]
+    fn binop_operand_types() -> Result<(), IError> {
+        fn set_lhs_type(expr_t: &mut Loc<ExprT>, lhs_type: TypeId) {
+            match &mut expr_t.inner {
+                ExprT::BinOp { lhs, .. } => match &mut lhs.inner {
+                    ExprT::Primary { type_, .. } => *type_ = lhs_type,
+                    expr => panic!("expected primary, got {:?}", expr),
+                },
+                expr => panic!("expected binop, got {:?}", expr),
+            }
+        }
+
+        // Any doesn't say how to read the value, so it's an error
+        let (mut expr_t, mut treewalker) = check_expr("1 < 2");
+        set_lhs_type(&mut expr_t, ANY_INDEX);
+        let err = treewalker.interpret_expr(&expr_t).unwrap_err();
+        assert_eq!(err.short_name, "InternalError");
+
+        // But a type variable solved to int is just an int
+        let (mut expr_t, mut treewalker) = check_expr("1 < 2");
+        let solved = treewalker.type_table.insert(Type::Solved(INT_INDEX));
+        set_lhs_type(&mut expr_t, solved);
+        assert_eq!(treewalker.interpret_expr(&expr_t)?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn bare_block() -> Result<(), IError> {
+        let source = "let x: int = 1;\n{ let x: int = 2; print(x); }\nprint(x);";
+        let (program_t, mut treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "2\n1\n");
+        Ok(())
+    }
+
+    #[test]
+    fn string_concat() -> Result<(), IError> {
+        let source = "let s: string = \"foo\";\nprint(s + \"bar\");\nprint(\"\" + s + \"\");";
+        let (program_t, mut treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "foobar\nfoo\n");
+        Ok(())
+    }
+
+    #[test]
+    fn unicode_strings() -> Result<(), IError> {
+        assert_eq!(eval(r#""\u{41}" == "A""#)?, 1);
+        assert_eq!(eval_string(r#""caf\u{e9} \u{1F600}""#)?, "café \u{1F600}");
+        assert_eq!(eval(r#"len("\u{e9}")"#)?, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn structural_equality() -> Result<(), IError> {
+        assert_eq!(eval("\"ab\" == \"a\" + \"b\"")?, 1);
+        assert_eq!(eval("\"ab\" != \"ab\"")?, 0);
+        assert_eq!(eval("\"ab\" == \"abc\"")?, 0);
+        assert_eq!(eval("(1, \"x\", 2.0) == (1, \"x\", 2.0)")?, 1);
+        assert_eq!(eval("(1, \"x\") == (1, \"y\")")?, 0);
+        assert_eq!(eval("[(1, 2), (3, 4)] == [(1, 2), (3, 4)]")?, 1);
+        assert_eq!(eval("[1, 2, 3] != [1, 2, 4]")?, 1);
+        assert_eq!(eval("[1, 2] == [1, 2, 3]")?, 0);
+        assert_eq!(eval("0.0 == -0.0")?, 1);
+
+        let source = "struct Point { x: int, name: string }
+let a: Point = Point { x: 1, name: \"a\" };
+let b: Point = Point { x: 1, name: \"a\" };
+let c: Point = Point { x: 1, name: \"c\" };
+print(a == b);
+print(a != c);
+print(a == c);";
+        let (program_t, mut treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "true\ntrue\nfalse\n");
+        Ok(())
+    }
+
+    #[test]
+    fn optionals() -> Result<(), IError> {
+        assert_eq!(eval("unwrap(some(41)) + 1")?, 42);
+        assert_eq!(eval("some(1) == some(1)")?, 1);
+        assert_eq!(eval("some(1) == none")?, 0);
+        assert_eq!(eval_string("to_string(unwrap(some(3)))")?, "3");
+        let err = eval("unwrap(none)").unwrap_err();
+        assert_eq!(err.short_name, "UnwrapNone");
+
+        let (program_t, mut treewalker) = check_program(
+            "let x: int? = some(2);\nprint(x);\nlet y: (int, bool)? = none;\nprint(y);",
+            DEFAULT_MAX_CALL_DEPTH,
+            InMemoryIO::new(),
+        );
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "some(2)\nnone\n");
+        Ok(())
+    }
+
+    #[test]
+    fn loop_jumps() -> Result<(), IError> {
+        // break leaves the loop before the condition would
+        let (treewalker, res) = run_program_with_walker(
+            "let i: int = 0;\nwhile i < 10 {\n  if i == 3 { break; };\n  i += 1;\n}",
+            DEFAULT_MAX_CALL_DEPTH,
+        );
+        res?;
+        assert_eq!(global(&treewalker, "i"), 3);
+
+        // continue skips adding 2 but keeps looping
+        let (treewalker, res) = run_program_with_walker(
+            "let i: int = 0;\nlet total: int = 0;\nwhile i < 4 {\n  i += 1;\n  if i == 2 { continue; };\n  total += i;\n}",
+            DEFAULT_MAX_CALL_DEPTH,
+        );
+        res?;
+        assert_eq!(global(&treewalker, "total"), 1 + 3 + 4);
+
+        // Returns from inside nested blocks leave the whole function
+        let source = "fn f(x: int) -> int {\n  while true {\n    if x > 0 { return 1; };\n    return 2;\n  }\n  3\n}\nlet a: int = f(5);\nlet b: int = f(0);";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res?;
+        assert_eq!(global(&treewalker, "a"), 1);
+        assert_eq!(global(&treewalker, "b"), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn chars() -> Result<(), IError> {
+        let (program_t, mut treewalker) = check_program(
+            "let c: char = 'z';\nprint(c);\nprint('\\n' == 'n');",
+            DEFAULT_MAX_CALL_DEPTH,
+            InMemoryIO::new(),
+        );
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "z\nfalse\n");
+        assert_eq!(eval("'a' == 'a'")?, 1);
+        assert_eq!(eval("'a' != 'b'")?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn casts() -> Result<(), IError> {
+        assert_eq!(eval("3 as float")?, 3.0f64.to_bits());
+        assert_eq!(eval("3.9 as int")?, 3);
+        assert_eq!(eval("-3.9 as int")? as i64, -3);
+        assert_eq!(eval("1 + 2 as float as int")?, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn short_circuit() -> Result<(), IError> {
+        assert_eq!(eval("true && 1 < 2")?, 1);
+        assert_eq!(eval("false || 2 < 1")?, 0);
+        assert_eq!(eval("1 > 2 || true && true")?, 1);
+        // boom would fail the program if it ever ran
+        let source = "fn boom() -> bool { require(false, \"rhs ran\"); true }
+let a: bool = false && boom();
+let b: bool = true || boom();";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res?;
+        assert_eq!(global(&treewalker, "a"), 0);
+        assert_eq!(global(&treewalker, "b"), 1);
+        let source = "fn boom() -> bool { require(false, \"rhs ran\"); true }
+let c: bool = true && boom();";
+        let err = run_program(source, DEFAULT_MAX_CALL_DEPTH).unwrap_err();
+        assert_eq!(err.short_name, "RequireFailed");
+        Ok(())
+    }
+
+    #[test]
+    fn captured_print() -> Result<(), IError> {
+        let (program_t, mut treewalker) =
+            check_program("print(42);", DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "42\n");
+        assert_eq!(treewalker.io().err.to_string(), "");
+        Ok(())
+    }
+
+    #[test]
+    fn read_lines() -> Result<(), IError> {
+        let source = "let name: string = read_line();\nprint(name);\nprint(len(read_line()));";
+        let (program_t, mut treewalker) = check_program(
+            source,
+            DEFAULT_MAX_CALL_DEPTH,
+            InMemoryIO::with_input("hello\n"),
+        );
+        treewalker.interpret_program(program_t)?;
+        // Once the input runs out we get empty strings
+        assert_eq!(treewalker.io().out.to_string(), "hello\n0\n");
+        Ok(())
+    }
+
+    #[test]
+    fn trace() -> Result<(), IError> {
+        let (program_t, treewalker) = check_program(
+            "let x: int = 1;\nprint(x);",
+            DEFAULT_MAX_CALL_DEPTH,
+            InMemoryIO::new(),
+        );
+        let mut treewalker = treewalker.with_trace(true);
+        treewalker.interpret_program(program_t)?;
+        let log = treewalker.io().log.to_string();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("[trace] (0---"), "{}", lines[0]);
+        assert!(lines[0].ends_with(" def"));
+        assert!(lines[1].ends_with(" expr"));
+        assert!(lines[2].ends_with(" call print"));
+        assert_eq!(treewalker.io().out.to_string(), "1\n");
+
+        let source = "let x: int = 1;\nprint(x);";
+        let (program_t, treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        let mut treewalker = treewalker
+            .with_trace(true)
+            .with_source_file("main.brg", source);
+        treewalker.interpret_program(program_t)?;
+        let log = treewalker.io().log.to_string();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines[0], "[trace] main.brg:1:1 def");
+        assert_eq!(lines[1], "[trace] main.brg:2:1 expr");
+        Ok(())
+    }
+
+    #[test]
+    fn output_limit() -> Result<(), IError> {
+        let source = "print(12345);\nprint(12345);\nprint(12345);";
+        let (program_t, treewalker) =
+            check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        let mut treewalker = treewalker.with_output_limit(10);
+        let err = treewalker.interpret_program(program_t).unwrap_err();
+        assert_eq!(err.short_name, "OutputLimitExceeded");
+        assert_eq!(treewalker.io().out.to_string(), "12345\n1234");
+
+        // Landing exactly on the limit is fine
+        let (program_t, treewalker) =
+            check_program("print(1234);", DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+        let mut treewalker = treewalker.with_output_limit(5);
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(treewalker.io().out.to_string(), "1234\n");
+        Ok(())
+    }
+
+    #[test]
+    fn flush_policy() -> Result<(), IError> {
+        let source = "let x: int = 1;
+print(x);
+print(\"a\");
+fn f(y: float) -> float { print(y); y * 2.0 }
+print(f(1.5));";
+        for policy in [FlushPolicy::EachPrint, FlushPolicy::Batch].iter() {
+            let (program_t, treewalker) =
+                check_program(source, DEFAULT_MAX_CALL_DEPTH, InMemoryIO::new());
+            let mut treewalker = treewalker.with_flush_policy(*policy);
+            treewalker.interpret_program(program_t)?;
+            assert_eq!(treewalker.io().out.to_string(), "1\na\n1.5\n3\n");
+            // Batch only flushes once the program is done
+            let flushes = match policy {
+                FlushPolicy::EachPrint => 5,
+                FlushPolicy::Batch => 1,
+            };
+            assert_eq!(treewalker.io().out.flush_count(), flushes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn native_functions() -> Result<(), IError> {
+        let source = "let x: int = double(20) + 2;\nlet y: int = double(x);";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().expect("failed to parse");
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        typechecker.register_native("double", vec![INT_INDEX], INT_INDEX);
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        let (_, name_table, type_table) = typechecker.get_tables();
+        let mut treewalker = TreeWalker::new(HashMap::new(), name_table, type_table);
+        treewalker.register_native(
+            "double",
+            Box::new(|args| match args {
+                [Value::Integer(i)] => Ok(Value::Integer(i * 2)),
+                _ => Err(IError::new("InvalidArgs", format!("{:?}", args))),
+            }),
+        );
+        treewalker.interpret_program(program_t)?;
+        assert_eq!(global(&treewalker, "x"), 42);
+        assert_eq!(global(&treewalker, "y"), 84);
+        Ok(())
+    }
+
+    #[test]
+    fn require() -> Result<(), IError> {
+        let source = |x: i64| {
+            format!(
+                "let x: int = {};\nrequire(x > 0, \"x must be positive\");",
+                x
+            )
+        };
+        run_program(&source(3), DEFAULT_MAX_CALL_DEPTH)?;
+        let err = run_program(&source(-3), DEFAULT_MAX_CALL_DEPTH).unwrap_err();
+        assert_eq!(err.short_name, "RequireFailed");
+        assert_eq!(err.message, "x must be positive");
+        let location = err.location.unwrap();
+        assert_eq!((location.0).0, source(-3).find("x > 0").unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn assert() -> Result<(), IError> {
+        run_program("assert(1 < 2);", DEFAULT_MAX_CALL_DEPTH)?;
+        run_program("assert(true, \"unused\");", DEFAULT_MAX_CALL_DEPTH)?;
+
+        let err =
+            run_program("let x: int = 3;\nassert(x > 5);", DEFAULT_MAX_CALL_DEPTH).unwrap_err();
+        assert_eq!(err.short_name, "AssertionFailed");
+        assert_eq!(
+            err.location,
+            Some(LocationRange(Location(23), Location(28)))
+        );
+
+        let source = "let x: int = 3;\nassert(x > 5, \"x is too small\");";
+        let err = run_program(source, DEFAULT_MAX_CALL_DEPTH).unwrap_err();
+        assert_eq!(err.short_name, "AssertionFailed");
+        assert_eq!(err.message, "x is too small");
+        Ok(())
+    }
+
+    #[test]
+    fn checked_arithmetic() -> Result<(), IError> {
+        let source = "9223372036854775807 + 1";
+        assert_eq!(eval(source)? as i64, i64::MIN);
+        assert_eq!(eval("0 - 9223372036854775807 - 2")? as i64, i64::MAX);
+
+        let (expr_t, treewalker) = check_expr(source);
+        let mut treewalker = treewalker.with_checked_arithmetic(true);
+        let err = treewalker.interpret_expr(&expr_t).unwrap_err();
+        assert_eq!(err.short_name, "IntegerOverflow");
+        assert_eq!(err.message, "9223372036854775807 + 1 overflows");
+        assert_eq!(err.location, Some(expr_t.location));
+
+        let (expr_t, treewalker) = check_expr("4611686018427387904 * 2");
+        let mut treewalker = treewalker.with_checked_arithmetic(true);
+        assert!(treewalker.interpret_expr(&expr_t).is_err());
+
+        let (expr_t, treewalker) = check_expr("9223372036854775806 + 1");
+        let mut treewalker = treewalker.with_checked_arithmetic(true);
+        assert_eq!(treewalker.interpret_expr(&expr_t)? as i64, i64::MAX);
+
+        // i64::MIN has no positive counterpart
+        let min = "(0 - 9223372036854775807 - 1)";
+        assert_eq!(eval(&format!("-{}", min))? as i64, i64::MIN);
+        assert_eq!(eval("-9223372036854775807")? as i64, -i64::MAX);
+        let (expr_t, treewalker) = check_expr(&format!("-{}", min));
+        let mut treewalker = treewalker.with_checked_arithmetic(true);
+        let err = treewalker.interpret_expr(&expr_t).unwrap_err();
+        assert_eq!(err.short_name, "IntegerOverflow");
+        assert_eq!(err.location, Some(expr_t.location));
+        assert_eq!(f64::from_bits(eval("-2.5")?), -2.5);
+        Ok(())
+    }
+
+    #[test]
+    fn chained_assignment() -> Result<(), IError> {
+        let source = "let a: int = 1;\n\
+                      let b: int = 2;\n\
+                      a = b = 5;";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res?;
+        assert_eq!(global(&treewalker, "a"), 5);
+        assert_eq!(global(&treewalker, "b"), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn overloaded_builtins() -> Result<(), IError> {
+        assert_eq!(eval("abs(0 - 3)")? as i64, 3);
+        assert_eq!(f64::from_bits(eval("abs(0.0 - 1.5)")?), 1.5);
+        assert_eq!(eval("min(2, 7)")? as i64, 2);
+        assert_eq!(f64::from_bits(eval("max(2.5, 7.0)")?), 7.0);
+        assert_eq!(eval_string("to_string(42)")?, "42");
+        assert_eq!(eval_string("to_string(2.5)")?, "2.5");
+        assert_eq!(eval_string("to_string(1 < 2)")?, "true");
+        Ok(())
+    }
+
+    #[test]
+    fn many_calls() {
+        // About 16k calls, which used to clone every function each time
+        let source =
+            "fn calls(n: int) -> int { if n == 0 { 1 } else { calls(n - 1) + calls(n - 1) } }\n\
+                      let total: int = calls(14);";
+        let (treewalker, res) = run_program_with_walker(source, DEFAULT_MAX_CALL_DEPTH);
+        res.unwrap();
+        assert_eq!(global(&treewalker, "total"), 1 << 14);
+    }
+
+    #[test]
+    fn len() -> Result<(), IError> {
+        assert_eq!(eval("len(\"hello\")")?, 5);
+        assert_eq!(eval("len(\"\")")?, 0);
+        assert_eq!(eval("len([1, 2, 3])")?, 3);
+        assert_eq!(eval("len([])")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn eval_program() -> Result<(), IError> {
+        let eval_source = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().expect("failed to parse");
+            assert!(program.errors.is_empty(), "{:?}", program.errors);
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            let program_t = typechecker.check_program(program);
+            let functions = typechecker.take_functions();
+            let (_, name_table, type_table) = typechecker.get_tables();
+            TreeWalker::new(functions, name_table, type_table).eval_program(program_t)
+        };
+        assert_eq!(eval_source("1 + 2")?, Value::Integer(3));
+        assert_eq!(
+            eval_source("let x: float = 1.5;\n(x, \"a\");")?,
+            Value::Tuple(vec![Value::Float(1.5), Value::String("a".to_string())])
+        );
+        assert_eq!(eval_source("let x: int = 1;")?, Value::Empty);
+        // Variables may share a builtin's name
+        assert_eq!(
+            eval_source("let len: int = 2;\nlen(\"abc\") + len;")?,
+            Value::Integer(5)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn float_equality() -> Result<(), IError> {
+        assert_eq!(eval("0.0 * (0.0 - 1.0) == 0.0")?, 1);
+        assert_eq!(eval("0.0 * (0.0 - 1.0) != 0.0")?, 0);
+        assert_eq!(eval("0.0 / 0.0 == 0.0 / 0.0")?, 0);
+        assert_eq!(eval("0.0 / 0.0 != 0.0 / 0.0")?, 1);
+        assert_eq!(eval("3 != 3")?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn malformed_ast_is_internal_error() {
+        let location = LocationRange(Location(0), Location(3));
+        let mut treewalker = TreeWalker::new(HashMap::new(), NameTable::new(), TypeTable::new());
+        let var = Loc {
+            location,
+            inner: ExprT::Var {
+                name: 1000,
+                type_: INT_INDEX,
+            },
+        };
+        let err = treewalker.interpret_expr(&var).unwrap_err();
+        assert_eq!(err.short_name, "InternalError");
+        assert!(err.message.contains("<name 1000>"), "{}", err.message);
+
+        let call = Loc {
+            location,
+            inner: ExprT::Call {
+                callee: NameTable::new().insert("foo".to_string()),
+                args: Vec::new(),
+                type_: INT_INDEX,
+            },
+        };
+        let err = treewalker.interpret_expr(&call).unwrap_err();
+        assert_eq!(err.short_name, "InternalError");
+    }
+
+    #[test]
+    fn type_name() -> Result<(), IError> {
+        assert_eq!(eval_string("type_name(3.0)")?, "float");
+        assert_eq!(eval_string("type_name(1 + 2)")?, "int");
+        assert_eq!(eval_string("type_name((1, \"a\"))")?, "(int, string)");
+        Ok(())
+    }
+
+    #[test]
+    fn oversized_tuple() -> Result<(), IError> {
+        let location = LocationRange(Location(0), Location(0));
+        let (mut treewalker, res) = eval_with_walker("(1, 2)");
+        let ptr = res?;
+        assert_eq!(treewalker.memory.get_var_len(ptr.into())?, 16);
+
+        // Two words fit in 16 bytes but three don't
+        assert!(treewalker.alloc_words(vec![1, 2], location, 16).is_ok());
+        let err = treewalker
+            .alloc_words(vec![1, 2, 3], location, 16)
+            .unwrap_err();
+        assert_eq!(err.short_name, "AllocationTooLarge");
+        Ok(())
+    }
+
+    #[test]
+    fn field_bounds_check() -> Result<(), IError> {
+        let location = LocationRange(Location(0), Location(0));
+        let (mut treewalker, res) = eval_with_walker("(1, 2)");
+        let ptr: VarPointer = res?.into();
+        assert_eq!(treewalker.memory.get_var_meta(ptr)?, 2);
+        let (string_walker, res) = eval_with_walker("\"abc\"");
+        assert_eq!(string_walker.memory.get_var_meta(res?.into())?, 3);
+
+        // Room for three words but only two elements, so the third
+        // word is off limits even though it's inside the var
+        let ptr = treewalker.memory.add_heap_var_with_meta(24, 2, location);
+        assert!(treewalker.field_ptr(ptr, 1, location).is_ok());
+        let err = treewalker.field_ptr(ptr, 2, location).unwrap_err();
+        assert_eq!(err.short_name, "IndexOutOfBounds");
+        Ok(())
+    }
+
+    #[test]
+    fn allocation_overflow() {
+        // Enough words to wrap a u32 byte count around to 8
+        let count = (u32::MAX as usize + 1) / 8 + 1;
+        let err = allocation_size(count, 8, u32::MAX).unwrap_err();
+        assert_eq!(err.short_name, "AllocationTooLarge");
+        assert!(allocation_size(usize::MAX, 1, u32::MAX).is_err());
+        assert_eq!(allocation_size(3, 8, u32::MAX).unwrap(), 24);
+        assert_eq!(
+            allocation_size(u32::MAX as usize, 1, u32::MAX).unwrap(),
+            u32::MAX
+        );
+    }
+
+    #[test]
+    fn seeded_rand() -> Result<(), IError> {
+        // Evaluates the expr a few times on a fresh walker with the seed
+        let sample = |source: &str, seed: u64| -> Result<Vec<u64>, IError> {
+            let (expr_t, mut treewalker) = check_expr(source);
+            treewalker.set_seed(seed);
+            (0..20)
+                .map(|_| treewalker.interpret_expr(&expr_t))
+                .collect()
+        };
+
+        let ints = sample("rand_int(0 - 5, 5)", 42)?;
+        assert_eq!(ints, sample("rand_int(0 - 5, 5)", 42)?);
+        assert_ne!(ints, sample("rand_int(0 - 5, 5)", 7)?);
+        assert!(ints.iter().all(|i| (-5..5).contains(&(*i as i64))));
+
+        let floats = sample("rand()", 42)?;
+        assert_eq!(floats, sample("rand()", 42)?);
+        assert!(floats
+            .iter()
+            .all(|f| (0.0..1.0).contains(&f64::from_bits(*f))));
+
+        let err = sample("rand_int(3, 3)", 42).unwrap_err();
+        assert_eq!(err.short_name, "InvalidRange");
+        Ok(())
+    }
+}
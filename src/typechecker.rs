@@ -2,15 +2,20 @@ use crate::ast::{
     Expr, ExprT, Function, Loc, Name, Op, Program, ProgramT, Stmt, StmtT, Type, TypeDef, TypeId,
     TypeSig, UnaryOp, Value,
 };
+use crate::desugar;
 use crate::lexer::LocationRange;
 use crate::printer::type_to_string;
 use crate::symbol_table::SymbolTable;
 use crate::utils::{
-    NameTable, TypeTable, ANY_INDEX, BOOL_INDEX, CHAR_INDEX, FLOAT_INDEX, INT_INDEX, PRINT_INDEX,
-    STR_INDEX, UNIT_INDEX,
+    is_builtin, NameTable, TypeTable, ABS_INDEX, ANY_INDEX, ASSERT_INDEX, BOOL_INDEX, CEIL_INDEX,
+    CHAR_INDEX, COS_INDEX, FLOAT_INDEX, FLOOR_INDEX, INT_INDEX, LEN_INDEX, MAX_INDEX, MIN_INDEX,
+    PRINT_INDEX, RAND_INDEX, RAND_INT_INDEX, READ_LINE_INDEX, REQUIRE_INDEX, SIN_INDEX, SOME_INDEX,
+    SQRT_INDEX, STR_INDEX, TO_STRING_INDEX, TYPE_NAME_INDEX, UNIT_INDEX, UNWRAP_INDEX,
 };
+use codespan_reporting::diagnostic::Severity;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
 
 #[derive(Debug, Fail, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TypeError {
@@ -26,8 +31,8 @@ pub enum TypeError {
     OpFailure {
         location: LocationRange,
         op: Op,
-        lhs_type: Type,
-        rhs_type: Type,
+        lhs_type: String,
+        rhs_type: String,
     },
     #[fail(display = "Could not unify {} with {}", type1, type2)]
     UnificationFailure {
@@ -40,6 +45,14 @@ pub enum TypeError {
         location: LocationRange,
         type_name: String,
     },
+    #[fail(
+        display = "{}: Struct {} contains itself, so it would be infinitely large. Try putting it in an array",
+        location, name
+    )]
+    InfiniteType {
+        location: LocationRange,
+        name: String,
+    },
     #[fail(display = "Field {} does not exist in record", name)]
     FieldDoesNotExist {
         location: LocationRange,
@@ -67,6 +80,13 @@ pub enum TypeError {
     },
     #[fail(display = "{}: Cannot return at top level", location)]
     TopLevelReturn { location: LocationRange },
+    #[fail(display = "{}: Can only export at top level", location)]
+    NonTopLevelExport { location: LocationRange },
+    #[fail(
+        display = "{}: break and continue can only be used inside a loop",
+        location
+    )]
+    BreakOutsideLoop { location: LocationRange },
     #[fail(
         display = "{}: Function appears to be shadowed by var of same name",
         location
@@ -74,10 +94,59 @@ pub enum TypeError {
     ShadowingFunction { location: LocationRange },
     #[fail(display = "Functions are not values")]
     FuncValues { location: LocationRange },
-    #[fail(display = "Tuple index is out of bounds for tuple {}", tuple)]
-    TupleOutOfBounds {
+    #[fail(
+        display = "Tuple index {} is out of bounds for a tuple of length {}",
+        index, len
+    )]
+    TupleIndexOutOfBounds {
+        location: LocationRange,
+        index: usize,
+        len: usize,
+    },
+    #[fail(
+        display = "Call to '{}' is ambiguous. Could be any of: {}",
+        name, candidates
+    )]
+    AmbiguousCall {
+        location: LocationRange,
+        name: String,
+        candidates: String,
+    },
+    #[fail(display = "Value of type {} is not used", type_name)]
+    UnusedValue {
+        location: LocationRange,
+        type_name: String,
+    },
+    #[fail(
+        display = "{}: '{}' is a variable of type {}, not a function",
+        location, name, type_name
+    )]
+    NotCallable {
+        location: LocationRange,
+        name: String,
+        type_name: String,
+    },
+    #[fail(display = "{}: Cannot cast {} to {}", location, from, to)]
+    InvalidCast {
+        location: LocationRange,
+        from: String,
+        to: String,
+    },
+    #[fail(
+        display = "{}: Return type of '{}' isn't known at this point, annotate the return type",
+        location, name
+    )]
+    ReturnTypeNotInferred {
+        location: LocationRange,
+        name: String,
+    },
+    #[fail(
+        display = "{}: Function '{}' has the same name as a builtin",
+        location, name
+    )]
+    ShadowingBuiltin {
         location: LocationRange,
-        tuple: String,
+        name: String,
     },
 }
 
@@ -100,15 +169,52 @@ impl TypeError {
                 location,
                 type_name: _,
             } => *location,
+            TypeError::InfiniteType { location, name: _ } => *location,
             TypeError::FieldDoesNotExist { location, name: _ } => *location,
             TypeError::NotARecord { location, type_: _ } => *location,
             TypeError::NotATuple { location, type_: _ } => *location,
             TypeError::FunctionNotDefined { location, name: _ } => *location,
             TypeError::InvalidUnaryExpr { location, expr: _ } => *location,
             TypeError::TopLevelReturn { location } => *location,
+            TypeError::NonTopLevelExport { location } => *location,
+            TypeError::BreakOutsideLoop { location } => *location,
             TypeError::ShadowingFunction { location } => *location,
             TypeError::FuncValues { location } => *location,
-            TypeError::TupleOutOfBounds { location, tuple: _ } => *location,
+            TypeError::TupleIndexOutOfBounds {
+                location,
+                index: _,
+                len: _,
+            } => *location,
+            TypeError::AmbiguousCall {
+                location,
+                name: _,
+                candidates: _,
+            } => *location,
+            TypeError::UnusedValue {
+                location,
+                type_name: _,
+            } => *location,
+            TypeError::NotCallable {
+                location,
+                name: _,
+                type_name: _,
+            } => *location,
+            TypeError::InvalidCast {
+                location,
+                from: _,
+                to: _,
+            } => *location,
+            TypeError::ReturnTypeNotInferred { location, name: _ } => *location,
+            TypeError::ShadowingBuiltin { location, name: _ } => *location,
+        }
+    }
+
+    // Warnings are reported alongside errors but don't stop
+    // the statement from being checked or run
+    pub fn severity(&self) -> Severity {
+        match self {
+            TypeError::UnusedValue { .. } => Severity::Warning,
+            _ => Severity::Error,
         }
     }
 }
@@ -119,6 +225,9 @@ pub struct FunctionInfo {
     return_type: TypeId,
 }
 
+// A checked block's statements, end expression and type
+type TypedBlock = (Vec<Loc<StmtT>>, Option<Box<Loc<ExprT>>>, TypeId);
+
 pub struct TypeChecker {
     symbol_table: SymbolTable,
     // Type names. Right now just has the primitives like string,
@@ -126,12 +235,148 @@ pub struct TypeChecker {
     type_names: HashMap<Name, TypeId>,
     // The return type for the typing context
     return_type: Option<TypeId>,
+    // How many loops deep we are in the current function
+    loop_depth: usize,
     // Type table
     type_table: TypeTable,
     // Symbol table
     name_table: NameTable,
     function_types: HashMap<Name, FunctionInfo>,
+    // Builtins with more than one signature, picked between
+    // at the call site by resolve_overload
+    overloads: HashMap<Name, Vec<FunctionInfo>>,
     functions: HashMap<Name, Function>,
+    // Functions in the order they're first defined, so
+    // take_functions hands them out in a stable order
+    function_order: Vec<Name>,
+    // Functions without a return type annotation whose bodies
+    // haven't been checked yet, so we don't know what they return
+    uninferred_returns: HashSet<Name>,
+    // Warnings found so far. Added to the program's errors at the end
+    warnings: Vec<TypeError>,
+    // Errors from sub-expressions that we replaced with ExprT::Error
+    // and kept going past
+    errors: Vec<TypeError>,
+}
+
+fn build_overloads() -> HashMap<Name, Vec<FunctionInfo>> {
+    let signature = |params_type: Vec<TypeId>, return_type| FunctionInfo {
+        params_type,
+        return_type,
+    };
+    let mut overloads = HashMap::new();
+    overloads.insert(
+        ABS_INDEX,
+        vec![
+            signature(vec![INT_INDEX], INT_INDEX),
+            signature(vec![FLOAT_INDEX], FLOAT_INDEX),
+        ],
+    );
+    for builtin in &[MIN_INDEX, MAX_INDEX] {
+        overloads.insert(
+            *builtin,
+            vec![
+                signature(vec![INT_INDEX, INT_INDEX], INT_INDEX),
+                signature(vec![FLOAT_INDEX, FLOAT_INDEX], FLOAT_INDEX),
+            ],
+        );
+    }
+    overloads.insert(
+        TO_STRING_INDEX,
+        vec![
+            signature(vec![INT_INDEX], STR_INDEX),
+            signature(vec![FLOAT_INDEX], STR_INDEX),
+            signature(vec![BOOL_INDEX], STR_INDEX),
+        ],
+    );
+    // assert(cond) or assert(cond, "message")
+    overloads.insert(
+        ASSERT_INDEX,
+        vec![
+            signature(vec![BOOL_INDEX], UNIT_INDEX),
+            signature(vec![BOOL_INDEX, STR_INDEX], UNIT_INDEX),
+        ],
+    );
+    overloads
+}
+
+// Whether param type `a` should win over `b` when an argument unifies
+// with both. Only int over float for now
+fn is_preferred(a: TypeId, b: TypeId) -> bool {
+    a == b || (a == INT_INDEX && b == FLOAT_INDEX)
+}
+
+// Finds structs that contain themselves by value, either directly
+// or through other structs. Arrays are indirected so they don't count
+fn find_infinite_types(type_defs: &[Loc<TypeDef>]) -> HashSet<Name> {
+    fn value_refs(sig: &TypeSig, refs: &mut Vec<Name>) {
+        match sig {
+            TypeSig::Name(name) => refs.push(*name),
+            TypeSig::Tuple(entries) => {
+                for entry in entries {
+                    value_refs(&entry.inner, refs);
+                }
+            }
+            // Optionals are on the heap like arrays
+            TypeSig::Array(_) | TypeSig::Optional(_) | TypeSig::Arrow(_, _) | TypeSig::Empty => {}
+        }
+    }
+
+    let mut graph = HashMap::new();
+    for type_def in type_defs {
+        let TypeDef::Struct(name, fields) = &type_def.inner;
+        let mut refs = Vec::new();
+        for (_, type_sig) in fields {
+            value_refs(&type_sig.inner, &mut refs);
+        }
+        graph.insert(*name, refs);
+    }
+
+    let mut infinite_types = HashSet::new();
+    for start in graph.keys() {
+        let mut visited = HashSet::new();
+        let mut stack = graph[start].clone();
+        while let Some(name) = stack.pop() {
+            if name == *start {
+                infinite_types.insert(*start);
+                break;
+            }
+            if visited.insert(name) {
+                if let Some(refs) = graph.get(&name) {
+                    stack.extend(refs);
+                }
+            }
+        }
+    }
+    infinite_types
+}
+
+// Whether every path through the expression hits a return. Loops
+// don't count, since we can't tell if they run
+fn always_returns(expr: &ExprT) -> bool {
+    match expr {
+        ExprT::Block {
+            stmts, end_expr, ..
+        } => {
+            stmts.iter().any(|stmt| stmt_returns(&stmt.inner))
+                || end_expr
+                    .as_ref()
+                    .is_some_and(|end_expr| always_returns(&end_expr.inner))
+        }
+        ExprT::If(_, then_block, Some(else_block), _) => {
+            always_returns(&then_block.inner) && always_returns(&else_block.inner)
+        }
+        _ => false,
+    }
+}
+
+fn stmt_returns(stmt: &StmtT) -> bool {
+    match stmt {
+        StmtT::Return(_) => true,
+        StmtT::Expr(expr) => always_returns(&expr.inner),
+        StmtT::Block(stmts) => stmts.iter().any(|stmt| stmt_returns(&stmt.inner)),
+        _ => false,
+    }
 }
 
 fn build_type_names(name_table: &mut NameTable) -> HashMap<Name, TypeId> {
@@ -162,14 +407,89 @@ impl TypeChecker {
                 return_type: UNIT_INDEX,
             },
         );
+        // len takes a string or an array. The any here is
+        // narrowed down when checking the call
+        function_types.insert(
+            LEN_INDEX,
+            FunctionInfo {
+                params_type: vec![ANY_INDEX],
+                return_type: INT_INDEX,
+            },
+        );
+        // Like len, some and unwrap get their real
+        // types when checking the call
+        function_types.insert(
+            SOME_INDEX,
+            FunctionInfo {
+                params_type: vec![ANY_INDEX],
+                return_type: ANY_INDEX,
+            },
+        );
+        function_types.insert(
+            UNWRAP_INDEX,
+            FunctionInfo {
+                params_type: vec![ANY_INDEX],
+                return_type: ANY_INDEX,
+            },
+        );
+        function_types.insert(
+            TYPE_NAME_INDEX,
+            FunctionInfo {
+                params_type: vec![ANY_INDEX],
+                return_type: STR_INDEX,
+            },
+        );
+        function_types.insert(
+            REQUIRE_INDEX,
+            FunctionInfo {
+                params_type: vec![BOOL_INDEX, STR_INDEX],
+                return_type: UNIT_INDEX,
+            },
+        );
+        function_types.insert(
+            RAND_INDEX,
+            FunctionInfo {
+                params_type: Vec::new(),
+                return_type: FLOAT_INDEX,
+            },
+        );
+        function_types.insert(
+            READ_LINE_INDEX,
+            FunctionInfo {
+                params_type: Vec::new(),
+                return_type: STR_INDEX,
+            },
+        );
+        function_types.insert(
+            RAND_INT_INDEX,
+            FunctionInfo {
+                params_type: vec![INT_INDEX, INT_INDEX],
+                return_type: INT_INDEX,
+            },
+        );
+        for builtin in &[SQRT_INDEX, SIN_INDEX, COS_INDEX, FLOOR_INDEX, CEIL_INDEX] {
+            function_types.insert(
+                *builtin,
+                FunctionInfo {
+                    params_type: vec![FLOAT_INDEX],
+                    return_type: FLOAT_INDEX,
+                },
+            );
+        }
         TypeChecker {
             symbol_table,
             type_names: build_type_names(&mut name_table),
             return_type: None,
+            loop_depth: 0,
             type_table,
             name_table,
             function_types,
+            overloads: build_overloads(),
             functions: HashMap::new(),
+            function_order: Vec::new(),
+            uninferred_returns: HashSet::new(),
+            warnings: Vec::new(),
+            errors: Vec::new(),
         }
     }
 
@@ -182,15 +502,99 @@ impl TypeChecker {
         &self.name_table
     }
 
-    pub fn get_functions(self) -> HashMap<Name, Function> {
-        self.functions
+    pub fn get_type_table(&self) -> &TypeTable {
+        &self.type_table
+    }
+
+    pub fn get_type_table_mut(&mut self) -> &mut TypeTable {
+        &mut self.type_table
+    }
+
+    // Takes the errors from sub-expressions that were recovered from.
+    // If there are any, the expression that was checked is invalid
+    pub fn take_errors(&mut self) -> Vec<TypeError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    // Checks an expression, swapping in ExprT::Error and noting the
+    // error if it fails so the caller can keep going
+    fn expr_or_error(&mut self, expr: Loc<Expr>) -> Loc<ExprT> {
+        let location = expr.location;
+        match self.expr(expr) {
+            Ok(expr_t) => expr_t,
+            Err(err) => {
+                self.errors.push(err);
+                Loc {
+                    location,
+                    inner: ExprT::Error(ANY_INDEX),
+                }
+            }
+        }
+    }
+
+    // Declares a function the host provides, so calls to it check.
+    // The host has to register the implementation with the treewalker
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        params_type: Vec<TypeId>,
+        return_type: TypeId,
+    ) -> Name {
+        let name = self.name_table.insert(name.to_string());
+        self.function_types.insert(
+            name,
+            FunctionInfo {
+                params_type,
+                return_type,
+            },
+        );
+        name
+    }
+
+    // Takes the functions checked so far, leaving their signatures
+    // in place so later code can still call them
+    // The checked functions in the order they were defined
+    pub fn take_functions(&mut self) -> Vec<(Name, Function)> {
+        let order = std::mem::take(&mut self.function_order);
+        let functions = order
+            .into_iter()
+            .filter_map(|name| self.functions.remove(&name).map(|func| (name, func)))
+            .collect();
+        self.functions.clear();
+        functions
+    }
+
+    // Lends the name table out to a new parser. Must be given back
+    // with `restore_name_table` before checking the parsed code
+    pub fn take_name_table(&mut self) -> NameTable {
+        std::mem::replace(&mut self.name_table, NameTable::new())
+    }
+
+    pub fn restore_name_table(&mut self, name_table: NameTable) {
+        self.name_table = name_table;
     }
 
     pub fn check_program(&mut self, program: Program) -> ProgramT {
         let mut named_types = Vec::new();
         let mut errors = Vec::new();
+        let infinite_types = find_infinite_types(&program.type_defs);
+        // Register every struct before resolving any fields, so
+        // structs can refer to ones defined after them
+        let mut type_defs = Vec::new();
         for type_def in program.type_defs {
-            match self.type_def(type_def) {
+            let TypeDef::Struct(name, _) = &type_def.inner;
+            if infinite_types.contains(name) {
+                errors.push(TypeError::InfiniteType {
+                    location: type_def.location,
+                    name: self.name_table.get_str(name).to_string(),
+                });
+                continue;
+            }
+            let type_id = self.declare_struct(*name);
+            type_defs.push((type_def, type_id));
+        }
+        for (type_def, type_id) in type_defs {
+            match self.type_def(type_def, type_id) {
                 Ok(named_type) => {
                     named_types.push(named_type);
                 }
@@ -199,23 +603,49 @@ impl TypeChecker {
                 }
             }
         }
-        if let Err(err) = self.read_functions(&program.stmts) {
+        // Exports only matter at the top level, so unwrap
+        // them here and check the inner statements as usual
+        let mut exports = Vec::new();
+        let stmts: Vec<_> = program
+            .stmts
+            .into_iter()
+            .map(|stmt| match stmt.inner {
+                Stmt::Export(inner) => {
+                    match &inner.inner {
+                        Stmt::Function { name, .. } | Stmt::Def(name, _, _) => exports.push(*name),
+                        _ => {}
+                    }
+                    *inner
+                }
+                _ => stmt,
+            })
+            .collect();
+        if let Err(err) = self.read_functions(&stmts) {
             errors.push(err);
         }
         let mut typed_stmts = Vec::new();
-        for stmt in program.stmts {
-            match self.stmt(stmt) {
+        for stmt in stmts {
+            let res = self.stmt(stmt);
+            // A statement we recovered errors in still can't be run
+            let recovered = self.take_errors();
+            let has_errors = !recovered.is_empty();
+            errors.extend(recovered);
+            match res {
                 Ok(stmt_t) => {
-                    typed_stmts.push(stmt_t);
+                    if !has_errors {
+                        typed_stmts.push(stmt_t);
+                    }
                 }
                 Err(err) => {
                     errors.push(err);
                 }
             }
         }
+        errors.append(&mut self.warnings);
         ProgramT {
             stmts: typed_stmts,
             named_types,
+            exports,
             errors,
         }
     }
@@ -246,8 +676,25 @@ impl TypeChecker {
                 body: _,
             } = &stmt.inner
             {
+                // Calls would still go to the builtin, so the function
+                // is rejected when its statement gets checked
+                if is_builtin(*name) {
+                    continue;
+                }
+                if !self.function_order.contains(name) {
+                    self.function_order.push(*name);
+                }
                 let params_type = self.func_params(params)?;
-                let return_type = self.lookup_type_sig(return_type)?;
+                // Inferred return types are filled in once the body is
+                // checked. Until then calls are rejected, since bodies
+                // can use earlier globals and have to be checked in order
+                let return_type = match return_type {
+                    Some(return_type) => self.lookup_type_sig(return_type)?,
+                    None => {
+                        self.uninferred_returns.insert(*name);
+                        ANY_INDEX
+                    }
+                };
                 self.function_types.insert(
                     *name,
                     FunctionInfo {
@@ -260,16 +707,33 @@ impl TypeChecker {
         Ok(())
     }
 
-    fn type_def(&mut self, type_def: Loc<TypeDef>) -> Result<(Name, TypeId), TypeError> {
+    // Gives the struct an empty record as a placeholder until
+    // type_def fills in its fields
+    fn declare_struct(&mut self, name: Name) -> TypeId {
+        let type_id = self.type_table.insert(Type::Record(Vec::new()));
+        self.type_names.insert(name, type_id);
+        self.type_table.set_struct_name(type_id, name);
+        type_id
+    }
+
+    fn type_def(
+        &mut self,
+        type_def: Loc<TypeDef>,
+        type_id: TypeId,
+    ) -> Result<(Name, TypeId), TypeError> {
         match type_def.inner {
             TypeDef::Struct(name, fields) => {
                 let mut typed_fields = Vec::new();
-                for (name, type_sig) in fields {
-                    let field_type = self.lookup_type_sig(&type_sig)?;
-                    typed_fields.push((name, field_type));
+                for (field_name, type_sig) in fields {
+                    match self.lookup_type_sig(&type_sig) {
+                        Ok(field_type) => typed_fields.push((field_name, field_type)),
+                        Err(err) => {
+                            self.type_names.remove(&name);
+                            return Err(err);
+                        }
+                    }
                 }
-                let type_id = self.type_table.insert(Type::Record(typed_fields));
-                self.type_names.insert(name, type_id);
+                self.type_table.update(type_id, Type::Record(typed_fields));
                 Ok((name, type_id))
             }
         }
@@ -280,6 +744,16 @@ impl TypeChecker {
         match stmt.inner {
             Stmt::Expr(expr) => {
                 let typed_expr = self.expr(expr)?;
+                let type_ = typed_expr.inner.get_type();
+                // Calls are usually there for their side effects
+                // so we let them throw away their value
+                let is_call = matches!(typed_expr.inner, ExprT::Call { .. });
+                if type_ != UNIT_INDEX && !is_call {
+                    self.warnings.push(TypeError::UnusedValue {
+                        location,
+                        type_name: type_to_string(&self.name_table, &self.type_table, type_),
+                    });
+                }
                 Ok(Loc {
                     location,
                     inner: StmtT::Expr(typed_expr),
@@ -291,17 +765,44 @@ impl TypeChecker {
                 return_type,
                 body,
             } => {
+                if is_builtin(name) {
+                    return Err(TypeError::ShadowingBuiltin {
+                        location,
+                        name: self.name_table.get_str(&name).to_string(),
+                    });
+                }
                 let params = self.func_params(&params)?;
-                let return_type = self.lookup_type_sig(&return_type)?;
+                let return_type = match return_type {
+                    Some(return_type) => Some(self.lookup_type_sig(&return_type)?),
+                    None => None,
+                };
                 self.function(name, params, *body, return_type, location)
             }
             Stmt::Def(name, type_sig, rhs) => Ok(self.def(name, type_sig, rhs, location)?),
             Stmt::Asgn(name, rhs) => Ok(self.asgn(name, rhs, location)?),
+            Stmt::ChainedAsgn(names, rhs) => self.chained_asgn(names, rhs, location),
+            Stmt::OpAsgn(name, op, rhs) => self.stmt(desugar::op_asgn(name, op, rhs, location)),
+            Stmt::FieldAsgn(lhs, name, rhs) => self.field_asgn(lhs, name, rhs, location),
+            Stmt::Export(_) => Err(TypeError::NonTopLevelExport { location }),
+            Stmt::Block(stmts) => {
+                let previous_scope = self.symbol_table.push_scope(false);
+                let typed_stmts: Result<Vec<_>, _> =
+                    stmts.into_iter().map(|stmt| self.stmt(stmt)).collect();
+                self.symbol_table.restore_scope(previous_scope);
+                Ok(Loc {
+                    location,
+                    inner: StmtT::Block(typed_stmts?),
+                })
+            }
             Stmt::Return(expr) => {
                 let typed_expr = self.expr(expr)?;
                 match self.return_type {
                     Some(return_type) => {
-                        if self.is_unifiable(typed_expr.inner.get_type(), return_type) {
+                        // Narrow the return type as we go, so every return
+                        // has to agree with the ones before it
+                        if let Some(unified) = self.unify(return_type, typed_expr.inner.get_type())
+                        {
+                            self.return_type = Some(unified);
                             Ok(Loc {
                                 location,
                                 inner: StmtT::Return(typed_expr),
@@ -326,6 +827,41 @@ impl TypeChecker {
                     }),
                 }
             }
+            Stmt::While(cond, body) => {
+                let typed_cond = self.expr(cond)?;
+                // The cond's type can be a forward to bool, so compare
+                // the id at the end of the forwards
+                if self.type_table.resolve_id(typed_cond.inner.get_type()) != BOOL_INDEX {
+                    let type2 = type_to_string(
+                        &self.name_table,
+                        &self.type_table,
+                        typed_cond.inner.get_type(),
+                    );
+                    return Err(TypeError::UnificationFailure {
+                        location: typed_cond.location,
+                        type1: "bool".to_string(),
+                        type2,
+                    });
+                }
+                self.loop_depth += 1;
+                let typed_body = self.expr(*body);
+                self.loop_depth -= 1;
+                Ok(Loc {
+                    location,
+                    inner: StmtT::While(typed_cond, Box::new(typed_body?)),
+                })
+            }
+            Stmt::Break | Stmt::Continue if self.loop_depth == 0 => {
+                Err(TypeError::BreakOutsideLoop { location })
+            }
+            Stmt::Break => Ok(Loc {
+                location,
+                inner: StmtT::Break,
+            }),
+            Stmt::Continue => Ok(Loc {
+                location,
+                inner: StmtT::Continue,
+            }),
         }
     }
 
@@ -347,6 +883,10 @@ impl TypeChecker {
                 value: Value::String(s),
                 type_: STR_INDEX,
             }),
+            Value::Char(_c) => Some(ExprT::Primary {
+                value,
+                type_: CHAR_INDEX,
+            }),
             Value::Empty => Some(ExprT::Primary {
                 value: Value::Empty,
                 type_: UNIT_INDEX,
@@ -361,6 +901,10 @@ impl TypeChecker {
                 let type_ = self.lookup_type_sig(sig)?;
                 Ok(self.type_table.insert(Type::Array(type_)))
             }
+            TypeSig::Optional(sig) => {
+                let type_ = self.lookup_type_sig(sig)?;
+                Ok(self.type_table.insert(Type::Optional(type_)))
+            }
             TypeSig::Tuple(entries) => {
                 let mut entry_types = Vec::new();
                 for entry in entries {
@@ -377,6 +921,16 @@ impl TypeChecker {
                 })
                 .map(|t| *t),
             TypeSig::Empty => Ok(UNIT_INDEX),
+            TypeSig::Arrow(params, return_type) => {
+                let mut param_types = Vec::new();
+                for param in params {
+                    param_types.push(self.lookup_type_sig(param)?);
+                }
+                let return_type = self.lookup_type_sig(return_type)?;
+                Ok(self
+                    .type_table
+                    .insert(Type::Arrow(param_types, return_type)))
+            }
         }
     }
 
@@ -387,7 +941,9 @@ impl TypeChecker {
         rhs: Loc<Expr>,
         location: LocationRange,
     ) -> Result<Loc<StmtT>, TypeError> {
-        if self.function_types.contains_key(&name) {
+        // Calls look up the callee separately, so a variable can share
+        // a builtin's name without getting in its way
+        if !is_builtin(name) && self.function_types.contains_key(&name) {
             return Err(TypeError::ShadowingFunction { location });
         }
         let typed_rhs = self.expr(rhs)?;
@@ -442,12 +998,76 @@ impl TypeChecker {
         }
     }
 
+    // Every target has to take the rhs's type
+    fn chained_asgn(
+        &mut self,
+        names: Vec<Name>,
+        rhs: Loc<Expr>,
+        location: LocationRange,
+    ) -> Result<Loc<StmtT>, TypeError> {
+        let rhs_t = self.expr(rhs)?;
+        let rhs_type = rhs_t.inner.get_type();
+        for name in names.iter().rev() {
+            let var_type = self
+                .symbol_table
+                .lookup_name(*name)
+                .ok_or(TypeError::VarNotDefined {
+                    location,
+                    name: self.name_table.get_str(name).to_string(),
+                })?
+                .var_type;
+            if self.unify(var_type, rhs_type).is_none() {
+                return Err(TypeError::UnificationFailure {
+                    location,
+                    type1: type_to_string(&self.name_table, &self.type_table, var_type),
+                    type2: type_to_string(&self.name_table, &self.type_table, rhs_type),
+                });
+            }
+        }
+        Ok(Loc {
+            location,
+            inner: StmtT::ChainedAsgn(names, rhs_t),
+        })
+    }
+
+    fn field_asgn(
+        &mut self,
+        lhs: Loc<Expr>,
+        name: Name,
+        rhs: Loc<Expr>,
+        location: LocationRange,
+    ) -> Result<Loc<StmtT>, TypeError> {
+        // Check the target as a field access so we get the
+        // same errors for missing fields and non-records
+        let field = self.expr(Loc {
+            location: LocationRange(lhs.location.0, location.1),
+            inner: Expr::Field(Box::new(lhs), name),
+        })?;
+        let (record_t, pos, field_type) = match field.inner {
+            ExprT::TupleField(record_t, pos, field_type) => (record_t, pos, field_type),
+            _ => unreachable!("field access should check to a tuple field"),
+        };
+        let rhs_t = self.expr(rhs)?;
+        if self.unify(field_type, rhs_t.inner.get_type()).is_some() {
+            Ok(Loc {
+                location,
+                inner: StmtT::TupleFieldAsgn(*record_t, pos, rhs_t),
+            })
+        } else {
+            Err(TypeError::UnificationFailure {
+                location,
+                type1: type_to_string(&self.name_table, &self.type_table, field_type),
+                type2: type_to_string(&self.name_table, &self.type_table, rhs_t.inner.get_type()),
+            })
+        }
+    }
+
     fn function(
         &mut self,
         name: Name,
         params: Vec<Loc<(Name, TypeId)>>,
         body: Loc<Expr>,
-        return_type: TypeId,
+        return_type: Option<TypeId>,
         location: LocationRange,
     ) -> Result<Loc<StmtT>, TypeError> {
         let previous_scope = self.symbol_table.push_scope(true);
@@ -459,16 +1079,44 @@ impl TypeChecker {
         // Save the current return type
         let mut old_return_type = self.return_type;
 
-        self.return_type = Some(return_type);
+        self.return_type = Some(return_type.unwrap_or(ANY_INDEX));
+        // Loops outside the function don't count inside it
+        let old_loop_depth = std::mem::replace(&mut self.loop_depth, 0);
 
         let body_location = body.location;
         // Check body
-        let body = self.expr(body)?;
-        let body_type = body.inner.get_type();
+        let body = self.expr(body);
+        self.uninferred_returns.remove(&name);
         std::mem::swap(&mut old_return_type, &mut self.return_type);
-        // If the body type is unit, we don't try to unify the body type
-        // with return type.
-        if body_type != UNIT_INDEX {
+        self.loop_depth = old_loop_depth;
+        // Restore the outer scope before bailing on errors so
+        // later statements don't end up inside this function
+        let local_variables = self.symbol_table.restore_vars(old_var_types);
+        let scope_index = self.symbol_table.restore_scope(previous_scope);
+        let body = body?;
+        let body_type = body.inner.get_type();
+        if return_type.is_none() {
+            // No annotation, so the returns and the body decide the
+            // return type. A unit body falls back on the returns
+            let returns_type = old_return_type.unwrap();
+            let inferred = if returns_type == ANY_INDEX {
+                Some(body_type)
+            } else if body_type == UNIT_INDEX && always_returns(&body.inner) {
+                Some(returns_type)
+            } else {
+                self.unify(returns_type, body_type)
+            };
+            let inferred = inferred.ok_or_else(|| TypeError::UnificationFailure {
+                location: body_location,
+                type1: type_to_string(&self.name_table, &self.type_table, returns_type),
+                type2: type_to_string(&self.name_table, &self.type_table, body_type),
+            })?;
+            if let Some(info) = self.function_types.get_mut(&name) {
+                info.return_type = inferred;
+            }
+        } else if body_type != UNIT_INDEX || !always_returns(&body.inner) {
+            // A unit body is fine if every path returns, since
+            // then we never fall off the end
             self.unify(old_return_type.unwrap(), body_type)
                 .ok_or_else(|| {
                     let type1 = type_to_string(
@@ -482,18 +1130,14 @@ impl TypeChecker {
                         type1,
                         type2,
                     }
-                })?
-        } else {
-            old_return_type.unwrap()
-        };
+                })?;
+        }
 
-        let local_variables = self.symbol_table.restore_vars(old_var_types);
-        let scope_index = self.symbol_table.restore_scope(previous_scope);
         self.functions.insert(
             name,
             Function {
                 params,
-                body: Box::new(body),
+                body: Rc::new(body),
                 local_variables,
                 scope_index,
             },
@@ -507,6 +1151,14 @@ impl TypeChecker {
     pub fn expr(&mut self, expr: Loc<Expr>) -> Result<Loc<ExprT>, TypeError> {
         let location = expr.location;
         match expr.inner {
+            // none fits any optional, so it starts out as any?
+            Expr::Primary { value: Value::None } => Ok(Loc {
+                location,
+                inner: ExprT::Primary {
+                    value: Value::None,
+                    type_: self.type_table.insert(Type::Optional(ANY_INDEX)),
+                },
+            }),
             Expr::Primary { value } => Ok(Loc {
                 location,
                 inner: self.value(value).unwrap(),
@@ -528,9 +1180,18 @@ impl TypeChecker {
                 })
             }
             Expr::BinOp { op, lhs, rhs } => {
-                let typed_lhs = self.expr(*lhs)?;
-                let typed_rhs = self.expr(*rhs)?;
-                let lhs_type = typed_lhs.inner.get_type();
+                let typed_lhs = self.expr_or_error(*lhs);
+                let typed_rhs = self.expr_or_error(*rhs);
+                let is_error = |expr_t: &Loc<ExprT>| matches!(expr_t.inner, ExprT::Error(_));
+                // The failed side was already reported, so don't
+                // report the op on top of it
+                if is_error(&typed_lhs) || is_error(&typed_rhs) {
+                    return Ok(Loc {
+                        location,
+                        inner: ExprT::Error(ANY_INDEX),
+                    });
+                }
+                let lhs_type = typed_lhs.inner.get_type();
                 let rhs_type = typed_rhs.inner.get_type();
                 match self.op(&op, lhs_type, rhs_type) {
                     Some(op_type) => Ok(Loc {
@@ -543,8 +1204,8 @@ impl TypeChecker {
                         },
                     }),
                     None => {
-                        let lhs_type = self.type_table.get_type(typed_lhs.inner.get_type()).clone();
-                        let rhs_type = self.type_table.get_type(typed_rhs.inner.get_type()).clone();
+                        let lhs_type = type_to_string(&self.name_table, &self.type_table, lhs_type);
+                        let rhs_type = type_to_string(&self.name_table, &self.type_table, rhs_type);
                         Err(TypeError::OpFailure {
                             location,
                             op: op.clone(),
@@ -567,6 +1228,53 @@ impl TypeChecker {
                     inner: ExprT::Tuple(typed_elems, self.type_table.insert(Type::Tuple(types))),
                 })
             }
+            Expr::Array(elems) => {
+                // Every element has to unify with the ones before it.
+                // An empty array can hold anything
+                let mut elem_type = ANY_INDEX;
+                let mut typed_elems = Vec::new();
+                for elem in elems {
+                    let typed_elem = self.expr(elem)?;
+                    let type_ = typed_elem.inner.get_type();
+                    elem_type = self.unify(elem_type, type_).ok_or_else(|| {
+                        TypeError::UnificationFailure {
+                            location: typed_elem.location,
+                            type1: type_to_string(&self.name_table, &self.type_table, elem_type),
+                            type2: type_to_string(&self.name_table, &self.type_table, type_),
+                        }
+                    })?;
+                    typed_elems.push(typed_elem);
+                }
+                Ok(Loc {
+                    location,
+                    inner: ExprT::Array(
+                        typed_elems,
+                        self.type_table.insert(Type::Array(elem_type)),
+                    ),
+                })
+            }
+            Expr::Cast(lhs, type_sig) => {
+                let lhs_t = self.expr(*lhs)?;
+                let from = self.type_table.resolve_id(lhs_t.inner.get_type());
+                let to = self.lookup_type_sig(&type_sig)?;
+                // Only numeric conversions for now, plus casting
+                // something to its own type
+                let is_valid_cast = from == to
+                    || (from == INT_INDEX && to == FLOAT_INDEX)
+                    || (from == FLOAT_INDEX && to == INT_INDEX);
+                if is_valid_cast {
+                    Ok(Loc {
+                        location,
+                        inner: ExprT::Cast(Box::new(lhs_t), to),
+                    })
+                } else {
+                    Err(TypeError::InvalidCast {
+                        location,
+                        from: type_to_string(&self.name_table, &self.type_table, from),
+                        to: type_to_string(&self.name_table, &self.type_table, to),
+                    })
+                }
+            }
             Expr::UnaryOp { op, rhs } => {
                 let typed_rhs = self.expr(*rhs)?;
                 let rhs_type = typed_rhs.inner.get_type();
@@ -601,17 +1309,68 @@ impl TypeChecker {
                     args_type.push(arg_t.inner.get_type());
                     typed_args.push(arg_t);
                 }
-                let (params_type, return_type) = {
-                    let entry =
-                        self.function_types
-                            .get(&callee)
-                            .ok_or(TypeError::FunctionNotDefined {
-                                location,
-                                name: self.name_table.get_str(&callee).to_string(),
-                            })?;
+                let (params_type, return_type) = if self.overloads.contains_key(&callee) {
+                    let entry = self.resolve_overload(callee, &args_type, location)?;
+                    (entry.params_type, entry.return_type)
+                } else if self.uninferred_returns.contains(&callee) {
+                    return Err(TypeError::ReturnTypeNotInferred {
+                        location,
+                        name: self.name_table.get_str(&callee).to_string(),
+                    });
+                } else if let Some(entry) = self.function_types.get(&callee) {
                     (entry.params_type.clone(), entry.return_type)
+                } else {
+                    let name = self.name_table.get_str(&callee).to_string();
+                    // Calling a variable gets its own error, since
+                    // the name is defined, just not as a function
+                    return Err(match self.symbol_table.lookup_name(callee) {
+                        Some(entry) => TypeError::NotCallable {
+                            location,
+                            name,
+                            type_name: type_to_string(
+                                &self.name_table,
+                                &self.type_table,
+                                entry.var_type,
+                            ),
+                        },
+                        None => TypeError::FunctionNotDefined { location, name },
+                    });
                 };
 
+                if callee == LEN_INDEX && !args_type.is_empty() {
+                    let arg_type = args_type[0];
+                    match self.type_table.resolve(arg_type) {
+                        Type::String | Type::Array(_) => {}
+                        _ => {
+                            return Err(TypeError::UnificationFailure {
+                                location,
+                                type1: "string or array".to_string(),
+                                type2: type_to_string(&self.name_table, &self.type_table, arg_type),
+                            })
+                        }
+                    }
+                }
+                // some(x) wraps x's type and unwrap takes it back off
+                let return_type = match (callee, args_type.first()) {
+                    (SOME_INDEX, Some(arg_type)) => {
+                        self.type_table.insert(Type::Optional(*arg_type))
+                    }
+                    (UNWRAP_INDEX, Some(arg_type)) => match self.type_table.resolve(*arg_type) {
+                        Type::Optional(type_) => *type_,
+                        _ => {
+                            return Err(TypeError::UnificationFailure {
+                                location,
+                                type1: "optional".to_string(),
+                                type2: type_to_string(
+                                    &self.name_table,
+                                    &self.type_table,
+                                    *arg_type,
+                                ),
+                            })
+                        }
+                    },
+                    _ => return_type,
+                };
                 if self.unify_type_vectors(&params_type, &args_type).is_some() {
                     Ok(Loc {
                         location,
@@ -640,18 +1399,10 @@ impl TypeChecker {
                 }
             }
             Expr::Block(stmts, end_expr) => {
-                let mut typed_stmts = Vec::new();
                 let previous_scope = self.symbol_table.push_scope(false);
-                for stmt in stmts {
-                    typed_stmts.push(self.stmt(stmt)?);
-                }
-                let (type_, typed_end_expr) = if let Some(expr) = end_expr {
-                    let typed_expr = self.expr(*expr)?;
-                    (typed_expr.inner.get_type(), Some(Box::new(typed_expr)))
-                } else {
-                    (UNIT_INDEX, None)
-                };
+                let block = self.block(stmts, end_expr);
                 let scope_index = self.symbol_table.restore_scope(previous_scope);
+                let (typed_stmts, typed_end_expr, type_) = block?;
                 Ok(Loc {
                     location,
                     inner: ExprT::Block {
@@ -746,13 +1497,17 @@ impl TypeChecker {
                 })?;
                 Ok(Loc {
                     location,
-                    inner: ExprT::Tuple(fields_t, type_),
+                    inner: ExprT::Record {
+                        name,
+                        fields: fields_t,
+                        type_,
+                    },
                 })
             }
             Expr::Field(lhs, name) => {
                 let lhs_t = self.expr(*lhs)?;
                 let type_id = lhs_t.inner.get_type();
-                match self.type_table.get_type(type_id) {
+                match self.type_table.resolve(type_id) {
                     Type::Record(fields) => {
                         let field_pos = fields
                             .iter()
@@ -780,7 +1535,7 @@ impl TypeChecker {
             Expr::TupleField(lhs, index) => {
                 let lhs_t = self.expr(*lhs)?;
                 let type_id = lhs_t.inner.get_type();
-                match self.type_table.get_type(type_id) {
+                match self.type_table.resolve(type_id) {
                     Type::Tuple(entries) => {
                         if index < entries.len() {
                             Ok(Loc {
@@ -788,9 +1543,10 @@ impl TypeChecker {
                                 inner: ExprT::TupleField(Box::new(lhs_t), index, entries[index]),
                             })
                         } else {
-                            Err(TypeError::TupleOutOfBounds {
+                            Err(TypeError::TupleIndexOutOfBounds {
                                 location,
-                                tuple: type_to_string(&self.name_table, &self.type_table, type_id),
+                                index,
+                                len: entries.len(),
                             })
                         }
                     }
@@ -803,8 +1559,29 @@ impl TypeChecker {
         }
     }
 
+    // Checks the contents of a block. Split out so the caller
+    // can restore the scope even if checking fails
+    fn block(
+        &mut self,
+        stmts: Vec<Loc<Stmt>>,
+        end_expr: Option<Box<Loc<Expr>>>,
+    ) -> Result<TypedBlock, TypeError> {
+        let mut typed_stmts = Vec::new();
+        for stmt in stmts {
+            typed_stmts.push(self.stmt(stmt)?);
+        }
+        if let Some(expr) = end_expr {
+            let typed_expr = self.expr(*expr)?;
+            let type_ = typed_expr.inner.get_type();
+            Ok((typed_stmts, Some(Box::new(typed_expr)), type_))
+        } else {
+            Ok((typed_stmts, None, UNIT_INDEX))
+        }
+    }
+
     fn op(&mut self, op: &Op, lhs_type: TypeId, rhs_type: TypeId) -> Option<TypeId> {
         match op {
+            Op::Plus if lhs_type == STR_INDEX && rhs_type == STR_INDEX => Some(STR_INDEX),
             Op::Plus | Op::Minus | Op::Times | Op::Div => {
                 if lhs_type == INT_INDEX && rhs_type == INT_INDEX {
                     Some(INT_INDEX)
@@ -818,6 +1595,24 @@ impl TypeChecker {
                     None
                 }
             }
+            // On bools, & and | are && and || without the short circuit
+            Op::BitAnd | Op::BitOr if lhs_type == BOOL_INDEX && rhs_type == BOOL_INDEX => {
+                Some(BOOL_INDEX)
+            }
+            Op::BitAnd | Op::BitOr | Op::BitXor | Op::Shl | Op::Shr => {
+                if lhs_type == INT_INDEX && rhs_type == INT_INDEX {
+                    Some(INT_INDEX)
+                } else {
+                    None
+                }
+            }
+            Op::And | Op::Or => {
+                if lhs_type == BOOL_INDEX && rhs_type == BOOL_INDEX {
+                    Some(BOOL_INDEX)
+                } else {
+                    None
+                }
+            }
             Op::BangEqual | Op::EqualEqual => {
                 if self.is_unifiable(lhs_type, rhs_type) {
                     Some(BOOL_INDEX)
@@ -839,6 +1634,62 @@ impl TypeChecker {
         }
     }
 
+    // Picks the overload of a builtin to use for these argument types:
+    //   1. An overload whose params are exactly the argument types wins
+    //   2. Otherwise, of the overloads the arguments unify with, pick the
+    //      one whose params are preferred (see is_preferred) over every
+    //      other match's params, i.e. int over float
+    //   3. If no single overload is preferred, the call is ambiguous
+    // If nothing matches, we return the first overload and let the
+    // caller report the unification failure
+    fn resolve_overload(
+        &mut self,
+        callee: Name,
+        args_type: &[TypeId],
+        location: LocationRange,
+    ) -> Result<FunctionInfo, TypeError> {
+        let overloads = self.overloads[&callee].clone();
+        if let Some(exact) = overloads.iter().find(|o| o.params_type == args_type) {
+            return Ok(exact.clone());
+        }
+        let matches: Vec<_> = overloads
+            .iter()
+            .filter(|o| self.unify_type_vectors(&o.params_type, args_type).is_some())
+            .collect();
+        if matches.is_empty() {
+            return Ok(overloads[0].clone());
+        }
+        let best = matches.iter().find(|candidate| {
+            matches.iter().all(|other| {
+                candidate
+                    .params_type
+                    .iter()
+                    .zip(other.params_type.iter())
+                    .all(|(a, b)| is_preferred(*a, *b))
+            })
+        });
+        match best {
+            Some(best) => Ok((*best).clone()),
+            None => Err(TypeError::AmbiguousCall {
+                location,
+                name: self.name_table.get_str(&callee).to_string(),
+                candidates: matches
+                    .iter()
+                    .map(|o| {
+                        let params = o
+                            .params_type
+                            .iter()
+                            .map(|t| type_to_string(&self.name_table, &self.type_table, *t))
+                            .collect::<Vec<String>>()
+                            .join(", ");
+                        format!("({})", params)
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            }),
+        }
+    }
+
     fn unify_type_vectors(
         &mut self,
         type_vector1: &[TypeId],
@@ -862,8 +1713,8 @@ impl TypeChecker {
         if type_id1 == type_id2 {
             return Some(type_id1);
         }
-        let type1 = self.type_table.get_type(type_id1).clone();
-        let type2 = self.type_table.get_type(type_id2).clone();
+        let type1 = self.type_table.resolve(type_id1).clone();
+        let type2 = self.type_table.resolve(type_id2).clone();
         match (type1, type2) {
             (Type::Record(fields), Type::Record(other_fields)) => {
                 if fields.len() != other_fields.len() {
@@ -897,6 +1748,14 @@ impl TypeChecker {
                     None
                 }
             }
+            (Type::Array(t1), Type::Array(t2)) => {
+                let type_ = self.unify(t1, t2)?;
+                Some(self.type_table.insert(Type::Array(type_)))
+            }
+            (Type::Optional(t1), Type::Optional(t2)) => {
+                let type_ = self.unify(t1, t2)?;
+                Some(self.type_table.insert(Type::Optional(type_)))
+            }
             (Type::Arrow(param_type1, return_type1), Type::Arrow(param_type2, return_type2)) => {
                 match (
                     self.unify_type_vectors(&param_type1, &param_type2),
@@ -921,3 +1780,730 @@ impl TypeChecker {
         self.unify(type1, type2).is_some()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expr, ExprT, Loc, Type, TypeId, TypeSig};
+    use crate::lexer::{Lexer, Location, LocationRange};
+    use crate::parser::Parser;
+    use crate::printer::type_to_string;
+    use crate::typechecker::{TypeChecker, TypeError};
+    use crate::utils::{
+        NameTable, ABS_INDEX, ANY_INDEX, BOOL_INDEX, FLOAT_INDEX, INT_INDEX, STR_INDEX,
+        TO_STRING_INDEX, UNIT_INDEX,
+    };
+    use codespan_reporting::diagnostic::Severity;
+    use std::rc::Rc;
+
+    fn parse_expr(source: &str) -> (Loc<Expr>, TypeChecker) {
+        let mut parser = Parser::new(Lexer::new(source));
+        let expr = parser.expr().expect("failed to parse");
+        (expr, TypeChecker::new(parser.get_name_table()))
+    }
+
+    #[test]
+    fn overload_resolution() {
+        let (expr, mut typechecker) = parse_expr("abs(3)");
+        let expr_t = typechecker.expr(expr).unwrap();
+        assert_eq!(expr_t.inner.get_type(), INT_INDEX);
+
+        let (expr, mut typechecker) = parse_expr("min(1.0, 2.0)");
+        let expr_t = typechecker.expr(expr).unwrap();
+        assert_eq!(expr_t.inner.get_type(), FLOAT_INDEX);
+
+        // An any argument matches every overload. int wins over float...
+        let mut typechecker = TypeChecker::new(NameTable::new());
+        let location = LocationRange(Location(0), Location(0));
+        let abs = typechecker
+            .resolve_overload(ABS_INDEX, &[ANY_INDEX], location)
+            .unwrap();
+        assert_eq!(abs.params_type, vec![INT_INDEX]);
+
+        // ...but nothing wins over bool, so to_string is ambiguous
+        match typechecker.resolve_overload(TO_STRING_INDEX, &[ANY_INDEX], location) {
+            Err(TypeError::AmbiguousCall { name, .. }) => assert_eq!(name, "to_string"),
+            res => panic!("expected ambiguous call, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn stable_name_ids() {
+        // Parses and checks the source, returning the name table
+        // it ends up with
+        let analyze = |source: &str, name_table: NameTable| -> NameTable {
+            let mut parser = Parser::new(Lexer::with_name_table(source, name_table));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            assert!(typechecker.check_program(program).errors.is_empty());
+            typechecker.get_tables().1
+        };
+        let before = "let x: int = 1;\nfn f(a: int) -> int { a + x }";
+        let after = "let w: int = 0;\nlet x: int = 1;\nfn f(a: int) -> int { a + x + w }";
+        let old = analyze(before, NameTable::new());
+        let ids = |name_table: &NameTable| -> Vec<usize> {
+            ["x", "f", "a"]
+                .iter()
+                .map(|name| *name_table.get_id(name).unwrap())
+                .collect()
+        };
+
+        // From scratch, w takes x's old id
+        let fresh = analyze(after, NameTable::new());
+        assert_ne!(ids(&fresh), ids(&old));
+
+        let seeded = analyze(
+            after,
+            NameTable::with_names(old.names().map(str::to_string)),
+        );
+        assert_eq!(ids(&seeded), ids(&old));
+        assert_eq!(*seeded.get_id("w").unwrap(), old.names().count());
+    }
+
+    fn block_type(source: &str) -> TypeId {
+        let (expr, mut typechecker) = parse_expr(source);
+        typechecker.expr(expr).unwrap().inner.get_type()
+    }
+
+    #[test]
+    fn string_ops() {
+        assert_eq!(block_type("\"a\" + \"b\""), STR_INDEX);
+        assert_eq!(block_type("\"a\" == \"b\""), BOOL_INDEX);
+        assert_eq!(block_type("\"a\" != \"b\""), BOOL_INDEX);
+        for op in &["-", "*", "/"] {
+            let (expr, mut typechecker) = parse_expr(&format!("\"a\" {} \"b\"", op));
+            match typechecker.expr(expr) {
+                Err(TypeError::OpFailure { op: failed, .. }) => assert_eq!(failed.to_string(), *op),
+                res => panic!("expected op failure, got {:?}", res),
+            }
+        }
+    }
+
+    #[test]
+    fn bool_bit_ops() {
+        assert_eq!(block_type("true & false"), BOOL_INDEX);
+        assert_eq!(block_type("true | false"), BOOL_INDEX);
+        assert_eq!(block_type("6 | 1"), INT_INDEX);
+        for source in &["true & 1", "1 | false", "true ^ false"] {
+            let (expr, mut typechecker) = parse_expr(source);
+            match typechecker.expr(expr) {
+                Err(TypeError::OpFailure { .. }) => {}
+                res => panic!("expected op failure for {}, got {:?}", source, res),
+            }
+        }
+    }
+
+    #[test]
+    fn casts() {
+        assert_eq!(block_type("3 as float"), FLOAT_INDEX);
+        assert_eq!(block_type("3.9 as int"), INT_INDEX);
+        assert_eq!(block_type("-1 as float * 2.0"), FLOAT_INDEX);
+        assert_eq!(block_type("true as bool"), BOOL_INDEX);
+        let (expr, mut typechecker) = parse_expr("\"x\" as int");
+        match typechecker.expr(expr) {
+            Err(TypeError::InvalidCast { from, to, .. }) => {
+                assert_eq!(from, "string");
+                assert_eq!(to, "int");
+            }
+            res => panic!("expected invalid cast, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn function_type_errors() {
+        let source = "fn apply(f: (int, float) -> bool, x: int) -> int { f + x }";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let errors = typechecker.check_program(program).errors;
+        match &errors[..] {
+            [err @ TypeError::OpFailure { .. }] => assert!(
+                err.to_string()
+                    .ends_with("arguments of type (int, float) => bool and int"),
+                "{}",
+                err
+            ),
+            errors => panic!("expected an op failure, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn shadowing_builtin() {
+        let source = "fn floor(x: int) -> int { x }\nlet y: float = floor(2.5);";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let errors = typechecker.check_program(program).errors;
+        match &errors[..] {
+            [TypeError::ShadowingBuiltin { name, .. }] => assert_eq!(name, "floor"),
+            errors => panic!("expected shadowing a builtin, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn inferred_return_type() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            assert!(program.errors.is_empty(), "{:?}", program.errors);
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        assert!(check("fn double(x: int) -> int { x * 2 }\nlet y: int = double(2);").is_empty());
+        match &check("fn double(x: int) -> int { \"two\" }")[..] {
+            [TypeError::UnificationFailure { .. }] => {}
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+
+        assert!(check("fn double(x: int) { x * 2 }\nlet y: int = double(2) + 1;").is_empty());
+        assert!(check("fn greet() { print(\"hi\"); }\ngreet();").is_empty());
+        match &check("fn double(x: int) { x * 2 }\nlet s: string = double(2);")[..] {
+            [TypeError::UnificationFailure { type1, type2, .. }] => {
+                assert_eq!(type1, "string");
+                assert_eq!(type2, "int");
+            }
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+
+        // g's body hasn't been checked when f calls it, so we can't
+        // know what it returns yet
+        let source =
+            "fn f(x: int) { g(x) }\nfn g(x: int) { x * 2 }\nlet s: string = f(1);\nprint(s);";
+        match &check(source)[..] {
+            [TypeError::ReturnTypeNotInferred { name, .. }, ..] => assert_eq!(name, "g"),
+            errors => panic!("expected an uninferred return type, got {:?}", errors),
+        }
+        let source = "fn f(x: int) -> int { g(x) }\nfn g(x: int) { x * 2 }\nlet y: int = f(1);";
+        match &check(source)[..] {
+            [TypeError::ReturnTypeNotInferred { name, .. }] => assert_eq!(name, "g"),
+            errors => panic!("expected an uninferred return type, got {:?}", errors),
+        }
+        assert!(check(
+            "fn f(x: int) -> int { g(x) }\nfn g(x: int) -> int { x * 2 }\nlet y: int = f(1);"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn mismatched_returns() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        let source = "fn pick(b: bool) {\n  if b { return 1; } else { return \"one\"; };\n}";
+        match &check(source)[..] {
+            [TypeError::UnificationFailure {
+                location,
+                type1,
+                type2,
+            }] => {
+                assert_eq!((location.0).0, source.find("return \"one\"").unwrap());
+                assert_eq!(type1, "string");
+                assert_eq!(type2, "int");
+            }
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+
+        // The returns decide the type when the body is unit
+        let source = "fn pick(b: bool) {\n  if b { return 1; } else { return 2; };\n}\nlet s: string = pick(true);";
+        match &check(source)[..] {
+            [TypeError::UnificationFailure { type1, type2, .. }] => {
+                assert_eq!(type1, "string");
+                assert_eq!(type2, "int");
+            }
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+        assert!(check(
+            "fn pick(b: bool) {\n  if b { return 1; };\n  2\n}\nlet x: int = pick(true);"
+        )
+        .is_empty());
+        match &check("fn pick(b: bool) {\n  if b { return 1; };\n  \"two\"\n}")[..] {
+            [TypeError::UnificationFailure { .. }] => {}
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+
+        // Falling off the end gives unit, which has to agree with the returns
+        for source in &[
+            "fn pick(b: bool) {\n  if b { return \"x\"; };\n}",
+            "fn pick(b: bool) -> string {\n  if b { return \"x\"; };\n}",
+        ] {
+            match &check(source)[..] {
+                [TypeError::UnificationFailure { type1, type2, .. }] => {
+                    assert_eq!(type1, "string");
+                    assert_eq!(type2, "()");
+                }
+                errors => panic!("expected a unification failure, got {:?}", errors),
+            }
+        }
+        assert!(check(
+            "fn pick(b: bool) -> string {\n  if b { return \"x\"; };\n  return \"y\";\n}"
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn struct_names_in_errors() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        let source = "struct Point { x: int, y: int }\nstruct Size { w: int, h: int }\nlet p: Point = Size { w: 1, h: 2 };";
+        match &check(source)[..] {
+            [TypeError::UnificationFailure { type1, type2, .. }] => {
+                assert_eq!(type1, "Point");
+                assert_eq!(type2, "Size");
+            }
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+        match &check("let t: (int, bool) = (1, 2.0);")[..] {
+            [TypeError::UnificationFailure { type1, type2, .. }] => {
+                assert_eq!(type1, "(int, bool)");
+                assert_eq!(type2, "(int, float)");
+            }
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn optionals() {
+        let (expr, mut typechecker) = parse_expr("some(1)");
+        let type_ = typechecker.expr(expr).unwrap().inner.get_type();
+        let type_str = type_to_string(
+            typechecker.get_name_table(),
+            typechecker.get_type_table(),
+            type_,
+        );
+        assert_eq!(type_str, "int?");
+
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            assert!(program.errors.is_empty(), "{:?}", program.errors);
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        assert!(
+            check("let x: int? = some(1);\nlet y: int? = none;\nlet z: int = unwrap(x);")
+                .is_empty()
+        );
+        assert!(check("let x: [string?] = [none, some(\"a\")];").is_empty());
+        for source in &[
+            "let x: int = some(1);",
+            "let x: int? = some(\"a\");",
+            "let x: int = none;",
+            "let x: int = unwrap(1);",
+        ] {
+            match &check(source)[..] {
+                [TypeError::UnificationFailure { .. }] => {}
+                errors => panic!(
+                    "expected a unification failure for {}, got {:?}",
+                    source, errors
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn break_outside_loop() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            assert!(program.errors.is_empty(), "{:?}", program.errors);
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        assert!(
+            check("let i: int = 0;\nwhile i < 3 { i += 1; if i == 1 { continue; }; break; }")
+                .is_empty()
+        );
+        for source in &["break;", "fn f() { continue; }", "{ break; }"] {
+            match &check(source)[..] {
+                [TypeError::BreakOutsideLoop { .. }] => {}
+                errors => panic!(
+                    "expected break outside loop for {}, got {:?}",
+                    source, errors
+                ),
+            }
+        }
+        match &check("while 1 { }")[..] {
+            [TypeError::UnificationFailure { .. }] => {}
+            errors => panic!("expected a unification failure, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn not_callable() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        match &check("let x: int = 1;\nx(1);")[..] {
+            [TypeError::NotCallable {
+                name, type_name, ..
+            }] => {
+                assert_eq!(name, "x");
+                assert_eq!(type_name, "int");
+            }
+            errors => panic!("expected not callable, got {:?}", errors),
+        }
+        match &check("y(1);")[..] {
+            [TypeError::FunctionNotDefined { name, .. }] => assert_eq!(name, "y"),
+            errors => panic!("expected function not defined, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn bare_block_scope() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            assert!(program.errors.is_empty(), "{:?}", program.errors);
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            typechecker.check_program(program).errors
+        };
+        let errors = check("{ let y: int = 2; print(y); }\nprint(y);");
+        match &errors[..] {
+            [TypeError::VarNotDefined { name, .. }] => assert_eq!(name, "y"),
+            errors => panic!("expected y to be undefined, got {:?}", errors),
+        }
+        let errors = check("fn f() -> int {\n  { let y: int = 2; }\n  { let y: int = 3; }\n  y\n}");
+        match &errors[..] {
+            [TypeError::VarNotDefined { name, .. }] => assert_eq!(name, "y"),
+            errors => panic!("expected y to be undefined, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn block_value() {
+        assert_eq!(block_type("{ 1 }"), INT_INDEX);
+    }
+
+    #[test]
+    fn block_ending_in_stmt() {
+        assert_eq!(block_type("{ 1; }"), UNIT_INDEX);
+    }
+
+    #[test]
+    fn block_with_let() {
+        assert_eq!(block_type("{ let x: int = 1; x }"), INT_INDEX);
+    }
+
+    #[test]
+    fn assert_shapes() {
+        for source in &["assert(1 < 2)", "assert(true, \"message\")"] {
+            let (expr, mut typechecker) = parse_expr(source);
+            let expr_t = typechecker.expr(expr).unwrap();
+            assert_eq!(expr_t.inner.get_type(), UNIT_INDEX);
+        }
+        for source in &["assert(\"no\")", "assert(true, 1.5)", "assert()"] {
+            let (expr, mut typechecker) = parse_expr(source);
+            assert!(typechecker.expr(expr).is_err());
+        }
+    }
+
+    #[test]
+    fn exports() {
+        let source = "export fn f(x: int) -> int { x }\nfn g(x: int) -> int { f(x) }\nexport let y: int = g(1);";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let name_table = parser.get_name_table();
+        let (f, g, y) = (
+            *name_table.get_id("f").unwrap(),
+            *name_table.get_id("g").unwrap(),
+            *name_table.get_id("y").unwrap(),
+        );
+        let mut typechecker = TypeChecker::new(name_table);
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty());
+        assert_eq!(program_t.exports, vec![f, y]);
+        assert!(!program_t.exports.contains(&g));
+    }
+
+    #[test]
+    fn infinite_types() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            TypeChecker::new(parser.get_name_table()).check_program(program)
+        };
+
+        let program_t = check("struct Node { value: int, next: Node }");
+        match &program_t.errors[..] {
+            [TypeError::InfiniteType { name, .. }] => assert_eq!(name, "Node"),
+            errors => panic!("expected infinite type, got {:?}", errors),
+        }
+
+        let program_t = check("struct A { b: (int, B) }\nstruct B { a: A }");
+        assert_eq!(program_t.errors.len(), 2);
+        assert!(program_t
+            .errors
+            .iter()
+            .all(|err| matches!(err, TypeError::InfiniteType { .. })));
+
+        let program_t = check("struct Node { value: int, children: [Node] }");
+        assert!(program_t.errors.is_empty());
+        assert_eq!(program_t.named_types.len(), 1);
+    }
+
+    #[test]
+    fn forward_struct_references() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            let program_t = typechecker.check_program(program);
+            (program_t, typechecker)
+        };
+
+        let (program_t, typechecker) = check(
+            "struct A { b: B }\nstruct B { x: int }\nlet a: A = A { b: B { x: 1 } };\nlet x: int = a.b.x;",
+        );
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        let (_, a) = program_t.named_types[0];
+        let (_, b) = program_t.named_types[1];
+        match typechecker.get_type_table().resolve(a) {
+            Type::Record(fields) => assert_eq!(fields[0].1, b),
+            type_ => panic!("expected a record, got {:?}", type_),
+        }
+
+        let (program_t, _) = check(
+            "struct Parent { children: [Child] }\nstruct Child { parents: [Parent] }\nlet p: Parent = Parent { children: [] };",
+        );
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        assert_eq!(program_t.named_types.len(), 2);
+
+        let (program_t, _) = check("struct A { b: B }\nstruct C { x: int }");
+        match &program_t.errors[..] {
+            [TypeError::TypeDoesNotExist { type_name, .. }] => assert_eq!(type_name, "B"),
+            errors => panic!("expected a missing type, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            TypeChecker::new(parser.get_name_table()).check_program(program)
+        };
+        let program_t = check("let a: int = 1;\nlet b: int = 2;\na = b = 5;");
+        assert!(program_t.errors.is_empty());
+
+        let program_t = check("let a: int = 1;\nlet b: string = \"b\";\na = b = 5;");
+        match &program_t.errors[..] {
+            [TypeError::UnificationFailure { type1, type2, .. }] => {
+                assert_eq!((type1.as_str(), type2.as_str()), ("string", "int"));
+            }
+            errors => panic!("expected unification failure, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn len_args() {
+        let (expr, mut typechecker) = parse_expr("len([1.0, 2.0])");
+        assert_eq!(typechecker.expr(expr).unwrap().inner.get_type(), INT_INDEX);
+
+        let (expr, mut typechecker) = parse_expr("len(10)");
+        match typechecker.expr(expr) {
+            Err(TypeError::UnificationFailure { type1, type2, .. }) => {
+                assert_eq!(type1, "string or array");
+                assert_eq!(type2, "int");
+            }
+            res => panic!("expected unification failure, got {:?}", res),
+        }
+
+        let (expr, mut typechecker) = parse_expr("[1, true, 2.0]");
+        assert!(typechecker.expr(expr).is_err());
+    }
+
+    #[test]
+    fn recovers_in_binary_ops() {
+        let check = |source: &str| {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let mut typechecker = TypeChecker::new(parser.get_name_table());
+            let program_t = typechecker.check_program(program);
+            assert!(program_t.stmts.is_empty());
+            program_t.errors
+        };
+        let errors = check("let x: int = y + 1;");
+        match &errors[..] {
+            [TypeError::VarNotDefined { name, .. }] => assert_eq!(name, "y"),
+            errors => panic!("expected one undefined var, got {:?}", errors),
+        }
+        // Both sides still get checked, and the sum they're in
+        // doesn't add an error of its own
+        let errors = check("let x: int = y + (1 + \"a\");");
+        match &errors[..] {
+            [TypeError::VarNotDefined { .. }, TypeError::OpFailure { .. }] => {}
+            errors => panic!("expected an error per operand, got {:?}", errors),
+        }
+        assert_eq!(check("let x: int = (a + b) * (c + 1);").len(), 3);
+    }
+
+    #[test]
+    fn unused_value() {
+        let mut parser = Parser::new(Lexer::new("let x: int = 1;\n1 + 1;\nprint(x);"));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert_eq!(program_t.errors.len(), 1);
+        let warning = &program_t.errors[0];
+        assert_eq!(warning.severity(), Severity::Warning);
+        match warning {
+            TypeError::UnusedValue { type_name, .. } => assert_eq!(type_name, "int"),
+            err => panic!("expected unused value warning, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn arrow_type_sig() {
+        let mut typechecker = TypeChecker::new(NameTable::new());
+        let int = *typechecker.name_table.get_id("int").unwrap();
+        let bool_ = *typechecker.name_table.get_id("bool").unwrap();
+        let location = LocationRange(Location(0), Location(0));
+        let sig = Loc {
+            location,
+            inner: TypeSig::Arrow(
+                vec![Loc {
+                    location,
+                    inner: TypeSig::Name(int),
+                }],
+                Box::new(Loc {
+                    location,
+                    inner: TypeSig::Name(bool_),
+                }),
+            ),
+        };
+        let type_id = typechecker.lookup_type_sig(&sig).unwrap();
+        assert_eq!(
+            typechecker.type_table.get_type(type_id),
+            &Type::Arrow(vec![INT_INDEX], BOOL_INDEX)
+        );
+    }
+
+    #[test]
+    fn field_through_solved_type() {
+        let source = "struct P { x: int, y: bool }\nlet p: P = P { x: 1, y: true };";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+
+        // Nothing in the checker writes forwards yet, so make one
+        // by hand: P forwards to a copy of itself
+        let p_id = program_t.named_types[0].1;
+        let fields = typechecker.type_table.get_type(p_id).clone();
+        let copy_id = typechecker.type_table.insert(fields.clone());
+        typechecker.type_table.update(p_id, Type::Solved(copy_id));
+        assert_eq!(typechecker.type_table.resolve(p_id), &fields);
+
+        let lexer = Lexer::with_name_table("p.y", typechecker.take_name_table());
+        let mut parser = Parser::new(lexer);
+        let expr = parser.expr().unwrap();
+        typechecker.restore_name_table(parser.get_name_table());
+        let expr_t = typechecker.expr(expr).unwrap();
+        assert_eq!(expr_t.inner.get_type(), BOOL_INDEX);
+    }
+
+    #[test]
+    fn while_cond_through_solved_type() {
+        let source = "struct P { y: bool }\nlet p: P = P { y: true };";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+
+        // Make y's type a forward to bool by hand, since nothing
+        // in the checker writes forwards yet
+        let p_id = program_t.named_types[0].1;
+        let y = match typechecker.type_table.get_type(p_id) {
+            Type::Record(fields) => fields[0].0,
+            type_ => panic!("expected record, got {:?}", type_),
+        };
+        let forward_id = typechecker.type_table.insert(Type::Solved(BOOL_INDEX));
+        typechecker
+            .type_table
+            .update(p_id, Type::Record(vec![(y, forward_id)]));
+
+        let lexer = Lexer::with_name_table("while p.y { break; }", typechecker.take_name_table());
+        let mut parser = Parser::new(lexer);
+        let stmt = parser.stmt().unwrap().unwrap();
+        typechecker.restore_name_table(parser.get_name_table());
+        assert!(typechecker.stmt(stmt).is_ok());
+    }
+
+    #[test]
+    fn tuple_index() {
+        let (expr, mut typechecker) = parse_expr("(1, true).1");
+        let expr_t = typechecker.expr(expr).unwrap();
+        match expr_t.inner {
+            ExprT::TupleField(_, 1, type_) => assert_eq!(type_, BOOL_INDEX),
+            expr => panic!("expected tuple field, got {:?}", expr),
+        }
+
+        let (expr, mut typechecker) = parse_expr("(1, true).2");
+        match typechecker.expr(expr) {
+            Err(TypeError::TupleIndexOutOfBounds { index, len, .. }) => {
+                assert_eq!((index, len), (2, 2));
+            }
+            res => panic!("expected out of bounds index, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn array_annotation() {
+        let mut parser = Parser::new(Lexer::new("let a: [int] = [1, 2];\nlet b: int = len(a);"));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+    }
+
+    #[test]
+    fn function_clone_shares_body() {
+        let source = "fn f(x: int) -> int { let y: int = x * 2; y + 1 }";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let mut typechecker = TypeChecker::new(parser.get_name_table());
+        let program_t = typechecker.check_program(program);
+        assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+        let functions = typechecker.take_functions();
+        let (_, function) = &functions[0];
+        assert_eq!(Rc::strong_count(&function.body), 1);
+        let cloned = function.clone();
+        assert!(Rc::ptr_eq(&function.body, &cloned.body));
+        assert_eq!(Rc::strong_count(&function.body), 2);
+    }
+
+    #[test]
+    fn function_order() {
+        let source =
+            "fn d() { 4 }\nfn b() { 2 }\nfn e() { d() }\nfn a() { 1 }\nfn c() { a() + b() }";
+        let names = || {
+            let mut parser = Parser::new(Lexer::new(source));
+            let program = parser.program().unwrap();
+            let name_table = parser.get_name_table();
+            let mut typechecker = TypeChecker::new(name_table.clone());
+            let program_t = typechecker.check_program(program);
+            assert!(program_t.errors.is_empty(), "{:?}", program_t.errors);
+            typechecker
+                .take_functions()
+                .into_iter()
+                .map(|(name, _)| name_table.get_str(&name).to_string())
+                .collect::<Vec<_>>()
+        };
+        let first = names();
+        assert_eq!(first, vec!["d", "b", "e", "a", "c"]);
+        for _ in 0..10 {
+            assert_eq!(names(), first);
+        }
+    }
+}
@@ -1,10 +1,19 @@
-use crate::ast::{Expr, Loc, Program, Stmt, TypeSig, Value};
-use crate::utils::{NameTable, PRINT_INDEX};
+use crate::ast::{Expr, Loc, Op, Program, Stmt, TypeDef, TypeSig, UnaryOp, Value};
+use crate::lexer::{Comment, LocationRange};
+use crate::utils::NameTable;
 use serde::{Deserialize, Serialize};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 
 pub struct Unparser {
     name_table: NameTable,
-    indent_level: usize,
+    // A Cell so blocks can indent their contents while
+    // everything else only needs &self
+    indent_level: Cell<usize>,
+    // When formatting, the comments not placed yet in source order,
+    // and the source to tell which ones share a line with code
+    comments: RefCell<VecDeque<Comment>>,
+    source: RefCell<String>,
 }
 
 #[derive(Debug, Fail, PartialEq, Clone, Serialize, Deserialize)]
@@ -14,16 +23,179 @@ pub enum UnparseError {
 }
 
 pub struct UnparsedProgram {
+    pub type_defs: String,
     pub functions: String,
     pub global_stmts: String
 }
 
+// Binding strength of each level in the parser, from
+// || (loosest) up to unary ops and then everything else
+const CAST_PRECEDENCE: u8 = 11;
+const UNARY_PRECEDENCE: u8 = 12;
+const MAX_PRECEDENCE: u8 = 13;
+
+// A top level item, for putting type definitions and
+// statements back in source order
+enum Item<'a> {
+    TypeDef(&'a Loc<TypeDef>),
+    Stmt(&'a Loc<Stmt>),
+}
+
+// Puts back the escapes the lexer took out
+fn escape_string(s: &str) -> String {
+    let mut escaped = String::new();
+    for ch in s.chars() {
+        match ch {
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            '\0' => escaped.push_str("\\0"),
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", ch as u32)),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+fn op_precedence(op: &Op) -> u8 {
+    match op {
+        Op::Or => 1,
+        Op::And => 2,
+        Op::EqualEqual | Op::BangEqual => 3,
+        Op::Greater | Op::GreaterEqual | Op::Less | Op::LessEqual => 4,
+        Op::BitOr => 5,
+        Op::BitXor => 6,
+        Op::BitAnd => 7,
+        Op::Shl | Op::Shr => 8,
+        Op::Plus | Op::Minus => 9,
+        Op::Times | Op::Div => 10,
+    }
+}
+
+fn expr_precedence(expr: &Expr) -> u8 {
+    match expr {
+        Expr::BinOp { op, lhs: _, rhs: _ } => op_precedence(op),
+        Expr::UnaryOp { op: _, rhs: _ } => UNARY_PRECEDENCE,
+        Expr::Cast(_, _) => CAST_PRECEDENCE,
+        Expr::If(_, _, _) => 0,
+        _ => MAX_PRECEDENCE,
+    }
+}
+
 impl Unparser {
     pub fn new(name_table: NameTable) -> Self {
         Unparser {
             name_table,
-            indent_level: 0,
+            indent_level: Cell::new(0),
+            comments: RefCell::new(VecDeque::new()),
+            source: RefCell::new(String::new()),
+        }
+    }
+
+    // Takes the comments that start before the offset
+    fn take_comments(&self, before: usize) -> Vec<Comment> {
+        let mut comments = self.comments.borrow_mut();
+        let mut taken = Vec::new();
+        while comments
+            .front()
+            .is_some_and(|comment| (comment.location.0).0 < before)
+        {
+            taken.extend(comments.pop_front());
+        }
+        taken
+    }
+
+    // Takes the next comment if it's on the same line as the
+    // code ending at the offset
+    fn take_trailing_comment(&self, after: usize) -> Option<Comment> {
+        let mut comments = self.comments.borrow_mut();
+        let start = (comments.front()?.location.0).0;
+        let source = self.source.borrow();
+        if after <= start && !source[after..start].contains('\n') {
+            comments.pop_front()
+        } else {
+            None
+        }
+    }
+
+    // Puts the comments for a line of a block around its text. The
+    // ones inside it that no nested block took go just before it
+    fn with_comments(
+        &self,
+        location: LocationRange,
+        unparse: impl FnOnce() -> Result<String, UnparseError>,
+    ) -> Result<String, UnparseError> {
+        let mut out = String::new();
+        for comment in self.take_comments((location.0).0) {
+            out.push_str(&format!("{}{}\n", self.indents(), comment.text));
+        }
+        let text = unparse()?;
+        for comment in self.take_comments((location.1).0) {
+            out.push_str(&format!("{}{}\n", self.indents(), comment.text));
+        }
+        out.push_str(&text);
+        if let Some(comment) = self.take_trailing_comment((location.1).0) {
+            out.push_str(&format!(" {}", comment.text));
+        }
+        Ok(out)
+    }
+
+    fn indents(&self) -> String {
+        "  ".repeat(self.indent_level.get())
+    }
+
+    // Runs f one indent level deeper
+    fn indented<T>(&self, f: impl FnOnce() -> T) -> T {
+        self.indent_level.set(self.indent_level.get() + 1);
+        let res = f();
+        self.indent_level.set(self.indent_level.get() - 1);
+        res
+    }
+
+    // Braces around the statements, each on its own line one
+    // level in, with the closing brace at the current level.
+    // `location` is the whole block, so we know which comments
+    // go before the closing brace
+    fn unparse_block(
+        &self,
+        stmts: &[Loc<Stmt>],
+        end_expr: Option<&Loc<Expr>>,
+        location: LocationRange,
+    ) -> Result<String, UnparseError> {
+        let has_comments = self
+            .comments
+            .borrow()
+            .front()
+            .is_some_and(|comment| (comment.location.0).0 < (location.1).0);
+        if stmts.is_empty() && end_expr.is_none() && !has_comments {
+            return Ok("{}".to_string());
         }
+        let mut out = "{\n".to_string();
+        self.indented(|| -> Result<(), UnparseError> {
+            for stmt in stmts {
+                out.push_str(&self.with_comments(stmt.location, || self.unparse_stmt(stmt))?);
+                out.push('\n');
+            }
+            if let Some(end_expr) = end_expr {
+                let text = self.with_comments(end_expr.location, || {
+                    Ok(format!(
+                        "{}{}",
+                        self.indents(),
+                        self.unparse_expr(end_expr)?
+                    ))
+                })?;
+                out.push_str(&text);
+                out.push('\n');
+            }
+            for comment in self.take_comments((location.1).0) {
+                out.push_str(&format!("{}{}\n", self.indents(), comment.text));
+            }
+            Ok(())
+        })?;
+        out.push_str(&format!("{}}}", self.indents()));
+        Ok(out)
     }
 
     fn get_free_name(&self) -> String {
@@ -55,12 +227,124 @@ impl Unparser {
         for stmt in global_stmts {
             unparsed_global_stmts.push(self.unparse_stmt(stmt)?);
         }
+        let mut unparsed_type_defs = Vec::new();
+        for type_def in &program.type_defs {
+            unparsed_type_defs.push(self.unparse_type_def(type_def)?);
+        }
         let main_function = format!("fn {}() {{ {} }}", self.get_free_name(), unparsed_global_stmts.join("\n"));
-        Ok(UnparsedProgram { functions: unparsed_functions.join("\n"), global_stmts: main_function })
+        Ok(UnparsedProgram {
+            type_defs: unparsed_type_defs.join("\n"),
+            functions: unparsed_functions.join("\n"),
+            global_stmts: main_function,
+        })
+    }
+
+    // Unparses the program in source order, without splitting
+    // out functions or wrapping globals in a main function
+    pub fn unparse_source(&self, program: &Program) -> Result<String, UnparseError> {
+        let mut lines = Vec::new();
+        for type_def in &program.type_defs {
+            lines.push(self.unparse_type_def(type_def)?);
+        }
+        for stmt in &program.stmts {
+            lines.push(self.unparse_stmt(stmt)?);
+        }
+        Ok(lines.join("\n"))
+    }
+
+    // Like unparse_source, but puts back the comments from the source
+    // and keeps up to one blank line between top level items. Comments
+    // go with the innermost statement around them, and ones on the
+    // same line as the end of a statement stay there
+    pub fn format_source(
+        &self,
+        program: &Program,
+        source: &str,
+        comments: &[Comment],
+    ) -> Result<String, UnparseError> {
+        let mut sorted_comments = comments.to_vec();
+        sorted_comments.sort_by_key(|comment| (comment.location.0).0);
+        self.comments.replace(sorted_comments.into());
+        self.source.replace(source.to_string());
+
+        let mut items: Vec<(LocationRange, Item)> = Vec::new();
+        for type_def in &program.type_defs {
+            items.push((type_def.location, Item::TypeDef(type_def)));
+        }
+        for stmt in &program.stmts {
+            items.push((stmt.location, Item::Stmt(stmt)));
+        }
+        items.sort_by_key(|(location, _)| (location.0).0);
+
+        // Each piece is whether it's an item, the range it covers in
+        // the source, and its text
+        let mut pieces: Vec<(bool, LocationRange, String)> = Vec::new();
+        for (location, item) in items {
+            for comment in self.take_comments((location.0).0) {
+                pieces.push((false, comment.location, comment.text));
+            }
+            let text = match item {
+                Item::TypeDef(type_def) => self.unparse_type_def(type_def)?,
+                Item::Stmt(stmt) => self.unparse_stmt(stmt)?,
+            };
+            // Comments inside the item that no block took
+            for comment in self.take_comments((location.1).0) {
+                pieces.push((false, LocationRange(location.0, location.0), comment.text));
+            }
+            pieces.push((true, location, text));
+        }
+        for comment in self.take_comments(usize::MAX) {
+            pieces.push((false, comment.location, comment.text));
+        }
+
+        let mut out = String::new();
+        let mut last_end = None;
+        for (is_item, location, text) in pieces {
+            if let Some(last_end) = last_end {
+                let start = (location.0).0;
+                let gap = if last_end <= start {
+                    &source[last_end..start]
+                } else {
+                    ""
+                };
+                let newlines = gap.matches('\n').count();
+                if !is_item && newlines == 0 && !gap.is_empty() {
+                    out.push(' ');
+                } else {
+                    out.push('\n');
+                    if newlines > 1 {
+                        out.push('\n');
+                    }
+                }
+            }
+            out.push_str(&text);
+            last_end = Some((location.1).0);
+        }
+        Ok(out)
+    }
+
+    fn unparse_type_def(&self, type_def: &Loc<TypeDef>) -> Result<String, UnparseError> {
+        match &type_def.inner {
+            TypeDef::Struct(name, fields) => {
+                let mut fields_str = String::new();
+                for (field_name, type_sig) in fields {
+                    fields_str.push_str(&format!(
+                        "  {}: {},\n",
+                        self.name_table.get_str(field_name),
+                        self.unparse_type_sig(type_sig)?
+                    ));
+                }
+                Ok(format!(
+                    "struct {} {{\n{}}}",
+                    self.name_table.get_str(name),
+                    fields_str
+                ))
+            }
+        }
     }
 
     fn unparse_stmt(&self, stmt: &Loc<Stmt>) -> Result<String, UnparseError> {
-        let indents = "  ".repeat(self.indent_level);
+        let indents = self.indents();
         match &stmt.inner {
             Stmt::Def(name, type_sig, rhs) => Ok(format!(
                 "{}let {}: {} = {};",
@@ -69,7 +353,55 @@ impl Unparser {
                 self.unparse_type_sig(type_sig)?,
                 self.unparse_expr(rhs)?
             )),
+            Stmt::Asgn(name, rhs) => Ok(format!(
+                "{}{} = {};",
+                indents,
+                self.name_table.get_str(name),
+                self.unparse_expr(rhs)?
+            )),
+            Stmt::ChainedAsgn(names, rhs) => {
+                let mut targets = String::new();
+                for name in names {
+                    targets.push_str(&format!("{} = ", self.name_table.get_str(name)));
+                }
+                Ok(format!(
+                    "{}{}{};",
+                    indents,
+                    targets,
+                    self.unparse_expr(rhs)?
+                ))
+            }
+            // An if at the top level is a statement on its own, but in a
+            // block it's an expression statement like any other
+            Stmt::Expr(
+                expr @ Loc {
+                    inner: Expr::If(..),
+                    ..
+                },
+            ) if self.indent_level.get() == 0 => {
+                Ok(format!("{}{}", indents, self.unparse_expr(expr)?))
+            }
             Stmt::Expr(expr) => Ok(format!("{}{};", indents, self.unparse_expr(expr)?)),
+            Stmt::OpAsgn(name, op, rhs) => Ok(format!(
+                "{}{} {}= {};",
+                indents,
+                self.name_table.get_str(name),
+                op,
+                self.unparse_expr(rhs)?
+            )),
+            Stmt::FieldAsgn(lhs, name, rhs) => Ok(format!(
+                "{}{}.{} = {};",
+                indents,
+                self.unparse_operand(lhs, MAX_PRECEDENCE)?,
+                self.name_table.get_str(name),
+                self.unparse_expr(rhs)?
+            )),
+            Stmt::Return(expr) => Ok(format!("{}return {};", indents, self.unparse_expr(expr)?)),
+            Stmt::Export(stmt) => Ok(format!(
+                "{}export {}",
+                indents,
+                self.unparse_stmt(stmt)?.trim_start()
+            )),
             Stmt::Function {
                 name,
                 params,
@@ -87,111 +419,167 @@ impl Unparser {
                         ))
                     })
                     .collect();
+                let return_type = match return_type {
+                    Some(return_type) => format!(" -> {}", self.unparse_type_sig(return_type)?),
+                    None => String::new(),
+                };
+                // Bodies that aren't blocks end in a semicolon
+                let body = match &body.inner {
+                    Expr::Block(..) => self.unparse_expr(body)?,
+                    _ => format!("{};", self.unparse_expr(body)?),
+                };
                 Ok(format!(
-                    "{}fn {}({}) -> {} {{\n{}}}",
+                    "{}fn {}({}){} {}",
                     indents,
                     self.name_table.get_str(name),
                     params?.join(", "),
-                    self.unparse_type_sig(return_type)?,
-                    self.unparse_expr(body)?
+                    return_type,
+                    body
                 ))
             }
-            s => Err(UnparseError::NotImplemented {
-                node: format!("{:?}", s),
-            }),
+            Stmt::Block(stmts) => Ok(format!(
+                "{}{}",
+                indents,
+                self.unparse_block(stmts, None, stmt.location)?
+            )),
+            Stmt::While(cond, body) => Ok(format!(
+                "{}while {} {}",
+                indents,
+                self.unparse_expr(cond)?,
+                self.unparse_expr(body)?
+            )),
+            Stmt::Break => Ok(format!("{}break;", indents)),
+            Stmt::Continue => Ok(format!("{}continue;", indents)),
         }
     }
 
     fn unparse_expr(&self, expr: &Loc<Expr>) -> Result<String, UnparseError> {
         match &expr.inner {
             Expr::Primary { value } => self.unparse_value(value),
-            Expr::BinOp { op, lhs, rhs } => Ok(format!(
-                "{} {} {}",
-                self.unparse_expr(&**lhs)?,
-                op,
-                self.unparse_expr(&**rhs)?
-            )),
+            Expr::BinOp { op, lhs, rhs } => {
+                let precedence = op_precedence(op);
+                // Logical, additive and multiplicative ops are left
+                // associative, while equality and comparison don't chain
+                // at all, so only a lhs at the same level of those can
+                // go without parens
+                let lhs_min = match precedence {
+                    3 | 4 => precedence + 1,
+                    _ => precedence,
+                };
+                Ok(format!(
+                    "{} {} {}",
+                    self.unparse_operand(lhs, lhs_min)?,
+                    op,
+                    self.unparse_operand(rhs, precedence + 1)?
+                ))
+            }
+            Expr::UnaryOp { op, rhs } => {
+                let op_str = match op {
+                    UnaryOp::Minus => "-",
+                    UnaryOp::Not => "!",
+                };
+                Ok(format!(
+                    "{}{}",
+                    op_str,
+                    self.unparse_operand(rhs, UNARY_PRECEDENCE)?
+                ))
+            }
             Expr::Call { callee, args } => {
                 let args_str: Result<Vec<_>, _> =
                     args.iter().map(|a| self.unparse_expr(a)).collect();
-                let str = if *callee == PRINT_INDEX {
-                    "print!"
-                } else {
-                    self.name_table.get_str(callee)
-                };
                 Ok(format!(
                     "{}({})",
-                    str,
+                    self.name_table.get_str(callee),
                     args_str?.join(", ")
                 ))
             }
             Expr::Field(lhs, name) => Ok(format!(
                 "{}.{}",
-                self.unparse_expr(lhs)?,
+                self.unparse_operand(lhs, MAX_PRECEDENCE)?,
                 self.name_table.get_str(name)
             )),
-            Expr::TupleField(lhs, index) => Ok(format!("{}.{}", self.unparse_expr(lhs)?, *index)),
+            Expr::TupleField(lhs, index) => Ok(format!(
+                "{}.{}",
+                self.unparse_operand(lhs, MAX_PRECEDENCE)?,
+                *index
+            )),
+            Expr::Cast(lhs, type_sig) => Ok(format!(
+                "{} as {}",
+                self.unparse_operand(lhs, CAST_PRECEDENCE)?,
+                self.unparse_type_sig(type_sig)?
+            )),
             Expr::Record { name, fields } => {
-                let indents = "  ".repeat(self.indent_level + 1);
                 let fields_vec: Result<Vec<_>, _> = fields
                     .iter()
                     .map(|(name, expr)| {
                         Ok(format!(
-                            "{}{}: {}",
-                            indents,
+                            "{}: {}",
                             self.name_table.get_str(name),
                             self.unparse_expr(expr)?
                         ))
                     })
                     .collect();
-
                 Ok(format!(
-                    "{} {{\n{}\n{}}}",
+                    "{} {{ {} }}",
                     self.name_table.get_str(name),
-                    "  ".repeat(self.indent_level),
-                    fields_vec?.join(",\n")
+                    fields_vec?.join(", ")
                 ))
             }
             Expr::Tuple(entries) => {
                 let entries: Result<Vec<_>, _> =
                     entries.iter().map(|e| self.unparse_expr(e)).collect();
-                Ok(format!("({})", entries?.join(", ")))
+                let entries = entries?;
+                // Keep the comma so it doesn't read back as a grouping
+                if entries.len() == 1 {
+                    Ok(format!("({},)", entries[0]))
+                } else {
+                    Ok(format!("({})", entries.join(", ")))
+                }
+            }
+            Expr::Array(entries) => {
+                let entries: Result<Vec<_>, _> =
+                    entries.iter().map(|e| self.unparse_expr(e)).collect();
+                Ok(format!("[{}]", entries?.join(", ")))
             }
             Expr::Block(stmts, end_expr) => {
-                let mut unparsed_stmts = Vec::new();
-                for stmt in stmts {
-                    unparsed_stmts.push(self.unparse_stmt(stmt)?);
-                }
-                if let Some(end_expr) = end_expr {
-                    unparsed_stmts.push(format!("{}\n", self.unparse_expr(end_expr)?));
-                }
-                Ok(unparsed_stmts.join(""))
+                self.unparse_block(stmts, end_expr.as_deref(), expr.location)
             }
             Expr::Var { name } => {
                 Ok(self.name_table.get_str(name).to_string())
             },
+            // Both branches are always blocks
             Expr::If(cond, then_block, else_block) => {
+                // In source order, so each branch gets its own comments
+                let cond_str = self.unparse_expr(cond)?;
+                let then_str = self.unparse_expr(then_block)?;
                 let else_str = if let Some(else_block) = else_block {
-                    format!(" else {{\n{}}}", self.unparse_expr(else_block)?)
+                    format!(" else {}", self.unparse_expr(else_block)?)
                 } else {
                     String::new()
                 };
-                Ok(format!(
-                    "if {} {{\n{}}}{}",
-                    self.unparse_expr(cond)?,
-                    self.unparse_expr(then_block)?,
-                    else_str
-                ))
+                Ok(format!("if {} {}{}", cond_str, then_str, else_str))
             }
-            e => Err(UnparseError::NotImplemented {
-                node: format!("{:?}", e),
-            }),
+        }
+    }
+
+    // Wraps the expr in parens if it binds looser than min_precedence
+    fn unparse_operand(
+        &self,
+        expr: &Loc<Expr>,
+        min_precedence: u8,
+    ) -> Result<String, UnparseError> {
+        let expr_str = self.unparse_expr(expr)?;
+        if expr_precedence(&expr.inner) < min_precedence {
+            Ok(format!("({})", expr_str))
+        } else {
+            Ok(expr_str)
         }
     }
 
     fn unparse_value(&self, value: &Value) -> Result<String, UnparseError> {
         match value {
-            Value::Float(v) => Ok(format!("{}", v)),
+            // Debug keeps the .0 on whole floats so they don't read back as ints
+            Value::Float(v) => Ok(format!("{:?}", v)),
             Value::Integer(v) => Ok(format!("{}", v)),
             Value::Bool(b) => {
                 if *b {
@@ -200,13 +588,15 @@ impl Unparser {
                     Ok("false".to_string())
                 }
             }
-            Value::String(s) => Ok(format!("\"{}\"", s)),
+            Value::String(s) => Ok(format!("\"{}\"", escape_string(s))),
+            Value::Char(c) => Ok(format!("{:?}", c)),
             Value::Tuple(entries) => {
                 let entries: Result<Vec<_>, _> =
                     entries.iter().map(|e| self.unparse_value(e)).collect();
                 Ok(format!("({})", entries?.join(", ")))
             }
             Value::Empty => Ok("()".to_string()),
+            Value::None => Ok("none".to_string()),
         }
     }
 
@@ -218,10 +608,169 @@ impl Unparser {
                 for entry in entries {
                     type_sigs.push(self.unparse_type_sig(entry)?);
                 }
-                Ok(type_sigs.join(", "))
+                Ok(format!("({})", type_sigs.join(", ")))
             }
             TypeSig::Array(type_sig) => Ok(format!("[{}]", self.unparse_type_sig(type_sig)?)),
+            TypeSig::Optional(type_sig) => Ok(format!("{}?", self.unparse_type_sig(type_sig)?)),
             TypeSig::Empty => Ok("()".to_string()),
+            TypeSig::Arrow(params, return_type) => {
+                let mut param_sigs = Vec::new();
+                for param in params {
+                    param_sigs.push(self.unparse_type_sig(param)?);
+                }
+                Ok(format!(
+                    "({}) -> {}",
+                    param_sigs.join(", "),
+                    self.unparse_type_sig(return_type)?
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::unparser::Unparser;
+    use std::mem;
+
+    #[test]
+    fn format_keeps_comments() {
+        let source = "// leading
+let x: int = 1; // trailing
+
+
+
+// about y
+let y: int = x + // inside
+  1;
+let z: int = y;
+// the end
+";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let comments = mem::take(&mut parser.lexer.comments);
+        let formatted = Unparser::new(parser.get_name_table())
+            .format_source(&program, source, &comments)
+            .unwrap();
+        let expected = "// leading
+let x: int = 1; // trailing
+
+// about y
+// inside
+let y: int = x + 1;
+let z: int = y;
+// the end";
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn format_keeps_comments_in_bodies() {
+        let source = "fn f() -> int {
+  // explain
+  let x: int = 1; // why
+  let y: int = x + // inside
+    1;
+  // the answer
+  y
+}";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let comments = mem::take(&mut parser.lexer.comments);
+        let formatted = Unparser::new(parser.get_name_table())
+            .format_source(&program, source, &comments)
+            .unwrap();
+        let expected = "fn f() -> int {
+  // explain
+  let x: int = 1; // why
+  // inside
+  let y: int = x + 1;
+  // the answer
+  y
+}";
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn format_keeps_comments_in_nested_blocks() {
+        let source = "fn f(b: bool) -> int {
+  if b {
+    // yes
+    return 1;
+  } else {
+    // nothing here
+  };
+  while b {
+    b = false;
+    // spun once
+  };
+  2
+  // after
+}";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let comments = mem::take(&mut parser.lexer.comments);
+        let formatted = Unparser::new(parser.get_name_table())
+            .format_source(&program, source, &comments)
+            .unwrap();
+        let expected = "fn f(b: bool) -> int {
+  if b {
+    // yes
+    return 1;
+  } else {
+    // nothing here
+  };
+  while b {
+    b = false;
+    // spun once
+  }
+  2
+  // after
+}";
+        assert_eq!(formatted, expected);
+    }
+
+    #[test]
+    fn preserves_precedence() {
+        let sources = [
+            "(1 + 2) * 3",
+            "1 + 2 * 3",
+            "1 - (2 - 3)",
+            "1 - 2 - 3",
+            "1 < 2 == 3 > 4",
+            "(1 == 2) == false",
+            "-(1 + 2) * -3",
+            "!(1 == 2)",
+            "(1 + 2).0",
+            "true || false && 1 < 2",
+            "(true || false) && true",
+            "(1,)",
+            r"'a' != '\''",
+        ];
+        for source in sources.iter() {
+            let mut parser = Parser::new(Lexer::new(source));
+            let expr = parser.expr().unwrap();
+            let unparsed = Unparser::new(parser.get_name_table())
+                .unparse_expr(&expr)
+                .unwrap();
+            assert_eq!(&unparsed, source);
+            let mut parser = Parser::new(Lexer::new(&unparsed));
+            assert_eq!(parser.expr().unwrap(), expr);
         }
     }
+
+    #[test]
+    fn struct_type_def() {
+        let source = "struct Point { x: int, y: (float, bool) }\nlet a: int = 1;";
+        let mut parser = Parser::new(Lexer::new(source));
+        let program = parser.program().unwrap();
+        let unparsed = Unparser::new(parser.get_name_table())
+            .unparse_program(&program)
+            .unwrap();
+        assert_eq!(
+            unparsed.type_defs,
+            "struct Point {\n  x: int,\n  y: (float, bool),\n}"
+        );
+    }
 }
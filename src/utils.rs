@@ -1,23 +1,75 @@
-use crate::ast::{Type, TypeId};
-use bimap::BiMap;
+use crate::ast::{Name, Type, TypeId};
 use codespan_reporting::term::termcolor::{ColorSpec, WriteColor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::io;
+use std::rc::Rc;
 
 pub fn any_as_u8_slice<T: Sized + Copy>(p: &T) -> &[u8] {
     unsafe { std::slice::from_raw_parts(p as *const T as *const u8, std::mem::size_of::<T>()) }
 }
 
+// Small xorshift PRNG for rand and rand_int. Not
+// cryptographically secure, just reproducible
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // xorshift gets stuck on 0, so mix the seed first
+        let state = seed ^ 0x9E37_79B9_7F4A_7C15;
+        Rng {
+            state: if state == 0 {
+                0x9E37_79B9_7F4A_7C15
+            } else {
+                state
+            },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    // Float in [0, 1) using the top 53 bits
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    // Int in [lo, hi). Caller makes sure lo < hi
+    pub fn next_in_range(&mut self, lo: i64, hi: i64) -> i64 {
+        let span = hi.wrapping_sub(lo) as u64;
+        lo.wrapping_add((self.next_u64() % span) as i64)
+    }
+}
+
 pub struct StringWriter {
     buf: Vec<u8>,
+    // How many times the writer was flushed, so tests can check
+    // when output would have reached the terminal
+    flushes: usize,
 }
 
 impl StringWriter {
     pub fn new() -> StringWriter {
         StringWriter {
             buf: Vec::with_capacity(8 * 1024),
+            flushes: 0,
         }
     }
 
+    pub fn flush_count(&self) -> usize {
+        self.flushes
+    }
+
     pub fn to_string(&self) -> String {
         if let Ok(s) = String::from_utf8(self.buf.clone()) {
             s
@@ -36,6 +88,7 @@ impl io::Write for StringWriter {
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.flushes += 1;
         Ok(())
     }
 }
@@ -54,44 +107,184 @@ impl WriteColor for StringWriter {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct NameTable(BiMap<String, usize>, usize);
+// Ids are handed out in order, so the names can live in a Vec indexed
+// by id. The Vec and the map share each name through an Rc, and the
+// map can be queried with a &str so we only allocate when a name is
+// seen for the first time.
+// Serialized as (name, id) pairs, since the AST is meaningless
+// without the names its ids point to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "Vec<(String, usize)>", try_from = "Vec<(String, usize)>")]
+pub struct NameTable {
+    names: Vec<Rc<str>>,
+    ids: HashMap<Rc<str>, usize>,
+}
 
 pub static PRINT_INDEX: usize = 0;
+pub const SQRT_INDEX: usize = 1;
+pub const SIN_INDEX: usize = 2;
+pub const COS_INDEX: usize = 3;
+pub const FLOOR_INDEX: usize = 4;
+pub const CEIL_INDEX: usize = 5;
+pub const TYPE_NAME_INDEX: usize = 6;
+pub const ABS_INDEX: usize = 7;
+pub const MIN_INDEX: usize = 8;
+pub const MAX_INDEX: usize = 9;
+pub const TO_STRING_INDEX: usize = 10;
+pub const LEN_INDEX: usize = 11;
+pub const RAND_INDEX: usize = 12;
+pub const RAND_INT_INDEX: usize = 13;
+pub const REQUIRE_INDEX: usize = 14;
+pub const ASSERT_INDEX: usize = 15;
+pub const SOME_INDEX: usize = 16;
+pub const UNWRAP_INDEX: usize = 17;
+pub const READ_LINE_INDEX: usize = 18;
+
+// Names of builtin functions. Order must match the indices above
+static BUILTIN_NAMES: [&str; 19] = [
+    "print",
+    "sqrt",
+    "sin",
+    "cos",
+    "floor",
+    "ceil",
+    "type_name",
+    "abs",
+    "min",
+    "max",
+    "to_string",
+    "len",
+    "rand",
+    "rand_int",
+    "require",
+    "assert",
+    "some",
+    "unwrap",
+    "read_line",
+];
+
+// Builtins get the first ids in every name table
+pub fn is_builtin(name: usize) -> bool {
+    name < BUILTIN_NAMES.len()
+}
 
 impl NameTable {
     pub fn new() -> Self {
-        let mut map = BiMap::new();
-        map.insert("print".to_string(), 0);
-        NameTable(map, 1)
+        let mut name_table = NameTable {
+            names: Vec::new(),
+            ids: HashMap::new(),
+        };
+        for name in BUILTIN_NAMES.iter() {
+            name_table.insert(name.to_string());
+        }
+        name_table
+    }
+
+    // Pre-seeds the table so each name gets the id of its position,
+    // after the builtins. Feeding in the names from a previous run
+    // keeps ids stable across edits, e.g. for an editor re-analyzing
+    // a file. Names the new source adds go on the end
+    pub fn with_names(names: impl IntoIterator<Item = String>) -> Self {
+        let mut name_table = NameTable::new();
+        for name in names {
+            name_table.insert(name);
+        }
+        name_table
+    }
+
+    // Rebuilds a table from its (name, id) pairs, where `next` is the
+    // id the next new name gets. Ids have to be dense and the builtins
+    // have to be at their usual ids, or the AST's names would point
+    // at the wrong thing
+    pub fn from_entries(mut entries: Vec<(String, usize)>, next: usize) -> Result<Self, String> {
+        entries.sort_by_key(|(_, id)| *id);
+        let mut name_table = NameTable {
+            names: Vec::new(),
+            ids: HashMap::new(),
+        };
+        for (name, id) in entries {
+            if name_table.insert(name) != id {
+                return Err(format!("Name table has a gap or duplicate at id {}", id));
+            }
+        }
+        if next != name_table.names.len() {
+            return Err(format!(
+                "Next id is {} but the table has {} names",
+                next,
+                name_table.names.len()
+            ));
+        }
+        let has_builtins = BUILTIN_NAMES
+            .iter()
+            .enumerate()
+            .all(|(id, name)| name_table.names.get(id).map(|n| &**n) == Some(*name));
+        if !has_builtins {
+            return Err("Name table is missing builtins at their ids".to_string());
+        }
+        Ok(name_table)
     }
+
+    // Every name, in id order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.names.iter().map(|name| &**name)
+    }
+
     pub fn insert(&mut self, sym: String) -> usize {
-        if let Some(id) = self.0.get_by_left(&sym) {
+        if let Some(id) = self.ids.get(sym.as_str()) {
             *id
         } else {
-            let id = self.1;
-            self.0.insert(sym, id);
-            self.1 += 1;
+            let id = self.names.len();
+            let sym: Rc<str> = sym.into();
+            self.names.push(Rc::clone(&sym));
+            self.ids.insert(sym, id);
             id
         }
     }
 
-    pub fn get_id(&self, sym: &String) -> Option<&usize> {
-        self.0.get_by_left(sym)
+    pub fn get_id(&self, sym: &str) -> Option<&usize> {
+        self.ids.get(sym)
     }
 
     pub fn get_str(&self, id: &usize) -> &str {
-        self.0.get_by_right(id).unwrap()
+        &self.names[*id]
     }
 
-    pub fn contains_str(&self, str: &String) -> bool {
-        self.0.get_by_left(str).is_some()
+    pub fn try_get_str(&self, id: &usize) -> Option<&str> {
+        self.names.get(*id).map(|name| &**name)
+    }
+
+    pub fn contains_str(&self, str: &str) -> bool {
+        self.ids.contains_key(str)
+    }
+}
+
+impl From<NameTable> for Vec<(String, usize)> {
+    fn from(name_table: NameTable) -> Self {
+        name_table
+            .names
+            .into_iter()
+            .enumerate()
+            .map(|(id, name)| (name.to_string(), id))
+            .collect()
+    }
+}
+
+impl TryFrom<Vec<(String, usize)>> for NameTable {
+    type Error = String;
+
+    fn try_from(entries: Vec<(String, usize)>) -> Result<Self, String> {
+        let next = entries.len();
+        NameTable::from_entries(entries, next)
     }
 }
 
 // "Table" is a loose term here
+#[derive(Debug, Clone)]
 pub struct TypeTable {
     table: Vec<Type>,
+    // The struct that defined each record type, so
+    // they print by name instead of by their fields
+    struct_names: HashMap<TypeId, Name>,
 }
 
 // NOTE: This is very brittle as if
@@ -117,6 +310,7 @@ impl TypeTable {
                 Type::Unit,
                 Type::Any,
             ],
+            struct_names: HashMap::new(),
         }
     }
 
@@ -129,4 +323,72 @@ impl TypeTable {
     pub fn get_type(&self, id: TypeId) -> &Type {
         &self.table[id]
     }
+
+    // Gets the type, following any Solved forwards to the
+    // type they point at. unify doesn't write forwards yet, so
+    // for now they only come from `update`
+    pub fn resolve(&self, id: TypeId) -> &Type {
+        &self.table[self.resolve_id(id)]
+    }
+
+    // Like resolve, but gives the id at the end of the forwards
+    pub fn resolve_id(&self, mut id: TypeId) -> TypeId {
+        while let Type::Solved(next) = &self.table[id] {
+            id = *next;
+        }
+        id
+    }
+
+    pub fn update(&mut self, id: TypeId, type_: Type) {
+        self.table[id] = type_;
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Type> {
+        self.table.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.table.len()
+    }
+
+    // Drops every type inserted after the table had `len` entries
+    pub fn truncate(&mut self, len: usize) {
+        self.table.truncate(len);
+        self.struct_names.retain(|id, _| *id < len);
+    }
+
+    pub fn set_struct_name(&mut self, id: TypeId, name: Name) {
+        self.struct_names.insert(id, name);
+    }
+
+    pub fn struct_name(&self, id: TypeId) -> Option<Name> {
+        self.struct_names.get(&self.resolve_id(id)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::{NameTable, PRINT_INDEX};
+
+    #[test]
+    fn name_table_serde() {
+        let mut name_table = NameTable::new();
+        let foo = name_table.insert("foo".to_string());
+        let bar = name_table.insert("bar".to_string());
+        let json = serde_json::to_string(&name_table).unwrap();
+        let loaded: NameTable = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded, name_table);
+        assert_eq!(loaded.get_str(&PRINT_INDEX), "print");
+        for id in &[PRINT_INDEX, foo, bar] {
+            assert_eq!(loaded.get_id(loaded.get_str(id)), Some(id));
+        }
+
+        // Dropping print leaves a gap at id 0
+        let entries = vec![("foo".to_string(), 1)];
+        assert!(NameTable::from_entries(entries, 2).is_err());
+        let mut entries: Vec<_> = name_table.names().map(str::to_string).zip(0..).collect();
+        entries.reverse();
+        let next = entries.len();
+        assert_eq!(NameTable::from_entries(entries, next), Ok(name_table));
+    }
 }
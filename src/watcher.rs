@@ -1,25 +1,80 @@
-use notify::{Watcher, RecursiveMode, watcher};
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use notify::{raw_watcher, RecursiveMode, Watcher};
 use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-pub fn watch_file(path: &Path) {
-    println!("WATCHING: {:?}", path);
-    // Create a channel to receive the events.
-    let (tx, rx) = channel();
+// Editors tend to fire a few events per save, so events
+// closer together than this count as one change
+pub const DEBOUNCE_WINDOW: Duration = Duration::from_millis(200);
 
-    // Create a watcher object, delivering debounced events.
-    // The notification back-end is selected based on the platform.
-    let mut watcher = watcher(tx, Duration::from_secs(10)).unwrap();
+// Given the times of the events in the current burst, in order, says
+// how much longer to wait before acting on them. A burst ends once
+// `window` passes without another event, so zero means act now and
+// None means there's nothing pending
+pub fn debounce(times: &[Duration], now: Duration, window: Duration) -> Option<Duration> {
+    let last = times.last()?;
+    Some(window.saturating_sub(now.saturating_sub(*last)))
+}
 
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    watcher.watch(path, RecursiveMode::Recursive).unwrap();
+// Calls `on_change` whenever the file changes, once per burst of events.
+// Watches the parent directory rather than the file, since editors
+// often save by writing a new file and renaming it over the old one
+pub fn watch_file(path: &Path, mut on_change: impl FnMut()) -> Result<(), notify::Error> {
+    let (tx, rx) = channel();
+    let mut watcher = raw_watcher(tx)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
 
+    let start = Instant::now();
+    let mut times = Vec::new();
     loop {
-        match rx.recv() {
-            Ok(event) => println!("{:?}", event),
-            Err(e) => println!("watch error: {:?}", e),
+        let event = match debounce(&times, start.elapsed(), DEBOUNCE_WINDOW) {
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            Some(wait) if wait == Duration::from_secs(0) => {
+                on_change();
+                times.clear();
+                continue;
+            }
+            Some(wait) => rx.recv_timeout(wait),
+        };
+        match event {
+            Ok(event) => match event.op {
+                Ok(_) if event.path.as_ref().and_then(|p| p.file_name()) == path.file_name() => {
+                    times.push(start.elapsed())
+                }
+                Ok(_) => {}
+                Err(err) => eprintln!("watch error: {}", err),
+            },
+            // The next pass around sees the burst is over
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watcher::debounce;
+    use std::time::Duration;
+
+    #[test]
+    fn debounces_bursts() {
+        let ms = |times: &[u64]| -> Vec<Duration> {
+            times.iter().map(|t| Duration::from_millis(*t)).collect()
+        };
+        let window = Duration::from_millis(200);
+        let wait = |times: &[u64], now: u64| {
+            debounce(&ms(times), Duration::from_millis(now), window).map(|d| d.as_millis())
+        };
+        assert_eq!(wait(&[], 1000), None);
+        // One save that fired three events. The wait counts from the last
+        assert_eq!(wait(&[0, 5, 30], 100), Some(130));
+        assert_eq!(wait(&[0, 5, 30], 230), Some(0));
+        assert_eq!(wait(&[0, 5, 30], 1000), Some(0));
+        // Events keep coming within the window, so it's all one burst
+        assert_eq!(wait(&[0, 150, 300, 450], 500), Some(150));
+    }
+}
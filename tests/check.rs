@@ -0,0 +1,25 @@
+use std::process::Command;
+use std::{env, fs};
+
+// Runs the built binary with --check on the source and returns whether it exited cleanly
+fn check(name: &str, code: &str) -> bool {
+    let dir = env::temp_dir().join(format!("bridge-check-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    fs::write(&path, code).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_parser"))
+        .arg("--check")
+        .arg(&path)
+        .output()
+        .unwrap()
+        .status;
+    fs::remove_file(&path).unwrap();
+    status.success()
+}
+
+#[test]
+fn check_exit_status() {
+    assert!(check("ok.brg", "let x: int = 1;\nprint(x + 2);"));
+    assert!(!check("type.brg", "let x: int = \"a\";"));
+    assert!(!check("parse.brg", "let x: int = ;"));
+}